@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX tasks_status_created_when;
+ALTER TABLE tasks DROP COLUMN worker_id;
\ No newline at end of file
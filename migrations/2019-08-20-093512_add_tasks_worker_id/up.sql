@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE tasks ADD COLUMN worker_id BIGINT;
+ALTER TABLE tasks ADD CONSTRAINT worker_id_foreign FOREIGN KEY (worker_id) REFERENCES workers(id);
+CREATE INDEX tasks_status_created_when ON tasks (status, created_when);
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE tasks DROP COLUMN result;
+ALTER TABLE tasks DROP COLUMN verdict;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE tasks ADD COLUMN verdict TEXT;
+ALTER TABLE tasks ADD COLUMN result JSONB;
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE worker_capabilities DROP CONSTRAINT worker_profile_keys;
+ALTER TABLE workers DROP COLUMN registered_when;
+ALTER TABLE workers DROP COLUMN token;
+ALTER TABLE workers DROP COLUMN name;
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE workers ADD COLUMN name TEXT;
+ALTER TABLE workers ADD COLUMN token TEXT UNIQUE;
+ALTER TABLE workers ADD COLUMN registered_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL;
+ALTER TABLE worker_capabilities ADD CONSTRAINT worker_profile_keys UNIQUE (worker_id, profile_id);
\ No newline at end of file
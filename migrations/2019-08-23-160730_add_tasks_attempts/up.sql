@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE tasks ADD COLUMN attempts INTEGER DEFAULT 0 NOT NULL;
\ No newline at end of file
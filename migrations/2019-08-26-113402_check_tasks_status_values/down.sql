@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE tasks DROP CONSTRAINT status_values;
\ No newline at end of file
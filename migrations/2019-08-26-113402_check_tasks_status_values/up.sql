@@ -0,0 +1,3 @@
+-- Your SQL goes here
+UPDATE tasks SET status = 'failed', message = 'unrecognized status ' || status WHERE status NOT IN ('new', 'queued', 'running', 'succeeded', 'failed', 'cancelled', 'timed_out');
+ALTER TABLE tasks ADD CONSTRAINT status_values CHECK (status IN ('new', 'queued', 'running', 'succeeded', 'failed', 'cancelled', 'timed_out'));
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX reports_file_key;
+ALTER TABLE reports DROP COLUMN file_key;
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- reports.file stays until the backfill (web-api --backfill-files) has moved
+-- every file into the blob store; a later migration drops it.
+ALTER TABLE reports ADD COLUMN file_key TEXT;
+CREATE INDEX reports_file_key ON reports (file_key);
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE reports DROP CONSTRAINT file_multihash_foreign;
+ALTER TABLE reports ADD COLUMN file_key TEXT;
+UPDATE reports SET file_key = samples.file_key FROM samples WHERE reports.has_file AND samples.multihash = reports.file_multihash;
+CREATE INDEX reports_file_key ON reports (file_key);
+ALTER TABLE reports DROP COLUMN has_file;
+DROP TABLE samples;
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE samples (
+    multihash TEXT PRIMARY KEY,
+    file_key TEXT,
+    size BIGINT,
+    reference_count INTEGER DEFAULT 0 NOT NULL,
+    created_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+INSERT INTO samples (multihash, file_key, reference_count)
+    SELECT file_multihash, MAX(file_key), COUNT(*) FILTER (WHERE file_key IS NOT NULL OR file IS NOT NULL)
+    FROM reports GROUP BY file_multihash;
+ALTER TABLE reports ADD COLUMN has_file BOOLEAN DEFAULT FALSE NOT NULL;
+UPDATE reports SET has_file = file_key IS NOT NULL OR file IS NOT NULL;
+DROP INDEX reports_file_key;
+ALTER TABLE reports DROP COLUMN file_key;
+ALTER TABLE reports ADD CONSTRAINT file_multihash_foreign FOREIGN KEY (file_multihash) REFERENCES samples(multihash);
\ No newline at end of file
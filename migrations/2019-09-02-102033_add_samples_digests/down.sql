@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX reports_file_multihash;
+ALTER TABLE samples DROP COLUMN sha512;
+ALTER TABLE samples DROP COLUMN sha256;
+ALTER TABLE samples DROP COLUMN sha1;
+ALTER TABLE samples DROP COLUMN md5;
\ No newline at end of file
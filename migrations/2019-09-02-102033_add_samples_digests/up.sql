@@ -0,0 +1,11 @@
+-- Your SQL goes here
+ALTER TABLE samples ADD COLUMN md5 TEXT;
+ALTER TABLE samples ADD COLUMN sha1 TEXT;
+ALTER TABLE samples ADD COLUMN sha256 TEXT;
+ALTER TABLE samples ADD COLUMN sha512 TEXT;
+CREATE INDEX samples_md5 ON samples (md5);
+CREATE INDEX samples_sha1 ON samples (sha1);
+CREATE INDEX samples_sha256 ON samples (sha256);
+CREATE INDEX samples_sha512 ON samples (sha512);
+UPDATE samples SET sha256 = substring(multihash from 5) WHERE multihash LIKE '1220%';
+CREATE INDEX reports_file_multihash ON reports (file_multihash);
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX reports_user_id_created_when;
+DROP INDEX tasks_report_id_verdict;
+ALTER TABLE tasks DROP CONSTRAINT verdict_values;
\ No newline at end of file
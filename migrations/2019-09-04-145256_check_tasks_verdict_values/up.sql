@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE tasks ADD CONSTRAINT verdict_values CHECK (verdict IN ('clean', 'malicious', 'suspicious', 'error'));
+CREATE INDEX tasks_report_id_verdict ON tasks (report_id, verdict);
+CREATE INDEX reports_user_id_created_when ON reports (user_id, created_when, id);
\ No newline at end of file
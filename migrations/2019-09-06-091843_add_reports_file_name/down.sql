@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE reports DROP COLUMN file_name;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE reports ADD COLUMN file_name TEXT;
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM tokens WHERE kind = 'refresh';
+DROP INDEX tokens_family;
+ALTER TABLE tokens DROP CONSTRAINT kind_values;
+ALTER TABLE tokens DROP COLUMN used_when;
+ALTER TABLE tokens DROP COLUMN last_used_when;
+ALTER TABLE tokens DROP COLUMN family;
+ALTER TABLE tokens DROP COLUMN kind;
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE tokens ADD COLUMN kind TEXT DEFAULT 'access' NOT NULL;
+ALTER TABLE tokens ADD COLUMN family TEXT;
+UPDATE tokens SET family = token;
+ALTER TABLE tokens ALTER COLUMN family SET NOT NULL;
+ALTER TABLE tokens ADD COLUMN last_used_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL;
+ALTER TABLE tokens ADD COLUMN used_when TIMESTAMP WITH TIME ZONE;
+ALTER TABLE tokens ADD CONSTRAINT kind_values CHECK (kind IN ('access', 'refresh'));
+CREATE INDEX tokens_family ON tokens (family);
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX workers_token_prefix;
+ALTER TABLE workers DROP COLUMN token_hash;
+ALTER TABLE workers DROP COLUMN token_prefix;
+ALTER TABLE workers ADD COLUMN token TEXT UNIQUE;
+DELETE FROM tokens;
+DROP INDEX tokens_token_prefix;
+ALTER TABLE tokens DROP COLUMN token_hash;
+ALTER TABLE tokens DROP COLUMN token_prefix;
+ALTER TABLE tokens ADD COLUMN token TEXT UNIQUE NOT NULL;
\ No newline at end of file
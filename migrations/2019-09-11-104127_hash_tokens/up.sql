@@ -0,0 +1,10 @@
+-- Your SQL goes here
+DELETE FROM tokens;
+ALTER TABLE tokens DROP COLUMN token;
+ALTER TABLE tokens ADD COLUMN token_prefix TEXT NOT NULL;
+ALTER TABLE tokens ADD COLUMN token_hash TEXT UNIQUE NOT NULL;
+CREATE INDEX tokens_token_prefix ON tokens (token_prefix);
+ALTER TABLE workers DROP COLUMN token;
+ALTER TABLE workers ADD COLUMN token_prefix TEXT;
+ALTER TABLE workers ADD COLUMN token_hash TEXT UNIQUE;
+CREATE INDEX workers_token_prefix ON workers (token_prefix);
\ No newline at end of file
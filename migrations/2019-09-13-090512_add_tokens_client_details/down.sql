@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX tokens_user_id;
+ALTER TABLE tokens DROP COLUMN ip_address;
+ALTER TABLE tokens DROP COLUMN user_agent;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE tokens ADD COLUMN user_agent TEXT;
+ALTER TABLE tokens ADD COLUMN ip_address TEXT;
+CREATE INDEX tokens_user_id ON tokens (user_id);
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE api_keys (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    key_prefix TEXT NOT NULL,
+    key_hash TEXT UNIQUE NOT NULL,
+    scopes TEXT[] NOT NULL,
+    allowed_ips TEXT[] DEFAULT '{}' NOT NULL,
+    expires_when TIMESTAMP WITH TIME ZONE,
+    created_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_used_when TIMESTAMP WITH TIME ZONE
+);
+ALTER TABLE api_keys ADD CONSTRAINT user_id_foreign FOREIGN KEY (user_id) REFERENCES users(id);
+CREATE INDEX api_keys_key_prefix ON api_keys (key_prefix);
+CREATE INDEX api_keys_user_id ON api_keys (user_id);
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN disabled BOOLEAN DEFAULT FALSE NOT NULL;
+ALTER TABLE users ADD COLUMN password_reset_required BOOLEAN DEFAULT FALSE NOT NULL;
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN email TEXT;
+CREATE TABLE password_resets (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    token_prefix TEXT NOT NULL,
+    token_hash TEXT UNIQUE NOT NULL,
+    created_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    used_when TIMESTAMP WITH TIME ZONE
+);
+ALTER TABLE password_resets ADD CONSTRAINT user_id_foreign FOREIGN KEY (user_id) REFERENCES users(id);
+CREATE INDEX password_resets_token_prefix ON password_resets (token_prefix);
\ No newline at end of file
@@ -0,0 +1,25 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN totp_secret TEXT;
+ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN DEFAULT FALSE NOT NULL;
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT;
+CREATE TABLE recovery_codes (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_when TIMESTAMP WITH TIME ZONE
+);
+ALTER TABLE recovery_codes ADD CONSTRAINT user_id_foreign FOREIGN KEY (user_id) REFERENCES users(id);
+CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
+CREATE TABLE mfa_challenges (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    token_prefix TEXT NOT NULL,
+    token_hash TEXT UNIQUE NOT NULL,
+    created_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    attempts INTEGER DEFAULT 0 NOT NULL
+);
+ALTER TABLE mfa_challenges ADD CONSTRAINT user_id_foreign FOREIGN KEY (user_id) REFERENCES users(id);
+CREATE INDEX mfa_challenges_token_prefix ON mfa_challenges (token_prefix);
+CREATE TABLE mfa_policies (
+    rank INTEGER PRIMARY KEY
+);
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE login_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip_address TEXT,
+    succeeded BOOLEAN NOT NULL,
+    attempted_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE INDEX login_attempts_username ON login_attempts (username, attempted_when);
+CREATE INDEX login_attempts_ip_address ON login_attempts (ip_address, attempted_when);
\ No newline at end of file
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE invitations (
+    id BIGSERIAL PRIMARY KEY,
+    code_prefix TEXT NOT NULL,
+    code_hash TEXT UNIQUE NOT NULL,
+    rank INTEGER NOT NULL DEFAULT 0,
+    created_by BIGINT,
+    created_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_when TIMESTAMP WITH TIME ZONE,
+    used_by BIGINT,
+    used_when TIMESTAMP WITH TIME ZONE
+);
+ALTER TABLE invitations ADD CONSTRAINT created_by_foreign FOREIGN KEY (created_by) REFERENCES users(id);
+ALTER TABLE invitations ADD CONSTRAINT used_by_foreign FOREIGN KEY (used_by) REFERENCES users(id);
+CREATE INDEX invitations_code_prefix ON invitations (code_prefix);
\ No newline at end of file
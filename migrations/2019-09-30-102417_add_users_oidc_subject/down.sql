@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE oidc_logins;
+ALTER TABLE users DROP COLUMN oidc_subject;
\ No newline at end of file
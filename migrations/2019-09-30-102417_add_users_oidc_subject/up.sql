@@ -0,0 +1,11 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN oidc_subject TEXT UNIQUE;
+CREATE TABLE oidc_logins (
+    id BIGSERIAL PRIMARY KEY,
+    state_prefix TEXT NOT NULL,
+    state_hash TEXT UNIQUE NOT NULL,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    created_when TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE INDEX oidc_logins_state_prefix ON oidc_logins (state_prefix);
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN ldap_dn TEXT UNIQUE;
\ No newline at end of file
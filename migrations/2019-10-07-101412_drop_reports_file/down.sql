@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE reports ADD COLUMN file BYTEA;
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM reports WHERE file IS NOT NULL) THEN
+        RAISE EXCEPTION 'reports.file still holds files, run web-api --backfill-files first';
+    END IF;
+END
+$$;
+ALTER TABLE reports DROP COLUMN file;
\ No newline at end of file
@@ -0,0 +1,720 @@
+use actix_web::{
+    error::BlockingError, http::header, web, Error as AWError, HttpRequest, HttpResponse,
+};
+use chrono::prelude::*;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    Connection, PgConnection,
+};
+use futures::{
+    future::{ok, Either},
+    Future,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::audit;
+use crate::auth;
+use crate::models;
+use crate::storage;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// Resolves the caller, who must be logged in interactively as an admin.
+fn admin(
+    conn: &PgConnection,
+    token: &str,
+    client: &models::Client,
+) -> Result<models::User, models::Error> {
+    let user = models::User::by_session(conn, token, client)?;
+    user.require(models::Permission::ManageUsers)?;
+
+    Ok(user)
+}
+
+fn unauthorized<E>(e: BlockingError<models::Error>) -> Result<HttpResponse, E> {
+    match e {
+        BlockingError::Error(models::Error::Database(diesel::result::Error::NotFound)) => {
+            Ok(HttpResponse::Unauthorized().finish())
+        }
+        BlockingError::Error(models::Error::Forbidden) => Ok(HttpResponse::Forbidden().finish()),
+        _ => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+fn not_found<E>(e: BlockingError<models::Error>) -> Result<HttpResponse, E> {
+    match e {
+        BlockingError::Error(models::Error::Database(diesel::result::Error::NotFound)) => {
+            Ok(HttpResponse::NotFound().finish())
+        }
+        BlockingError::Error(models::Error::Forbidden) => Ok(HttpResponse::Forbidden().finish()),
+        _ => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserResponse {
+    id: i64,
+    username: String,
+    email: Option<String>,
+    role: models::Role,
+    disabled: bool,
+    password_reset_required: bool,
+    totp_enabled: bool,
+}
+
+impl From<models::User> for UserResponse {
+    fn from(user: models::User) -> Self {
+        UserResponse {
+            role: user.role(),
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            disabled: user.disabled,
+            password_reset_required: user.password_reset_required,
+            totp_enabled: user.totp_enabled,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    search: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    users: Vec<UserResponse>,
+}
+
+pub fn list(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                admin(conn, &token, &client)?;
+
+                Ok(models::User::search(
+                    conn,
+                    query.search.as_ref().map(String::as_str),
+                    query
+                        .limit
+                        .unwrap_or(DEFAULT_PAGE_SIZE)
+                        .max(1)
+                        .min(MAX_PAGE_SIZE),
+                    query.offset.unwrap_or(0).max(0),
+                )?)
+            })
+            .and_then(|users| {
+                Ok(HttpResponse::Ok().json(ListResponse {
+                    users: users.into_iter().map(UserResponse::from).collect(),
+                }))
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UserPath {
+    pub user_id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RoleRequest {
+    role: models::Role,
+}
+
+pub fn set_role(
+    req: HttpRequest,
+    path: web::Path<UserPath>,
+    role: web::Json<RoleRequest>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let admin = admin(&db.get().unwrap(), &token, &client)?;
+
+                Ok((db, admin))
+            })
+            .and_then(move |(db, admin)| {
+                web::block(move || -> Result<_, models::Error> {
+                    // Admins cannot demote themselves and leave nobody in charge.
+                    if admin.id == path.user_id {
+                        return Err(models::Error::Forbidden);
+                    }
+
+                    let conn = &db.get().unwrap();
+
+                    conn.transaction(|| {
+                        let user = models::User::set_role(conn, path.user_id, role.role)?;
+                        pending.record(conn, audit::Outcome::Success)?;
+
+                        Ok(user)
+                    })
+                })
+                .and_then(|user| Ok(HttpResponse::Ok().json(UserResponse::from(user))))
+                .or_else(not_found)
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+fn set_disabled(
+    req: HttpRequest,
+    path: web::Path<UserPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    disabled: bool,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let admin = admin(&db.get().unwrap(), &token, &client)?;
+
+                Ok((db, admin))
+            })
+            .and_then(move |(db, admin)| {
+                web::block(move || -> Result<_, models::Error> {
+                    if admin.id == path.user_id {
+                        return Err(models::Error::Forbidden);
+                    }
+
+                    let conn = &db.get().unwrap();
+
+                    conn.transaction(|| {
+                        let user = models::User::set_disabled(conn, path.user_id, disabled)?;
+                        pending.record(conn, audit::Outcome::Success)?;
+
+                        Ok(user)
+                    })
+                })
+                .and_then(|user| Ok(HttpResponse::Ok().json(UserResponse::from(user))))
+                .or_else(not_found)
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+/// Disables an account and ends its sessions.
+pub fn disable(
+    req: HttpRequest,
+    path: web::Path<UserPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    set_disabled(req, path, db, true)
+}
+
+pub fn enable(
+    req: HttpRequest,
+    path: web::Path<UserPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    set_disabled(req, path, db, false)
+}
+
+/// Logs the user out everywhere, revokes their API keys and refuses their
+/// password until they pick a new one.
+pub fn require_password_reset(
+    req: HttpRequest,
+    path: web::Path<UserPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let admin = admin(&db.get().unwrap(), &token, &client)?;
+
+                Ok((db, admin))
+            })
+            .and_then(move |(db, _)| {
+                web::block(move || -> Result<_, models::Error> {
+                    let conn = &db.get().unwrap();
+
+                    conn.transaction(|| {
+                        let user = models::User::require_password_reset(conn, path.user_id)?;
+                        pending.record(conn, audit::Outcome::Success)?;
+
+                        Ok(user)
+                    })
+                })
+                .and_then(|user| Ok(HttpResponse::Ok().json(UserResponse::from(user))))
+                .or_else(not_found)
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportsAction {
+    Delete,
+    Reassign,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    reports: ReportsAction,
+    reassign_to: Option<i64>,
+}
+
+/// Deletes an account. The caller must say whether its reports go with it
+/// or move to another user.
+pub fn delete(
+    req: HttpRequest,
+    path: web::Path<UserPath>,
+    query: web::Query<DeleteQuery>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    store: web::Data<storage::Store>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = auth::client(&req);
+
+    let disposition = match (&query.reports, query.reassign_to) {
+        (ReportsAction::Delete, None) => Some(models::ReportDisposition::Delete),
+        (ReportsAction::Reassign, Some(new_owner)) if new_owner != path.user_id => {
+            Some(models::ReportDisposition::ReassignTo(new_owner))
+        }
+        _ => None,
+    };
+
+    match (req.headers().get(header::AUTHORIZATION), disposition) {
+        (Some(token), Some(disposition)) => {
+            let token = token.to_str().unwrap().to_string();
+
+            Either::A(
+                web::block(move || {
+                    let admin = admin(&db.get().unwrap(), &token, &client)?;
+
+                    Ok((db, admin))
+                })
+                .and_then(move |(db, admin)| {
+                    web::block(move || -> Result<_, models::Error> {
+                        if admin.id == path.user_id {
+                            return Err(models::Error::Forbidden);
+                        }
+
+                        let conn = &db.get().unwrap();
+
+                        let orphaned_keys = conn.transaction::<_, models::Error, _>(|| {
+                            let orphaned_keys =
+                                models::User::delete(conn, path.user_id, disposition)?;
+                            pending.record(conn, audit::Outcome::Success)?;
+
+                            Ok(orphaned_keys)
+                        })?;
+
+                        // The accounts are gone either way; a file that fails
+                        // to delete is only wasted space.
+                        for key in orphaned_keys {
+                            if let Err(e) = store.delete(&key) {
+                                error!("could not delete orphaned sample {}: {}", key, e);
+                            }
+                        }
+
+                        Ok(())
+                    })
+                    .and_then(|_| Ok(HttpResponse::Ok().finish()))
+                    .or_else(not_found)
+                })
+                .or_else(unauthorized),
+            )
+        }
+        (Some(_), None) => Either::B(ok(HttpResponse::BadRequest().finish())),
+        (None, _) => Either::B(ok(HttpResponse::Unauthorized().finish())),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MfaPolicy {
+    roles: Vec<models::Role>,
+}
+
+pub fn mfa_policy(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                admin(conn, &token, &client)?;
+
+                Ok(models::MfaPolicy::roles(conn)?)
+            })
+            .and_then(|roles| Ok(HttpResponse::Ok().json(MfaPolicy { roles })))
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+/// Sets which roles must use two-factor authentication. Members who have not
+/// enrolled yet keep their sessions but can only manage their own account
+/// until they do.
+pub fn set_mfa_policy(
+    req: HttpRequest,
+    policy: web::Json<MfaPolicy>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                admin(conn, &token, &client)?;
+                models::MfaPolicy::set_roles(conn, &policy.roles)?;
+
+                Ok(models::MfaPolicy::roles(conn)?)
+            })
+            .and_then(|roles| Ok(HttpResponse::Ok().json(MfaPolicy { roles })))
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct InvitationResponse {
+    id: i64,
+    role: models::Role,
+    created_by: Option<i64>,
+    created_when: DateTime<Utc>,
+    expires_when: Option<DateTime<Utc>>,
+    used_by: Option<i64>,
+    used_when: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+}
+
+impl From<models::Invitation> for InvitationResponse {
+    fn from(invitation: models::Invitation) -> Self {
+        InvitationResponse {
+            role: models::Role::from_rank(invitation.rank),
+            id: invitation.id,
+            created_by: invitation.created_by,
+            created_when: invitation.created_when,
+            expires_when: invitation.expires_when,
+            used_by: invitation.used_by,
+            used_when: invitation.used_when,
+            code: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct InvitationListResponse {
+    invitations: Vec<InvitationResponse>,
+}
+
+pub fn invitations(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                admin(conn, &token, &client)?;
+
+                Ok(models::Invitation::list(conn)?)
+            })
+            .and_then(|invitations| {
+                Ok(HttpResponse::Ok().json(InvitationListResponse {
+                    invitations: invitations
+                        .into_iter()
+                        .map(InvitationResponse::from)
+                        .collect(),
+                }))
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InvitationRequest {
+    role: Option<models::Role>,
+    expires_when: Option<DateTime<Utc>>,
+}
+
+/// Issues an invitation code. The code is only ever returned here.
+pub fn invite(
+    req: HttpRequest,
+    invitation: web::Json<InvitationRequest>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                let admin = admin(conn, &token, &client)?;
+
+                conn.transaction(|| {
+                    let created = models::Invitation::create(
+                        conn,
+                        admin.id,
+                        invitation.role.unwrap_or(models::Role::User),
+                        invitation.expires_when,
+                    )?;
+                    pending.record(conn, audit::Outcome::Success)?;
+
+                    Ok(created)
+                })
+            })
+            .and_then(|(invitation, code)| {
+                Ok(HttpResponse::Ok().json(InvitationResponse {
+                    code: Some(code),
+                    ..InvitationResponse::from(invitation)
+                }))
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InvitationPath {
+    pub invitation_id: i64,
+}
+
+/// Withdraws an invitation that has not been used yet.
+pub fn revoke_invitation(
+    req: HttpRequest,
+    path: web::Path<InvitationPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                admin(conn, &token, &client)?;
+
+                conn.transaction(|| {
+                    let revoked = models::Invitation::revoke(conn, path.invitation_id)?;
+                    if revoked {
+                        pending.record(conn, audit::Outcome::Success)?;
+                    }
+
+                    Ok(revoked)
+                })
+            })
+            .and_then(|revoked| {
+                if revoked {
+                    Ok(HttpResponse::Ok().finish())
+                } else {
+                    Ok(HttpResponse::NotFound().finish())
+                }
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    actor_id: Option<i64>,
+    action: Option<String>,
+    target: Option<String>,
+    outcome: Option<audit::Outcome>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditEventListResponse {
+    audit_events: Vec<models::AuditEvent>,
+}
+
+/// Searches the audit log, newest first.
+pub fn audit_events(
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+        let query = query.into_inner();
+        let filter = models::AuditFilter {
+            actor_id: query.actor_id,
+            action: query.action,
+            target: query.target,
+            outcome: query.outcome,
+            from: query.from,
+            to: query.to,
+            limit: query
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .max(1)
+                .min(MAX_PAGE_SIZE),
+            offset: query.offset.unwrap_or(0).max(0),
+        };
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                let user = models::User::by_session(conn, &token, &client)?;
+                user.require(models::Permission::ReadAuditLog)?;
+
+                Ok(models::AuditEvent::search(conn, &filter)?)
+            })
+            .and_then(|audit_events| {
+                Ok(HttpResponse::Ok().json(AuditEventListResponse { audit_events }))
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReportsProfileRequest {
+    profile: String,
+    owner_id: Option<i64>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    verdict: Option<models::Verdict>,
+}
+
+#[derive(Serialize)]
+pub struct ReportsProfileResponse {
+    queued: usize,
+}
+
+/// Scans every report matching the request with a profile it has not been
+/// scanned with yet, skipping reports whose file has been discarded.
+pub fn add_profile_to_reports(
+    req: HttpRequest,
+    request: web::Json<ReportsProfileRequest>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    audit::pending(&req).set_target(format!("profile:{}", request.profile));
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let user = models::User::by_session(&db.get().unwrap(), &token, &client)?;
+                user.require(models::Permission::ManageAllReports)?;
+
+                Ok(db)
+            })
+            .and_then(move |db| {
+                web::block(move || -> Result<_, models::Error> {
+                    let conn = &db.get().unwrap();
+                    let request = request.into_inner();
+
+                    let profile_id = models::Profile::id_for_machine_name(conn, &request.profile)?;
+                    let filter = models::ReportFilter {
+                        after: None,
+                        limit: 0,
+                        ascending: false,
+                        from: request.from,
+                        to: request.to,
+                        multihash: None,
+                        profile: None,
+                        verdict: request.verdict,
+                    };
+
+                    let queued = models::Task::queue_for_reports(
+                        conn,
+                        profile_id,
+                        request.owner_id,
+                        &filter,
+                    )?;
+
+                    if queued > 0 {
+                        conn.execute("NOTIFY tasks_created")?;
+                    }
+
+                    Ok(queued)
+                })
+                .and_then(|queued| Ok(HttpResponse::Ok().json(ReportsProfileResponse { queued })))
+                .or_else(not_found)
+            })
+            .or_else(unauthorized),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
@@ -0,0 +1,389 @@
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::prelude::*;
+use futures::{try_ready, Async, Poll, Stream};
+
+use crate::storage::BlobStream;
+
+const ENCRYPTION_HEADER_SIZE: u32 = 12;
+/// Encrypted, sizes in a trailing data descriptor, UTF-8 file name.
+const FLAGS: u16 = 0x0809;
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+
+    for (n, entry) in table.iter_mut().enumerate() {
+        *entry = (0..8).fold(n as u32, |c, _| {
+            if c & 1 == 1 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            }
+        });
+    }
+
+    table
+}
+
+/// Traditional PKWARE ("ZipCrypto") encryption, the scheme every unzip tool
+/// understands. It is weak, but only meant to keep antivirus software and
+/// careless double-clicks away from the sample.
+struct ZipCrypto {
+    keys: [u32; 3],
+    table: [u32; 256],
+}
+
+impl ZipCrypto {
+    fn new(password: &[u8], table: [u32; 256]) -> Self {
+        let mut cipher = ZipCrypto {
+            keys: [0x1234_5678, 0x2345_6789, 0x3456_7890],
+            table,
+        };
+
+        for &byte in password {
+            cipher.update(byte);
+        }
+
+        cipher
+    }
+
+    fn crc(&self, crc: u32, byte: u8) -> u32 {
+        self.table[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    }
+
+    fn update(&mut self, byte: u8) {
+        self.keys[0] = self.crc(self.keys[0], byte);
+        self.keys[1] = self.keys[1]
+            .wrapping_add(self.keys[0] & 0xff)
+            .wrapping_mul(134_775_813)
+            .wrapping_add(1);
+        self.keys[2] = self.crc(self.keys[2], (self.keys[1] >> 24) as u8);
+    }
+
+    fn encrypt(&mut self, byte: u8) -> u8 {
+        let temp = (self.keys[2] | 2) & 0xffff;
+        let cipher = byte ^ ((temp.wrapping_mul(temp ^ 1) >> 8) as u8);
+
+        self.update(byte);
+
+        cipher
+    }
+}
+
+enum State {
+    LocalHeader,
+    Data,
+    Trailer,
+    Done,
+}
+
+/// Streams a single file as a password-protected ZIP archive.
+///
+/// The entry is stored uncompressed with its CRC and sizes in a data
+/// descriptor, so the archive can be produced in one pass without buffering
+/// the whole sample. The sample is read through a `BlobStream`, so reads stay
+/// off the event loop.
+pub struct EncryptedZipStream {
+    blob: BlobStream,
+    name: Vec<u8>,
+    cipher: ZipCrypto,
+    table: [u32; 256],
+    crc: u32,
+    size: u32,
+    time: u16,
+    date: u16,
+    state: State,
+}
+
+impl EncryptedZipStream {
+    pub fn new(blob: BlobStream, name: &str, password: &str, modified: DateTime<Utc>) -> Self {
+        let table = crc32_table();
+
+        EncryptedZipStream {
+            blob,
+            name: name.as_bytes().to_vec(),
+            cipher: ZipCrypto::new(password.as_bytes(), table),
+            table,
+            crc: 0xffff_ffff,
+            size: 0,
+            time: ((modified.hour() << 11) | (modified.minute() << 5) | (modified.second() / 2))
+                as u16,
+            date: (((modified.year() - 1980).max(0) as u32) << 9
+                | (modified.month() << 5)
+                | modified.day()) as u16,
+            state: State::LocalHeader,
+        }
+    }
+
+    fn local_header(&mut self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(30 + self.name.len() + 12);
+
+        buf.put_u32_le(0x0403_4b50);
+        buf.put_u16_le(20);
+        buf.put_u16_le(FLAGS);
+        buf.put_u16_le(0);
+        buf.put_u16_le(self.time);
+        buf.put_u16_le(self.date);
+        buf.put_u32_le(0);
+        buf.put_u32_le(0);
+        buf.put_u32_le(0);
+        buf.put_u16_le(self.name.len() as u16);
+        buf.put_u16_le(0);
+        buf.put_slice(&self.name);
+
+        // With a data descriptor the CRC is not known up front, so the last
+        // header byte is checked against the modification time instead.
+        let mut header: [u8; 12] = rand::random();
+        header[11] = (self.time >> 8) as u8;
+
+        for byte in header.iter() {
+            buf.put_u8(self.cipher.encrypt(*byte));
+        }
+
+        buf.freeze()
+    }
+
+    fn trailer(&self) -> Bytes {
+        let crc = !self.crc;
+        let compressed_size = self.size + ENCRYPTION_HEADER_SIZE;
+        let local_header_size = 30 + self.name.len() as u32 + ENCRYPTION_HEADER_SIZE;
+        let central_directory_offset = local_header_size + self.size + 16;
+        let central_directory_size = 46 + self.name.len() as u32;
+
+        let mut buf = BytesMut::with_capacity(16 + 46 + self.name.len() + 22);
+
+        buf.put_u32_le(0x0807_4b50);
+        buf.put_u32_le(crc);
+        buf.put_u32_le(compressed_size);
+        buf.put_u32_le(self.size);
+
+        buf.put_u32_le(0x0201_4b50);
+        buf.put_u16_le(20);
+        buf.put_u16_le(20);
+        buf.put_u16_le(FLAGS);
+        buf.put_u16_le(0);
+        buf.put_u16_le(self.time);
+        buf.put_u16_le(self.date);
+        buf.put_u32_le(crc);
+        buf.put_u32_le(compressed_size);
+        buf.put_u32_le(self.size);
+        buf.put_u16_le(self.name.len() as u16);
+        buf.put_u16_le(0);
+        buf.put_u16_le(0);
+        buf.put_u16_le(0);
+        buf.put_u16_le(0);
+        buf.put_u32_le(0);
+        buf.put_u32_le(0);
+        buf.put_slice(&self.name);
+
+        buf.put_u32_le(0x0605_4b50);
+        buf.put_u16_le(0);
+        buf.put_u16_le(0);
+        buf.put_u16_le(1);
+        buf.put_u16_le(1);
+        buf.put_u32_le(central_directory_size);
+        buf.put_u32_le(central_directory_offset);
+        buf.put_u16_le(0);
+
+        buf.freeze()
+    }
+}
+
+impl Stream for EncryptedZipStream {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        match self.state {
+            State::LocalHeader => {
+                self.state = State::Data;
+                Ok(Async::Ready(Some(self.local_header())))
+            }
+            State::Data => {
+                let mut buf = match try_ready!(self.blob.poll()) {
+                    Some(chunk) => BytesMut::from(&chunk[..]),
+                    None => {
+                        self.state = State::Trailer;
+                        return self.poll();
+                    }
+                };
+
+                self.size += buf.len() as u32;
+
+                for byte in buf.iter_mut() {
+                    self.crc = self.table[((self.crc ^ u32::from(*byte)) & 0xff) as usize]
+                        ^ (self.crc >> 8);
+                    *byte = self.cipher.encrypt(*byte);
+                }
+
+                Ok(Async::Ready(Some(buf.freeze())))
+            }
+            State::Trailer => {
+                self.state = State::Done;
+                Ok(Async::Ready(Some(self.trailer())))
+            }
+            State::Done => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use super::*;
+
+    const NAME: &str = "sample.bin";
+    const PASSWORD: &str = "infected";
+
+    fn u16_at(buf: &[u8], offset: usize) -> u16 {
+        u16::from(buf[offset]) | u16::from(buf[offset + 1]) << 8
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from(u16_at(buf, offset)) | u32::from(u16_at(buf, offset + 2)) << 16
+    }
+
+    /// CRC-32 one bit at a time, as a reference for the table.
+    fn crc32(crc: u32, byte: u8) -> u32 {
+        (0..8).fold(crc ^ u32::from(byte), |c, _| {
+            if c & 1 == 1 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            }
+        })
+    }
+
+    /// ZipCrypto decryption as written in APPNOTE.TXT section 6.1.
+    struct Decrypter([u32; 3]);
+
+    impl Decrypter {
+        fn new(password: &[u8]) -> Self {
+            let mut decrypter = Decrypter([0x1234_5678, 0x2345_6789, 0x3456_7890]);
+            for &byte in password {
+                decrypter.update(byte);
+            }
+
+            decrypter
+        }
+
+        fn update(&mut self, byte: u8) {
+            let keys = &mut self.0;
+            keys[0] = crc32(keys[0], byte);
+            keys[1] = keys[1]
+                .wrapping_add(keys[0] & 0xff)
+                .wrapping_mul(134_775_813)
+                .wrapping_add(1);
+            keys[2] = crc32(keys[2], (keys[1] >> 24) as u8);
+        }
+
+        fn decrypt(&mut self, data: &[u8]) -> Vec<u8> {
+            data.iter()
+                .map(|&byte| {
+                    let temp = (self.0[2] | 2) & 0xffff;
+                    let plain = byte ^ (temp.wrapping_mul(temp ^ 1) >> 8) as u8;
+                    self.update(plain);
+
+                    plain
+                })
+                .collect()
+        }
+    }
+
+    fn archive(content: &[u8]) -> Vec<u8> {
+        let blob = BlobStream::new(Box::new(io::Cursor::new(content.to_vec())));
+        let modified = Utc.ymd(2019, 7, 14).and_hms(13, 45, 30);
+
+        EncryptedZipStream::new(blob, NAME, PASSWORD, modified)
+            .concat2()
+            .wait()
+            .unwrap()
+            .to_vec()
+    }
+
+    /// Checks every header of the archive and returns the CRC-32 it records
+    /// for `content`, after decrypting the entry and comparing it.
+    fn check_archive(content: &[u8]) -> u32 {
+        let zip = archive(content);
+        let size = content.len();
+
+        // Local file header.
+        assert_eq!(u32_at(&zip, 0), 0x0403_4b50);
+        assert_eq!(u16_at(&zip, 6), FLAGS);
+        assert_eq!(u16_at(&zip, 8), 0);
+        assert_eq!(u16_at(&zip, 10), (13 << 11) | (45 << 5) | 15);
+        assert_eq!(u16_at(&zip, 12), (39 << 9) | (7 << 5) | 14);
+        assert_eq!(u16_at(&zip, 26) as usize, NAME.len());
+        assert_eq!(&zip[30..30 + NAME.len()], NAME.as_bytes());
+
+        // Encryption header and data.
+        let data_offset = 30 + NAME.len();
+        let mut decrypter = Decrypter::new(PASSWORD.as_bytes());
+        let header = decrypter.decrypt(&zip[data_offset..data_offset + 12]);
+        assert_eq!(header[11], (u16_at(&zip, 10) >> 8) as u8);
+        let plain = decrypter.decrypt(&zip[data_offset + 12..data_offset + 12 + size]);
+        assert!(plain == content);
+
+        // Data descriptor.
+        let descriptor = data_offset + 12 + size;
+        let crc = u32_at(&zip, descriptor + 4);
+        assert_eq!(u32_at(&zip, descriptor), 0x0807_4b50);
+        assert_eq!(crc, !content.iter().fold(!0, |crc, &byte| crc32(crc, byte)));
+        assert_eq!(u32_at(&zip, descriptor + 8) as usize, size + 12);
+        assert_eq!(u32_at(&zip, descriptor + 12) as usize, size);
+
+        // Central directory.
+        let central_directory = descriptor + 16;
+        assert_eq!(u32_at(&zip, central_directory), 0x0201_4b50);
+        assert_eq!(u16_at(&zip, central_directory + 8), FLAGS);
+        assert_eq!(u32_at(&zip, central_directory + 16), crc);
+        assert_eq!(u32_at(&zip, central_directory + 20) as usize, size + 12);
+        assert_eq!(u32_at(&zip, central_directory + 24) as usize, size);
+        assert_eq!(u16_at(&zip, central_directory + 28) as usize, NAME.len());
+        assert_eq!(u32_at(&zip, central_directory + 42), 0);
+        assert_eq!(
+            &zip[central_directory + 46..central_directory + 46 + NAME.len()],
+            NAME.as_bytes()
+        );
+
+        // End of central directory record, which must end the archive.
+        let end = central_directory + 46 + NAME.len();
+        assert_eq!(zip.len(), end + 22);
+        assert_eq!(u32_at(&zip, end), 0x0605_4b50);
+        assert_eq!(u16_at(&zip, end + 8), 1);
+        assert_eq!(u16_at(&zip, end + 10), 1);
+        assert_eq!(u32_at(&zip, end + 12) as usize, 46 + NAME.len());
+        assert_eq!(u32_at(&zip, end + 16) as usize, central_directory);
+
+        crc
+    }
+
+    #[test]
+    fn crc32_table_matches_check_value() {
+        let table = crc32_table();
+        let crc = b"123456789".iter().fold(0xffff_ffff, |crc: u32, &byte| {
+            table[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+        });
+
+        assert_eq!(!crc, 0xcbf4_3926);
+    }
+
+    #[test]
+    fn archives_small_blob() {
+        assert_eq!(check_archive(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn archives_blob_over_several_chunks() {
+        let content: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+
+        assert_eq!(check_archive(&content), 0xa745_c145);
+    }
+
+    #[test]
+    fn archives_empty_blob() {
+        assert_eq!(check_archive(b""), 0);
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::UdpSocket,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method, StatusCode},
+    Error as AWError, HttpMessage, HttpRequest,
+};
+use chrono::prelude::*;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    PgConnection,
+};
+use futures::{
+    future::{ok, FutureResult},
+    Future, Poll,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::models;
+
+/// How a request ended, as far as the audit log is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    /// Turned away for lack of a valid credential or permission.
+    Denied,
+    Failure,
+}
+
+impl Outcome {
+    pub fn from_status(status: StatusCode) -> Self {
+        if status.is_success() || status.is_redirection() {
+            Outcome::Success
+        } else if status == StatusCode::UNAUTHORIZED
+            || status == StatusCode::FORBIDDEN
+            || status == StatusCode::TOO_MANY_REQUESTS
+        {
+            Outcome::Denied
+        } else {
+            Outcome::Failure
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Denied => "denied",
+            Outcome::Failure => "failure",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Event {
+    pub actor_id: Option<i64>,
+    pub action: &'static str,
+    pub target: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: Outcome,
+    pub occurred_when: DateTime<Utc>,
+}
+
+/// Hands events to the writer thread, so that handlers never wait on them.
+pub struct Auditor {
+    sender: Mutex<mpsc::Sender<(Event, bool)>>,
+}
+
+pub type Audit = Arc<Auditor>;
+
+impl Auditor {
+    /// Queues `event` for the writer, which only exports it if it is `stored`
+    /// already.
+    fn send(&self, event: Event, stored: bool) {
+        if self.sender.lock().unwrap().send((event, stored)).is_err() {
+            error!("audit writer has stopped, an event was lost");
+        }
+    }
+}
+
+/// Middleware recording an event for every request to a resource, named after
+/// the action its method maps to. Methods without an action pass through.
+pub struct Audited {
+    audit: Audit,
+    actions: Vec<(Method, &'static str)>,
+    target: Option<(&'static str, &'static str)>,
+}
+
+impl Audited {
+    pub fn new(audit: &Audit) -> Self {
+        Audited {
+            audit: audit.clone(),
+            actions: Vec::new(),
+            target: None,
+        }
+    }
+
+    pub fn on(mut self, method: Method, action: &'static str) -> Self {
+        self.actions.push((method, action));
+        self
+    }
+
+    /// Names the target `kind:{param}`, after the path parameter `param`.
+    pub fn target(mut self, kind: &'static str, param: &'static str) -> Self {
+        self.target = Some((kind, param));
+        self
+    }
+}
+
+impl<S, B> Transform<S> for Audited
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = AWError;
+    type InitError = ();
+    type Transform = AuditedMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuditedMiddleware {
+            service,
+            audit: self.audit.clone(),
+            actions: self.actions.clone(),
+            target: self.target,
+        })
+    }
+}
+
+pub struct AuditedMiddleware<S> {
+    service: S,
+    audit: Audit,
+    actions: Vec<(Method, &'static str)>,
+    target: Option<(&'static str, &'static str)>,
+}
+
+impl<S, B> Service for AuditedMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = AWError;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let action = match self
+            .actions
+            .iter()
+            .find(|(method, _)| method == req.method())
+        {
+            Some((_, action)) => *action,
+            None => return Box::new(self.service.call(req)),
+        };
+
+        let pending = Pending {
+            action: Some(action),
+            target: Arc::new(Mutex::new(self.target.and_then(|(kind, param)| {
+                req.match_info()
+                    .get(param)
+                    .map(|value| format!("{}:{}", kind, value))
+            }))),
+            ip_address: req.peer_addr().map(|addr| addr.ip().to_string()),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from),
+            actor: Default::default(),
+            stored: Default::default(),
+        };
+        req.extensions_mut().insert(pending.clone());
+
+        let audit = self.audit.clone();
+
+        Box::new(self.service.call(req).then(move |result| {
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(e) => e.as_response_error().error_response().status(),
+            };
+            pending.finish(&audit, status);
+
+            result
+        }))
+    }
+}
+
+/// A request being followed by [`Audited`], shared with its handler through
+/// the request's extensions.
+#[derive(Clone, Default)]
+pub struct Pending {
+    /// None when the request's resource is not audited.
+    action: Option<&'static str>,
+    target: Arc<Mutex<Option<String>>>,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    /// Whoever the request's credential turns out to belong to.
+    pub actor: Arc<Mutex<Option<i64>>>,
+    stored: Arc<Mutex<Option<Event>>>,
+}
+
+impl Pending {
+    /// Names the target when it comes from the request body rather than the
+    /// path.
+    pub fn set_target(&self, target: String) {
+        *self.target.lock().unwrap() = Some(target);
+    }
+
+    /// Stores the event in the handler's own transaction, so that it is
+    /// committed along with what it describes instead of waiting on the
+    /// writer. Call it last, just before the transaction commits.
+    pub fn record(
+        &self,
+        conn: &PgConnection,
+        outcome: Outcome,
+    ) -> Result<(), diesel::result::Error> {
+        if let Some(action) = self.action {
+            let event = self.event(action, outcome);
+            models::AuditEvent::insert(conn, &event)?;
+            *self.stored.lock().unwrap() = Some(event);
+        }
+
+        Ok(())
+    }
+
+    fn event(&self, action: &'static str, outcome: Outcome) -> Event {
+        Event {
+            actor_id: *self.actor.lock().unwrap(),
+            action,
+            target: self.target.lock().unwrap().clone(),
+            ip_address: self.ip_address.clone(),
+            user_agent: self.user_agent.clone(),
+            outcome,
+            occurred_when: Utc::now(),
+        }
+    }
+
+    /// Hands the event to the writer once the response is known. An event
+    /// the handler stored is only exported, unless the request failed on the
+    /// server, as its transaction may then have been rolled back.
+    fn finish(&self, audit: &Audit, status: StatusCode) {
+        let action = match self.action {
+            Some(action) => action,
+            None => return,
+        };
+
+        match self.stored.lock().unwrap().take() {
+            Some(event) if !status.is_server_error() => audit.send(event, true),
+            _ => audit.send(self.event(action, Outcome::from_status(status)), false),
+        }
+    }
+}
+
+/// The audit state of the request, which records nothing if its resource is
+/// not [`Audited`].
+pub fn pending(req: &HttpRequest) -> Pending {
+    req.extensions()
+        .get::<Pending>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Where events are copied to besides the database.
+enum Export {
+    Nowhere,
+    JsonLines(File),
+    Syslog(UdpSocket),
+}
+
+impl Export {
+    fn from_env() -> Self {
+        match env::var("AUDIT_EXPORT").as_ref().map(String::as_str) {
+            Ok("jsonl") => Export::JsonLines(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(env::var("AUDIT_EXPORT_PATH").expect("incomplete audit configuration"))
+                    .expect("could not open audit export file"),
+            ),
+            Ok("syslog") => {
+                let socket = UdpSocket::bind("0.0.0.0:0").expect("could not open syslog socket");
+                socket
+                    .connect(
+                        env::var("AUDIT_SYSLOG_ADDRESS").unwrap_or_else(|_| "127.0.0.1:514".into()),
+                    )
+                    .expect("could not reach syslog");
+
+                Export::Syslog(socket)
+            }
+            Ok("none") | Err(_) => Export::Nowhere,
+            Ok(other) => panic!("unknown audit export: {}", other),
+        }
+    }
+
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        match self {
+            Export::Nowhere => Ok(()),
+            Export::JsonLines(file) => writeln!(file, "{}", serde_json::to_string(event)?),
+            Export::Syslog(socket) => {
+                // RFC 5424 over UDP, facility authpriv, with the event as
+                // JSON for the message.
+                let severity = match event.outcome {
+                    Outcome::Success => 5,
+                    Outcome::Denied | Outcome::Failure => 4,
+                };
+                let message = format!(
+                    "<{}>1 {} - web-api - audit - {}",
+                    10 * 8 + severity,
+                    event
+                        .occurred_when
+                        .to_rfc3339_opts(SecondsFormat::Millis, true),
+                    serde_json::to_string(event)?
+                );
+
+                socket.send(message.as_bytes()).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Starts the thread that stores audit events and copies them to
+/// `AUDIT_EXPORT` for a SIEM: `jsonl` appends them to `AUDIT_EXPORT_PATH`,
+/// `syslog` sends them to `AUDIT_SYSLOG_ADDRESS` over UDP.
+pub fn spawn(pool: Pool<ConnectionManager<PgConnection>>) -> Audit {
+    let (sender, receiver) = mpsc::channel::<(Event, bool)>();
+    let mut export = Export::from_env();
+
+    thread::spawn(move || {
+        for (event, stored) in receiver {
+            if !stored {
+                match pool.get() {
+                    Ok(conn) => {
+                        if let Err(e) = models::AuditEvent::insert(&conn, &event) {
+                            error!("could not store audit event: {}", e);
+                        }
+                    }
+                    Err(e) => error!("audit writer could not get a database connection: {}", e),
+                }
+            }
+
+            if let Err(e) = export.write(&event) {
+                error!("could not export audit event: {}", e);
+            }
+        }
+    });
+
+    Arc::new(Auditor {
+        sender: Mutex::new(sender),
+    })
+}
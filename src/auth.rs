@@ -1,4 +1,7 @@
 use actix_web::{http::header, web, Error as AWError, HttpRequest, HttpResponse};
+use chrono::prelude::*;
+use std::env;
+
 use diesel::{
     r2d2::{ConnectionManager, Pool},
     Connection, PgConnection,
@@ -7,41 +10,162 @@ use futures::{
     future::{ok, Either},
     Future,
 };
+use log::{error, warn};
 use serde_derive::{Deserialize, Serialize};
 
+use crate::audit;
+use crate::backend;
 use crate::models;
+use crate::notify;
+use crate::oidc;
+use crate::registration;
+use crate::totp;
+use crate::validation;
+
+/// Describes the client behind a request, for the session list and the audit
+/// log.
+pub fn client(req: &HttpRequest) -> models::Client {
+    models::Client {
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from),
+        ip_address: req.peer_addr().map(|addr| addr.ip().to_string()),
+        actor: audit::pending(req).actor,
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Register {
     username: String,
     password: String,
+    email: Option<String>,
+    invitation_code: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct RegisterResponse {
-    token: Option<String>,
+/// How a registration ended, short of a database error.
+enum RegisterOutcome {
+    Registered(models::TokenPair),
+    Invalid(validation::ValidationErrors),
+}
+
+/// Checks a registration against the policy, short of the invitation code
+/// itself.
+fn check_register(
+    registration: &registration::Policy,
+    register: &Register,
+) -> Vec<validation::FieldError> {
+    let mut errors = Vec::new();
+    errors.extend(registration.check_username(&register.username));
+    errors.extend(registration.check_password(
+        "password",
+        &register.password,
+        Some(&register.username),
+    ));
+    if register
+        .email
+        .as_ref()
+        .map_or(false, |email| !email.contains('@'))
+    {
+        errors.push(validation::FieldError::new(
+            "email",
+            "invalid",
+            "must be an email address",
+        ));
+    }
+    if registration.mode == registration::Mode::Invite && register.invitation_code.is_none() {
+        errors.push(validation::FieldError::new(
+            "invitation_code",
+            "required",
+            "registration is by invitation only",
+        ));
+    }
+
+    errors
 }
 
+/// Creates an account under the registration policy. Invitations also work
+/// while registration is open, to sign someone up with a higher role.
 pub fn register(
+    req: HttpRequest,
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    registration: web::Data<registration::Registration>,
     register: web::Json<Register>,
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
-    web::block(move || {
-        let conn = &db.get().unwrap();
+    audit::pending(&req).set_target(format!("username:{}", register.username));
+    let client = client(&req);
 
-        conn.transaction(|| {
-            let user_id = models::User::create(conn, &register.username, &register.password, 0)?;
-            let token = models::Token::generate(conn, user_id)?;
+    let errors = check_register(&registration, &register);
 
-            Ok(token)
-        })
-    })
-    .and_then(|token| Ok(HttpResponse::Ok().json(RegisterResponse { token: Some(token) })))
-    .or_else(
-        |_: actix_web::error::BlockingError<diesel::result::Error>| {
-            Ok(HttpResponse::Conflict().finish())
-        },
-    )
+    if registration.mode == registration::Mode::Closed {
+        Either::B(ok(HttpResponse::Forbidden().finish()))
+    } else if !errors.is_empty() {
+        Either::B(ok(
+            HttpResponse::UnprocessableEntity().json(validation::ValidationErrors { errors })
+        ))
+    } else {
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                conn.transaction(|| {
+                    let invitation = match register.invitation_code {
+                        Some(ref code) => match models::Invitation::claim(conn, code)? {
+                            Some(invitation) => Some(invitation),
+                            None => {
+                                return Ok(RegisterOutcome::Invalid(validation::invalid(
+                                    validation::FieldError::new(
+                                        "invitation_code",
+                                        "invalid",
+                                        "is unknown, expired or already used",
+                                    ),
+                                )))
+                            }
+                        },
+                        None => None,
+                    };
+
+                    let user_id = models::User::create(
+                        conn,
+                        &register.username,
+                        &register.password,
+                        register.email.as_ref().map(String::as_str),
+                        invitation
+                            .as_ref()
+                            .map_or(models::Role::User.rank(), |invitation| invitation.rank),
+                    )?;
+
+                    if let Some(invitation) = invitation {
+                        invitation.mark_used(conn, user_id)?;
+                    }
+
+                    Ok(RegisterOutcome::Registered(models::Token::generate(
+                        conn, user_id, &client,
+                    )?))
+                })
+            })
+            .map(|outcome| match outcome {
+                RegisterOutcome::Registered(tokens) => HttpResponse::Ok().json(tokens),
+                RegisterOutcome::Invalid(errors) => {
+                    HttpResponse::UnprocessableEntity().json(errors)
+                }
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(
+                        diesel::result::Error::DatabaseError(
+                            diesel::result::DatabaseErrorKind::UniqueViolation,
+                            _,
+                        ),
+                    ) => Ok(HttpResponse::Conflict().json(validation::invalid(
+                        validation::FieldError::new("username", "taken", "is already taken"),
+                    ))),
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -50,50 +174,299 @@ pub struct Login {
     password: String,
 }
 
-#[derive(Serialize, Deserialize)]
-struct LoginResponse {
-    token: Option<String>,
+/// How a login attempt ended.
+enum LoginOutcome {
+    LoggedIn(models::TokenPair),
+    MfaRequired(String),
+    Rejected,
+    PasswordResetRequired,
+    /// Too many recent failures; try again after this many seconds.
+    Throttled(i64),
+}
+
+#[derive(Serialize)]
+struct MfaChallengeResponse {
+    mfa_challenge: String,
+    expires_in: i64,
+}
+
+impl LoginOutcome {
+    fn throttled_until(until: DateTime<Utc>) -> Self {
+        LoginOutcome::Throttled((until - Utc::now()).num_seconds() + 1)
+    }
+
+    fn into_response(self) -> HttpResponse {
+        match self {
+            LoginOutcome::LoggedIn(tokens) => HttpResponse::Ok().json(tokens),
+            LoginOutcome::MfaRequired(mfa_challenge) => {
+                HttpResponse::Ok().json(MfaChallengeResponse {
+                    mfa_challenge,
+                    expires_in: models::MfaChallenge::lifetime().num_seconds(),
+                })
+            }
+            LoginOutcome::Rejected => HttpResponse::Unauthorized().finish(),
+            LoginOutcome::PasswordResetRequired => HttpResponse::Forbidden().finish(),
+            LoginOutcome::Throttled(seconds) => HttpResponse::TooManyRequests()
+                .header(header::RETRY_AFTER, seconds.to_string())
+                .finish(),
+        }
+    }
 }
 
 pub fn login(
+    req: HttpRequest,
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    backend: web::Data<backend::Backend>,
     login: web::Json<Login>,
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    pending.set_target(format!("username:{}", login.username));
+    let client = client(&req);
+
     web::block(move || {
         let conn = &db.get().unwrap();
 
-        conn.transaction(|| {
-            let is_valid = models::User::verify_password(conn, &login.username, &login.password)?;
-            if is_valid {
-                let user = models::User::by_username(conn, &login.username)?;
+        conn.transaction::<_, models::Error, _>(|| {
+            let policy = models::LoginPolicy::from_env();
+            let locked_until =
+                models::LoginAttempt::locked_until(conn, &login.username, &client, &policy)?;
+            if let Some(locked_until) = locked_until {
+                return Ok(LoginOutcome::throttled_until(locked_until));
+            }
+
+            let user = match backend.authenticate(conn, &login.username, &login.password)? {
+                Some(user) => user,
+                None => {
+                    models::LoginAttempt::record(conn, &login.username, &client, false)?;
+                    warn!(
+                        "failed login for {} from {}",
+                        login.username,
+                        client
+                            .ip_address
+                            .as_ref()
+                            .map_or("unknown address", String::as_str)
+                    );
+                    return Ok(LoginOutcome::Rejected);
+                }
+            };
 
-                Ok((models::Token::generate(conn, user.id)?, is_valid))
+            if user.disabled {
+                Ok(LoginOutcome::Rejected)
+            } else if user.password_reset_required {
+                Ok(LoginOutcome::PasswordResetRequired)
+            } else if user.totp_enabled {
+                Ok(LoginOutcome::MfaRequired(models::MfaChallenge::create(
+                    conn, user.id,
+                )?))
             } else {
-                Ok(("".into(), is_valid))
+                // A login only succeeds once it gets tokens, so that a
+                // right password cannot clear failed second factors.
+                models::LoginAttempt::record(conn, &login.username, &client, true)?;
+                let tokens = models::Token::generate(conn, user.id, &client)?;
+                pending.record(conn, audit::Outcome::Success)?;
+
+                Ok(LoginOutcome::LoggedIn(tokens))
             }
         })
     })
-    .map(|(token, is_valid)| {
-        if is_valid {
-            HttpResponse::Ok().json(LoginResponse { token: Some(token) })
-        } else {
-            HttpResponse::Unauthorized().finish()
-        }
-    })
+    .map(LoginOutcome::into_response)
     .or_else(
-        |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
-            actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
-                Ok(HttpResponse::Unauthorized().finish())
+        |e: actix_web::error::BlockingError<models::Error>| match e {
+            actix_web::error::BlockingError::Error(models::Error::Authentication(e)) => {
+                error!("could not check credentials: {}", e);
+                Ok(HttpResponse::BadGateway().finish())
             }
             _ => Ok(HttpResponse::InternalServerError().finish()),
         },
     )
 }
 
+#[derive(Serialize)]
+pub struct OidcStartResponse {
+    authorization_url: String,
+    expires_in: i64,
+}
+
+/// Begins single sign-on. The client sends the user to `authorization_url`,
+/// and the provider sends them back to `OIDC_REDIRECT_URI` with a `code` and
+/// `state` for the client to post to the callback.
+pub fn oidc_start(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    oidc: web::Data<oidc::Oidc>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let provider = match oidc.get_ref() {
+        Some(provider) => provider.clone(),
+        None => return Either::B(ok(HttpResponse::NotFound().finish())),
+    };
+
+    Either::A(
+        web::block(move || -> Result<_, models::Error> {
+            let authorization = provider.authorize()?;
+            models::OidcLogin::create(&db.get().unwrap(), &authorization)?;
+
+            Ok(authorization.url)
+        })
+        .and_then(|authorization_url| {
+            Ok(HttpResponse::Ok().json(OidcStartResponse {
+                authorization_url,
+                expires_in: models::OidcLogin::lifetime().num_seconds(),
+            }))
+        })
+        .or_else(
+            |e: actix_web::error::BlockingError<models::Error>| match e {
+                actix_web::error::BlockingError::Error(models::Error::Oidc(e)) => {
+                    error!("could not start single sign-on: {}", e);
+                    Ok(HttpResponse::BadGateway().finish())
+                }
+                _ => Ok(HttpResponse::InternalServerError().finish()),
+            },
+        ),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallback {
+    code: String,
+    state: String,
+}
+
+/// Finishes single sign-on, logging the user in as they would with a
+/// password.
+pub fn oidc_callback(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    oidc: web::Data<oidc::Oidc>,
+    callback: web::Json<OidcCallback>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = client(&req);
+
+    match oidc.get_ref() {
+        Some(provider) => {
+            let provider = provider.clone();
+
+            Either::A(
+                web::block(move || -> Result<_, models::Error> {
+                    let conn = &db.get().unwrap();
+
+                    let login = match models::OidcLogin::redeem(conn, &callback.state)? {
+                        Some(login) => login,
+                        None => return Ok(LoginOutcome::Rejected),
+                    };
+
+                    let identity =
+                        match provider.exchange(&callback.code, &login.code_verifier, &login.nonce)
+                        {
+                            Ok(identity) => identity,
+                            Err(oidc::Error::InvalidToken(reason)) => {
+                                warn!("rejected single sign-on: {}", reason);
+                                return Ok(LoginOutcome::Rejected);
+                            }
+                            Err(e) => return Err(e.into()),
+                        };
+
+                    Ok(conn.transaction::<_, diesel::result::Error, _>(|| {
+                        let user = models::User::by_oidc_identity(conn, &identity)?;
+
+                        if user.disabled {
+                            Ok(LoginOutcome::Rejected)
+                        } else if user.totp_enabled {
+                            // Accounts locked out by wrong codes get no new
+                            // challenges until the lockout ends.
+                            let locked_until = models::LoginAttempt::locked_until(
+                                conn,
+                                &user.username,
+                                &client,
+                                &models::LoginPolicy::from_env(),
+                            )?;
+
+                            match locked_until {
+                                Some(locked_until) => {
+                                    Ok(LoginOutcome::throttled_until(locked_until))
+                                }
+                                None => Ok(LoginOutcome::MfaRequired(
+                                    models::MfaChallenge::create(conn, user.id)?,
+                                )),
+                            }
+                        } else {
+                            let tokens = models::Token::generate(conn, user.id, &client)?;
+                            pending.record(conn, audit::Outcome::Success)?;
+
+                            Ok(LoginOutcome::LoggedIn(tokens))
+                        }
+                    })?)
+                })
+                .map(LoginOutcome::into_response)
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Oidc(e)) => {
+                            error!("could not finish single sign-on: {}", e);
+                            Ok(HttpResponse::BadGateway().finish())
+                        }
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                ),
+            )
+        }
+        None => Either::B(ok(HttpResponse::NotFound().finish())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginMfa {
+    mfa_challenge: String,
+    code: String,
+}
+
+/// Completes a login started with a password by answering its challenge
+/// with a TOTP or recovery code. Wrong codes count towards the account's
+/// lockout like wrong passwords.
+pub fn login_mfa(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    login: web::Json<LoginMfa>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = client(&req);
+
+    web::block(move || {
+        let conn = &db.get().unwrap();
+        let policy = models::LoginPolicy::from_env();
+
+        let answer = models::MfaChallenge::answer(
+            conn,
+            &login.mfa_challenge,
+            &login.code,
+            &client,
+            &policy,
+        )?;
+
+        match answer {
+            models::MfaAnswer::Accepted(ref user) if !user.disabled => conn.transaction(|| {
+                models::LoginAttempt::record(conn, &user.username, &client, true)?;
+                let tokens = models::Token::generate(conn, user.id, &client)?;
+                pending.record(conn, audit::Outcome::Success)?;
+
+                Ok(LoginOutcome::LoggedIn(tokens))
+            }),
+            models::MfaAnswer::Locked(until) => Ok(LoginOutcome::throttled_until(until)),
+            _ => Ok(LoginOutcome::Rejected),
+        }
+    })
+    .map(LoginOutcome::into_response)
+    .or_else(
+        |_: actix_web::error::BlockingError<diesel::result::Error>| {
+            Ok(HttpResponse::InternalServerError().finish())
+        },
+    )
+}
+
 pub fn logout(
     req: HttpRequest,
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = client(&req);
+
     if let Some(token) = &req.headers().get(header::AUTHORIZATION) {
         let token = token.to_str().unwrap().to_string();
 
@@ -102,7 +475,7 @@ pub fn logout(
                 let conn = &db.get().unwrap();
 
                 conn.transaction(|| {
-                    if models::Token::user_by_token(conn, &token).is_ok() {
+                    if models::Token::user_by_token(conn, &token, &client).is_ok() {
                         models::Token::destroy(conn, &token)?;
                         Ok(true)
                     } else {
@@ -127,3 +500,513 @@ pub fn logout(
         Either::B(ok(HttpResponse::Unauthorized().finish()))
     }
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct Refresh {
+    refresh_token: String,
+}
+
+pub fn refresh(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    refresh: web::Json<Refresh>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = client(&req);
+
+    web::block(move || models::Token::refresh(&db.get().unwrap(), &refresh.refresh_token, &client))
+        .map(|tokens| match tokens {
+            Some(tokens) => HttpResponse::Ok().json(tokens),
+            None => HttpResponse::Unauthorized().finish(),
+        })
+        .or_else(
+            |_: actix_web::error::BlockingError<diesel::result::Error>| {
+                Ok(HttpResponse::InternalServerError().finish())
+            },
+        )
+}
+
+#[derive(Serialize)]
+pub struct SessionsResponse {
+    sessions: Vec<models::Session>,
+}
+
+pub fn sessions(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                models::Token::sessions_for_user(conn, user.id)
+            })
+            .and_then(|sessions| Ok(HttpResponse::Ok().json(SessionsResponse { sessions })))
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SessionPath {
+    pub session_id: String,
+}
+
+pub fn revoke_session(
+    req: HttpRequest,
+    path: web::Path<SessionPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                conn.transaction(|| {
+                    let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                    let revoked = models::Token::revoke_session(conn, user.id, &path.session_id)?;
+                    if revoked {
+                        pending.record(conn, audit::Outcome::Success)?;
+                    }
+
+                    Ok(revoked)
+                })
+            })
+            .and_then(|revoked| {
+                if revoked {
+                    Ok(HttpResponse::Ok().finish())
+                } else {
+                    Ok(HttpResponse::NotFound().finish())
+                }
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+/// Logs the caller out everywhere, including the session making the request.
+pub fn revoke_all_sessions(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                conn.transaction(|| {
+                    let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                    models::Token::revoke_all(conn, user.id)?;
+                    pending.record(conn, audit::Outcome::Success)
+                })
+            })
+            .and_then(|_| Ok(HttpResponse::Ok().finish()))
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    current_password: String,
+    new_password: String,
+}
+
+/// Changes the caller's password and ends their other sessions.
+pub fn change_password(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    registration: web::Data<registration::Registration>,
+    change: web::Json<ChangePassword>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = client(&req);
+
+    let error = registration.check_password("new_password", &change.new_password, None);
+
+    match (error, req.headers().get(header::AUTHORIZATION)) {
+        (Some(error), _) => Either::B(ok(
+            HttpResponse::UnprocessableEntity().json(validation::invalid(error))
+        )),
+        (None, Some(token)) => {
+            let token = token.to_str().unwrap().to_string();
+
+            Either::A(
+                web::block(move || {
+                    let conn = &db.get().unwrap();
+
+                    conn.transaction(|| {
+                        let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                        if !models::User::verify_password(
+                            conn,
+                            &user.username,
+                            &change.current_password,
+                        )? {
+                            return Ok(false);
+                        }
+
+                        models::User::set_password(conn, user.id, &change.new_password)?;
+                        models::Token::revoke_others(conn, user.id, &token)?;
+                        pending.record(conn, audit::Outcome::Success)?;
+
+                        Ok(true)
+                    })
+                })
+                .and_then(|is_changed| {
+                    if is_changed {
+                        Ok(HttpResponse::Ok().finish())
+                    } else {
+                        Ok(HttpResponse::Forbidden().finish())
+                    }
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                        actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                            Ok(HttpResponse::Unauthorized().finish())
+                        }
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                ),
+            )
+        }
+        (None, None) => Either::B(ok(HttpResponse::Unauthorized().finish())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    username: String,
+}
+
+/// Mails a reset token to the user, if they have an address on file and
+/// have not asked for too many already.
+///
+/// The answer is always `200 OK`, whether or not the account exists and
+/// even when something fails, and the mail is only queued, so that neither
+/// the response nor its timing tells who has an account.
+pub fn forgot_password(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    notifier: web::Data<notify::Notify>,
+    forgot: web::Json<ForgotPassword>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    audit::pending(&req).set_target(format!("username:{}", forgot.username));
+
+    web::block(move || -> Result<_, models::Error> {
+        let conn = &db.get().unwrap();
+
+        let user = match models::User::by_username(conn, &forgot.username) {
+            Ok(user) => user,
+            Err(diesel::result::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let email = match user.email {
+            Some(ref email) if !user.disabled => email,
+            _ => return Ok(()),
+        };
+
+        let token = match models::PasswordReset::create(conn, user.id)? {
+            Some(token) => token,
+            None => {
+                warn!("too many password resets pending for {}", user.username);
+                return Ok(());
+            }
+        };
+        let link = match env::var("PASSWORD_RESET_URL") {
+            Ok(url) => format!("{}{}", url, token),
+            Err(_) => token,
+        };
+
+        notifier.send(
+            email,
+            "Password reset",
+            &format!(
+                "Someone asked to reset the password of your account {}.\n\n\
+                 To choose a new one within the next {} minutes, use:\n\n{}\n\n\
+                 If that was not you, you can ignore this message.\n",
+                user.username,
+                models::PasswordReset::lifetime().num_minutes(),
+                link
+            ),
+        )?;
+
+        Ok(())
+    })
+    .then(|result| {
+        if let Err(e) = result {
+            error!("could not start password reset: {}", e);
+        }
+
+        Ok(HttpResponse::Ok().finish())
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    token: String,
+    new_password: String,
+}
+
+/// Sets a new password with a mailed reset token and ends every session.
+pub fn reset_password(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    registration: web::Data<registration::Registration>,
+    reset: web::Json<ResetPassword>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = client(&req);
+
+    // Checked before the token is used up, so that a refused password can be
+    // retried with the same link.
+    if let Some(error) = registration.check_password("new_password", &reset.new_password, None) {
+        Either::B(ok(
+            HttpResponse::UnprocessableEntity().json(validation::invalid(error))
+        ))
+    } else {
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                conn.transaction(
+                    || match models::PasswordReset::redeem(conn, &reset.token)? {
+                        Some(user_id) => {
+                            client.identify(user_id);
+                            models::User::set_password(conn, user_id, &reset.new_password)?;
+                            models::Token::revoke_all(conn, user_id)?;
+                            pending.record(conn, audit::Outcome::Success)?;
+
+                            Ok(true)
+                        }
+                        None => Ok(false),
+                    },
+                )
+            })
+            .and_then(|is_reset| {
+                if is_reset {
+                    Ok(HttpResponse::Ok().finish())
+                } else {
+                    Ok(HttpResponse::Unauthorized().finish())
+                }
+            })
+            .or_else(
+                |_: actix_web::error::BlockingError<diesel::result::Error>| {
+                    Ok(HttpResponse::InternalServerError().finish())
+                },
+            ),
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    secret: String,
+    provisioning_uri: String,
+}
+
+/// Hands out a new TOTP secret for the caller to add to their authenticator
+/// app. Two-factor authentication only starts once a code is confirmed.
+pub fn begin_totp(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                if user.totp_enabled {
+                    return Ok(None);
+                }
+
+                let secret = models::User::begin_totp_enrollment(conn, user.id)?;
+                let issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| "Violetear".into());
+
+                Ok(Some(TotpEnrollment {
+                    provisioning_uri: totp::provisioning_uri(&issuer, &user.username, &secret),
+                    secret,
+                }))
+            })
+            .and_then(|enrollment| match enrollment {
+                Some(enrollment) => Ok(HttpResponse::Ok().json(enrollment)),
+                None => Ok(HttpResponse::Conflict().finish()),
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmTotp {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Turns on two-factor authentication and returns the recovery codes, which
+/// are only ever shown here.
+pub fn confirm_totp(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    confirm: web::Json<ConfirmTotp>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                conn.transaction(|| {
+                    let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                    let recovery_codes = models::User::confirm_totp(conn, user.id, &confirm.code)?;
+                    if recovery_codes.is_some() {
+                        pending.record(conn, audit::Outcome::Success)?;
+                    }
+
+                    Ok(recovery_codes)
+                })
+            })
+            .and_then(|recovery_codes| match recovery_codes {
+                Some(recovery_codes) => {
+                    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+                }
+                None => Ok(HttpResponse::Forbidden().finish()),
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DisableTotp {
+    password: String,
+}
+
+/// Turns two-factor authentication off, which takes the account password.
+pub fn disable_totp(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    disable: web::Json<DisableTotp>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+    let client = client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                conn.transaction(|| {
+                    let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                    if !models::User::verify_password(conn, &user.username, &disable.password)? {
+                        return Ok(false);
+                    }
+
+                    models::User::disable_totp(conn, user.id)?;
+                    pending.record(conn, audit::Outcome::Success)?;
+
+                    Ok(true)
+                })
+            })
+            .and_then(|is_disabled| {
+                if is_disabled {
+                    Ok(HttpResponse::Ok().finish())
+                } else {
+                    Ok(HttpResponse::Forbidden().finish())
+                }
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
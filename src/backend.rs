@@ -0,0 +1,372 @@
+use std::{collections::HashMap, env, io, sync::Arc};
+
+use diesel::PgConnection;
+use ldap3::{ldap_escape, LdapConn, LdapConnSettings, Scope, SearchEntry};
+use log::{error, warn};
+
+use crate::models::{self, Role};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Database(err: diesel::result::Error) {
+            from()
+            cause(err)
+            display("database error: {}", err)
+        }
+        Directory(err: io::Error) {
+            from()
+            cause(err)
+            display("directory error: {}", err)
+        }
+    }
+}
+
+/// Checks usernames and passwords against wherever accounts live.
+pub trait AuthBackend: Send + Sync {
+    /// Returns the user the password belongs to, or `None` if it is wrong or
+    /// there is no such user.
+    fn authenticate(
+        &self,
+        conn: &PgConnection,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<models::User>, Error>;
+}
+
+pub type Backend = Arc<dyn AuthBackend>;
+
+/// Builds the backend selected by `AUTH_BACKEND` (`local` or `ldap`).
+///
+/// The LDAP backend reads `LDAP_URL`, `LDAP_BIND_DN` and `LDAP_BIND_PASSWORD`
+/// for the account it searches with (anonymous if unset), `LDAP_BASE_DN`,
+/// `LDAP_USER_FILTER`, `LDAP_EMAIL_ATTRIBUTE`, `LDAP_GROUP_ATTRIBUTE`,
+/// `LDAP_ROLE_MAPPING`, `LDAP_STARTTLS`, `LDAP_TLS_NO_VERIFY`,
+/// `LDAP_LOCAL_FALLBACK` and `LDAP_LINK_BY_USERNAME`.
+///
+/// `LDAP_ROLE_MAPPING` is a `;`-separated list of `group DN=>role` pairs.
+/// The first group the user is a member of decides their role.
+pub fn from_env() -> Backend {
+    match env::var("AUTH_BACKEND").as_ref().map(String::as_str) {
+        Ok("ldap") => {
+            let flag = |name: &str, default: bool| {
+                env::var(name)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(default)
+            };
+
+            let role_mapping = env::var("LDAP_ROLE_MAPPING")
+                .unwrap_or_default()
+                .split(';')
+                .filter(|pair| !pair.trim().is_empty())
+                .map(|pair| {
+                    let mut parts = pair.rsplitn(2, "=>");
+                    let role = parts.next().and_then(|role| Role::from_name(role.trim()));
+                    let group = parts.next().map(|group| group.trim().to_lowercase());
+
+                    match (group, role) {
+                        (Some(group), Some(role)) => (group, role),
+                        _ => panic!("invalid ldap role mapping: {}", pair),
+                    }
+                })
+                .collect();
+
+            Arc::new(LdapBackend {
+                url: env::var("LDAP_URL").expect("incomplete ldap configuration"),
+                bind: match (env::var("LDAP_BIND_DN"), env::var("LDAP_BIND_PASSWORD")) {
+                    (Ok(dn), Ok(password)) => Some((dn, password)),
+                    _ => None,
+                },
+                base_dn: env::var("LDAP_BASE_DN").expect("incomplete ldap configuration"),
+                user_filter: env::var("LDAP_USER_FILTER")
+                    .unwrap_or_else(|_| "(uid={username})".into()),
+                email_attribute: env::var("LDAP_EMAIL_ATTRIBUTE").unwrap_or_else(|_| "mail".into()),
+                group_attribute: env::var("LDAP_GROUP_ATTRIBUTE")
+                    .unwrap_or_else(|_| "memberOf".into()),
+                role_mapping,
+                starttls: flag("LDAP_STARTTLS", false),
+                tls_no_verify: flag("LDAP_TLS_NO_VERIFY", false),
+                local_fallback: flag("LDAP_LOCAL_FALLBACK", false),
+                link_by_username: flag("LDAP_LINK_BY_USERNAME", false),
+            })
+        }
+        Ok("local") | Err(_) => Arc::new(LocalBackend),
+        Ok(other) => panic!("unknown auth backend: {}", other),
+    }
+}
+
+/// Passwords hashed with bcrypt in `users.hashed_password`.
+pub struct LocalBackend;
+
+impl AuthBackend for LocalBackend {
+    fn authenticate(
+        &self,
+        conn: &PgConnection,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<models::User>, Error> {
+        if models::User::verify_password(conn, username, password)? {
+            Ok(Some(models::User::by_username(conn, username)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// What the directory knows about someone who logged in.
+pub struct DirectoryEntry {
+    pub dn: String,
+    pub username: String,
+    pub email: Option<String>,
+    /// The role their groups map to, if any of them do.
+    pub role: Option<Role>,
+}
+
+/// Binds as the user against an LDAP directory or Active Directory, after
+/// looking their entry up with `user_filter`. Accounts are created locally
+/// on first login. Existing local accounts with the same username are only
+/// linked to the directory with `link_by_username`.
+///
+/// With `local_fallback`, which is off unless `LDAP_LOCAL_FALLBACK` is set,
+/// users the directory does not know, and everyone while it is unreachable,
+/// are checked against local passwords instead. Accounts that have ever
+/// logged in through the directory are never checked locally, so neither a
+/// wrong directory password nor an outage gets them a second chance.
+pub struct LdapBackend {
+    url: String,
+    bind: Option<(String, String)>,
+    base_dn: String,
+    user_filter: String,
+    email_attribute: String,
+    group_attribute: String,
+    role_mapping: Vec<(String, Role)>,
+    starttls: bool,
+    tls_no_verify: bool,
+    local_fallback: bool,
+    link_by_username: bool,
+}
+
+/// How a directory login went.
+enum Lookup {
+    Authenticated(DirectoryEntry),
+    WrongPassword,
+    UnknownUser,
+}
+
+impl LdapBackend {
+    /// Local passwords, for accounts the directory has never vouched for.
+    fn authenticate_locally(
+        conn: &PgConnection,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<models::User>, Error> {
+        match LocalBackend.authenticate(conn, username, password)? {
+            Some(ref user) if user.ldap_dn.is_some() => Ok(None),
+            user => Ok(user),
+        }
+    }
+
+    fn lookup(&self, username: &str, password: &str) -> Result<Lookup, io::Error> {
+        let ldap = LdapConn::with_settings(
+            LdapConnSettings::new()
+                .set_starttls(self.starttls)
+                .set_no_tls_verify(self.tls_no_verify),
+            &self.url,
+        )?;
+
+        match self.bind {
+            Some((ref dn, ref password)) => ldap.simple_bind(dn, password)?.success()?,
+            None => ldap.simple_bind("", "")?.success()?,
+        };
+
+        let filter = self
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(
+                &self.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![self.email_attribute.as_str(), self.group_attribute.as_str()],
+            )?
+            .success()?;
+
+        // Anything but exactly one match is ambiguous.
+        if entries.len() != 1 {
+            // Bind once anyway, as a wrong password would, so that response
+            // times do not reveal which usernames exist. The outcome does not
+            // matter.
+            if !password.is_empty() {
+                let _ = ldap.simple_bind(&format!("cn=unknown-user,{}", self.base_dn), password);
+            }
+
+            return Ok(Lookup::UnknownUser);
+        }
+
+        let entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+
+        // An empty password would make this an anonymous bind, which most
+        // directories accept.
+        if password.is_empty() || ldap.simple_bind(&entry.dn, password)?.success().is_err() {
+            return Ok(Lookup::WrongPassword);
+        }
+
+        let attrs: HashMap<String, Vec<String>> = entry
+            .attrs
+            .into_iter()
+            .map(|(name, values)| (name.to_lowercase(), values))
+            .collect();
+        let groups: Vec<String> = attrs
+            .get(&self.group_attribute.to_lowercase())
+            .map(|groups| groups.iter().map(|group| group.to_lowercase()).collect())
+            .unwrap_or_default();
+
+        Ok(Lookup::Authenticated(DirectoryEntry {
+            dn: entry.dn,
+            username: username.to_string(),
+            email: attrs
+                .get(&self.email_attribute.to_lowercase())
+                .and_then(|emails| emails.first().cloned()),
+            role: self
+                .role_mapping
+                .iter()
+                .find(|(group, _)| groups.contains(group))
+                .map(|(_, role)| *role),
+        }))
+    }
+}
+
+impl AuthBackend for LdapBackend {
+    fn authenticate(
+        &self,
+        conn: &PgConnection,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<models::User>, Error> {
+        match self.lookup(username, password) {
+            Ok(Lookup::Authenticated(entry)) => {
+                let user = models::User::by_directory_entry(conn, &entry, self.link_by_username)?;
+
+                if user.is_none() {
+                    warn!(
+                        "directory user {} matches an unlinked local account, refusing login",
+                        username
+                    );
+                }
+
+                Ok(user)
+            }
+            Ok(Lookup::WrongPassword) => Ok(None),
+            Ok(Lookup::UnknownUser) if self.local_fallback => {
+                Self::authenticate_locally(conn, username, password)
+            }
+            Ok(Lookup::UnknownUser) => Ok(None),
+            Err(e) if self.local_fallback => {
+                error!(
+                    "directory unavailable, falling back to local accounts: {}",
+                    e
+                );
+                Self::authenticate_locally(conn, username, password)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Logs in against a real directory. Run with `cargo test -- --ignored`
+    /// against OpenLDAP, for example `docker run -p 389:389 osixia/openldap`,
+    /// whose defaults match the ones below. The test adds and removes its own
+    /// user, as the account in `LDAP_TEST_BIND_DN`.
+    #[test]
+    #[ignore]
+    fn ldap_lookup_against_openldap() {
+        let url = env::var("LDAP_TEST_URL").unwrap_or_else(|_| "ldap://127.0.0.1:389".into());
+        let base_dn = env::var("LDAP_TEST_BASE_DN").unwrap_or_else(|_| "dc=example,dc=org".into());
+        let bind_dn =
+            env::var("LDAP_TEST_BIND_DN").unwrap_or_else(|_| format!("cn=admin,{}", base_dn));
+        let bind_password = env::var("LDAP_TEST_BIND_PASSWORD").unwrap_or_else(|_| "admin".into());
+
+        let username = format!("test-{}", uuid::Uuid::new_v4().to_simple());
+        let dn = format!("uid={},{}", username, base_dn);
+        let values = |values: &[&'static str]| values.iter().cloned().collect::<HashSet<_>>();
+
+        let admin = LdapConn::new(&url).unwrap();
+        admin
+            .simple_bind(&bind_dn, &bind_password)
+            .unwrap()
+            .success()
+            .unwrap();
+        admin
+            .add(
+                &dn,
+                vec![
+                    ("objectClass", values(&["inetOrgPerson"])),
+                    ("uid", [username.as_str()].iter().cloned().collect()),
+                    ("cn", values(&["Test User"])),
+                    ("sn", values(&["User"])),
+                    ("mail", values(&["test@example.org"])),
+                    ("departmentNumber", values(&["Analysts", "Other"])),
+                    ("userPassword", values(&["correct horse"])),
+                ],
+            )
+            .unwrap()
+            .success()
+            .unwrap();
+
+        let backend = LdapBackend {
+            url,
+            bind: Some((bind_dn, bind_password)),
+            base_dn,
+            user_filter: "(uid={username})".into(),
+            email_attribute: "mail".into(),
+            group_attribute: "departmentNumber".into(),
+            role_mapping: vec![
+                ("admins".into(), Role::Admin),
+                ("analysts".into(), Role::Analyst),
+            ],
+            starttls: false,
+            tls_no_verify: false,
+            local_fallback: false,
+            link_by_username: false,
+        };
+
+        let authenticated = backend.lookup(&username, "correct horse");
+        let wrong_password = backend.lookup(&username, "wrong");
+        let empty_password = backend.lookup(&username, "");
+        let unknown_user = backend.lookup("nobody-at-all", "correct horse");
+
+        admin.delete(&dn).unwrap().success().unwrap();
+
+        match authenticated.unwrap() {
+            Lookup::Authenticated(entry) => {
+                assert_eq!(entry.dn.to_lowercase(), dn.to_lowercase());
+                assert_eq!(entry.username, username);
+                assert_eq!(
+                    entry.email.as_ref().map(String::as_str),
+                    Some("test@example.org")
+                );
+                assert_eq!(entry.role, Some(Role::Analyst));
+            }
+            _ => panic!("the right password was refused"),
+        }
+        match wrong_password.unwrap() {
+            Lookup::WrongPassword => {}
+            _ => panic!("a wrong password was not refused"),
+        }
+        match empty_password.unwrap() {
+            Lookup::WrongPassword => {}
+            _ => panic!("an empty password was not refused"),
+        }
+        match unknown_user.unwrap() {
+            Lookup::UnknownUser => {}
+            _ => panic!("an unknown user was found"),
+        }
+    }
+}
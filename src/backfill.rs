@@ -0,0 +1,62 @@
+use diesel::{Connection, PgConnection};
+use log::{info, warn};
+
+use crate::models;
+use crate::storage::{self, Store};
+
+/// Moves report files still held in the old `reports.file` column into the
+/// blob store, one report per transaction, and returns how many were moved.
+///
+/// This has to finish before the migration dropping that column can run.
+pub fn run(conn: &PgConnection, store: &Store) -> Result<usize, models::Error> {
+    let mut moved = 0;
+
+    while backfill_next(conn, store)? {
+        moved += 1;
+
+        if moved % 100 == 0 {
+            info!("backfilled {} report files so far", moved);
+        }
+    }
+
+    Ok(moved)
+}
+
+fn backfill_next(conn: &PgConnection, store: &Store) -> Result<bool, models::Error> {
+    conn.transaction(|| {
+        let legacy = match models::LegacyFile::next(conn)? {
+            Some(legacy) => legacy,
+            None => return Ok(false),
+        };
+
+        if legacy.has_file {
+            let upload = storage::Upload::new()
+                .and_then(|upload| upload.write_all(&legacy.file))
+                .and_then(storage::Upload::finish)
+                .map_err(storage::Error::from)?;
+
+            if upload.multihash != legacy.file_multihash {
+                warn!(
+                    "report {} was filed as {} but its file hashes to {}",
+                    legacy.id, legacy.file_multihash, upload.multihash
+                );
+            }
+
+            // The sample row is stored first so that a failed upload rolls it
+            // back along with everything else.
+            if models::Sample::backfill(
+                conn,
+                &legacy.file_multihash,
+                &upload.multihash,
+                &upload.digests,
+                upload.size as i64,
+            )? {
+                store.put_file(&upload.multihash, &upload.path)?;
+            }
+        }
+
+        legacy.clear(conn)?;
+
+        Ok(true)
+    })
+}
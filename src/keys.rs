@@ -0,0 +1,177 @@
+use actix_web::{http::header, web, Error as AWError, HttpRequest, HttpResponse};
+use chrono::prelude::*;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    Connection, PgConnection,
+};
+use futures::{
+    future::{ok, Either},
+    Future,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::audit;
+use crate::auth;
+use crate::models;
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    keys: Vec<models::ApiKey>,
+}
+
+/// Lists the caller's API keys. Managing keys takes a login session; an API
+/// key cannot be used to see or mint others.
+pub fn list(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+        let client = auth::client(&req);
+
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                models::ApiKey::list_for_user(conn, user.id)
+            })
+            .and_then(|keys| Ok(HttpResponse::Ok().json(ListResponse { keys })))
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateRequest {
+    name: String,
+    scopes: Vec<models::Scope>,
+    #[serde(default)]
+    allowed_ips: Vec<String>,
+    expires_when: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct CreateResponse {
+    #[serde(flatten)]
+    api_key: models::ApiKey,
+    key: String,
+}
+
+pub fn create(
+    req: HttpRequest,
+    create: web::Json<CreateRequest>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let is_valid = !create.name.is_empty()
+        && !create.scopes.is_empty()
+        && create
+            .allowed_ips
+            .iter()
+            .all(|range| models::ApiKey::is_valid_ip_range(range));
+
+    match req.headers().get(header::AUTHORIZATION) {
+        Some(_) if !is_valid => Either::B(ok(HttpResponse::BadRequest().finish())),
+        Some(token) => {
+            let token = token.to_str().unwrap().to_string();
+            let pending = audit::pending(&req);
+            let client = auth::client(&req);
+
+            Either::A(
+                web::block(move || {
+                    let conn = &db.get().unwrap();
+
+                    conn.transaction(|| {
+                        let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                        let (api_key, key) = models::ApiKey::create(
+                            conn,
+                            user.id,
+                            &create.name,
+                            &create.scopes,
+                            &create.allowed_ips,
+                            create.expires_when,
+                        )?;
+                        pending.set_target(format!("key:{}", api_key.id));
+                        pending.record(conn, audit::Outcome::Success)?;
+
+                        Ok((api_key, key))
+                    })
+                })
+                .and_then(|(api_key, key)| {
+                    Ok(HttpResponse::Ok().json(CreateResponse { api_key, key }))
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                        actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                            Ok(HttpResponse::Unauthorized().finish())
+                        }
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                ),
+            )
+        }
+        None => Either::B(ok(HttpResponse::Unauthorized().finish())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ByIdPath {
+    pub key_id: i64,
+}
+
+pub fn revoke(
+    req: HttpRequest,
+    path: web::Path<ByIdPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+        let pending = audit::pending(&req);
+        let client = auth::client(&req);
+
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                conn.transaction(|| {
+                    let user = models::Token::user_by_token(conn, &token, &client)?;
+
+                    let revoked = models::ApiKey::revoke(conn, user.id, path.key_id)?;
+                    if revoked {
+                        pending.record(conn, audit::Outcome::Success)?;
+                    }
+
+                    Ok(revoked)
+                })
+            })
+            .and_then(|revoked| {
+                if revoked {
+                    Ok(HttpResponse::Ok().finish())
+                } else {
+                    Ok(HttpResponse::NotFound().finish())
+                }
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
@@ -6,7 +6,10 @@ extern crate quick_error;
 use std::env;
 
 use actix_cors::Cors;
-use actix_web::{http::header, middleware, web, App, HttpServer};
+use actix_web::{
+    http::{header, Method},
+    middleware, web, App, HttpServer,
+};
 use diesel::{
     pg::PgConnection,
     r2d2::{self, ConnectionManager},
@@ -14,17 +17,33 @@ use diesel::{
 
 use dotenv::dotenv;
 
+mod admin;
+mod archive;
+mod audit;
 mod auth;
+mod keys;
 mod profiles;
+mod reaper;
 mod reports;
 mod routes;
+mod samples;
 mod tasks;
+mod workers;
 
+mod backend;
+mod backfill;
 mod models;
+mod notify;
+mod oidc;
+mod registration;
 mod schema;
+mod secrets;
+mod storage;
+mod totp;
+mod validation;
 
 fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_server=info,actix_web=info");
+    std::env::set_var("RUST_LOG", "actix_server=info,actix_web=info,web_api=info");
     env_logger::init();
 
     dotenv().ok();
@@ -43,6 +62,11 @@ fn main() -> std::io::Result<()> {
                 .takes_value(true)
                 .number_of_values(1),
         )
+        .arg(
+            clap::Arg::with_name("backfill-files")
+                .long("backfill-files")
+                .help("Moves report files left in the database into the blob store, then exits"),
+        )
         .get_matches();
 
     let manager = ConnectionManager::<PgConnection>::new(
@@ -51,6 +75,25 @@ fn main() -> std::io::Result<()> {
 
     let pool = r2d2::Pool::new(manager).unwrap();
 
+    let store = storage::from_env();
+
+    if args.is_present("backfill-files") {
+        let moved = backfill::run(&pool.get().unwrap(), &store)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+
+        log::info!("backfilled {} report files into the blob store", moved);
+
+        return Ok(());
+    }
+
+    reaper::spawn(pool.clone());
+
+    let notifier = notify::from_env();
+    let registration = registration::from_env();
+    let oidc = oidc::from_env();
+    let backend = backend::from_env();
+    let audit = audit::spawn(pool.clone());
+
     HttpServer::new(move || {
         App::new()
             .wrap(
@@ -58,17 +101,23 @@ fn main() -> std::io::Result<()> {
                     .allowed_origin(
                         &env::var("CORS_ORIGIN").unwrap_or_else(|_| "http://[::1]:8000".into()),
                     )
-                    .allowed_methods(vec!["GET", "POST"])
+                    .allowed_methods(vec!["GET", "POST", "DELETE"])
                     .allowed_headers(vec![
                         header::AUTHORIZATION,
                         header::ACCEPT,
                         header::CONTENT_TYPE,
                         header::ACCEPT_ENCODING,
                         header::ACCEPT_LANGUAGE,
+                        header::HeaderName::from_static(reports::ARCHIVE_PASSWORD_HEADER),
                     ])
                     .max_age(3600),
             )
             .data(pool.clone())
+            .data(store.clone())
+            .data(notifier.clone())
+            .data(registration.clone())
+            .data(oidc.clone())
+            .data(backend.clone())
             .wrap(middleware::DefaultHeaders::new())
             .wrap(middleware::Compress::default())
             .wrap(middleware::Logger::default())
@@ -80,29 +129,383 @@ fn main() -> std::io::Result<()> {
                             .service(
                                 web::resource("/login")
                                     .data(web::JsonConfig::default().limit(4096))
-                                    .route(web::post().to_async(auth::login)),
+                                    .route(web::post().to_async(auth::login))
+                                    .wrap(
+                                        audit::Audited::new(&audit).on(Method::POST, "auth.login"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/login/mfa")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(auth::login_mfa))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.login_mfa"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/oidc/start")
+                                    .route(web::get().to_async(auth::oidc_start))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::GET, "auth.oidc_start"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/oidc/callback")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(auth::oidc_callback))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.oidc_callback"),
+                                    ),
                             )
                             .service(
                                 web::resource("/register")
                                     .data(web::JsonConfig::default().limit(4096))
-                                    .route(web::post().to_async(auth::register)),
+                                    .route(web::post().to_async(auth::register))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.register"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/refresh")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(auth::refresh))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.refresh"),
+                                    ),
                             )
                             .service(
                                 web::resource("/logout")
                                     .data(web::JsonConfig::default().limit(4096))
-                                    .route(web::post().to_async(auth::logout)),
+                                    .route(web::post().to_async(auth::logout))
+                                    .wrap(
+                                        audit::Audited::new(&audit).on(Method::POST, "auth.logout"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/password")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(auth::change_password))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.password.change"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/password/forgot")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(auth::forgot_password))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.password.forgot"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/password/reset")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(auth::reset_password))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.password.reset"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/totp")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(auth::begin_totp))
+                                    .route(web::delete().to_async(auth::disable_totp))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.totp.enroll")
+                                            .on(Method::DELETE, "auth.totp.disable"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/totp/confirm")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(auth::confirm_totp))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "auth.totp.confirm"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/sessions")
+                                    .route(web::get().to_async(auth::sessions))
+                                    .route(web::delete().to_async(auth::revoke_all_sessions))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::GET, "auth.sessions.list")
+                                            .on(Method::DELETE, "auth.sessions.revoke_all"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/sessions/{session_id}")
+                                    .route(web::delete().to_async(auth::revoke_session))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::DELETE, "auth.sessions.revoke")
+                                            .target("session", "session_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/keys")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::get().to_async(keys::list))
+                                    .route(web::post().to_async(keys::create))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::GET, "auth.keys.list")
+                                            .on(Method::POST, "auth.keys.create"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/keys/{key_id}")
+                                    .route(web::delete().to_async(keys::revoke))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::DELETE, "auth.keys.revoke")
+                                            .target("key", "key_id"),
+                                    ),
+                            ),
+                    )
+                    .service(
+                        web::resource("/profiles")
+                            .data(web::JsonConfig::default().limit(65_536))
+                            .route(web::get().to_async(profiles::list))
+                            .route(web::post().to_async(profiles::create))
+                            .wrap(audit::Audited::new(&audit).on(Method::POST, "profiles.create")),
+                    )
+                    .service(
+                        web::resource("/admin/mfa-policy")
+                            .data(web::JsonConfig::default().limit(4096))
+                            .route(web::get().to_async(admin::mfa_policy))
+                            .route(web::post().to_async(admin::set_mfa_policy))
+                            .wrap(
+                                audit::Audited::new(&audit)
+                                    .on(Method::GET, "admin.mfa_policy.view")
+                                    .on(Method::POST, "admin.mfa_policy.set"),
+                            ),
+                    )
+                    .service(
+                        web::resource("/admin/audit-events")
+                            .route(web::get().to_async(admin::audit_events))
+                            .wrap(
+                                audit::Audited::new(&audit)
+                                    .on(Method::GET, "admin.audit_events.list"),
+                            ),
+                    )
+                    .service(
+                        web::resource("/admin/reports/profiles")
+                            .data(web::JsonConfig::default().limit(4096))
+                            .route(web::post().to_async(admin::add_profile_to_reports))
+                            .wrap(
+                                audit::Audited::new(&audit)
+                                    .on(Method::POST, "admin.reports.add_profile"),
+                            ),
+                    )
+                    .service(
+                        web::resource("/admin/invitations")
+                            .data(web::JsonConfig::default().limit(4096))
+                            .route(web::get().to_async(admin::invitations))
+                            .route(web::post().to_async(admin::invite))
+                            .wrap(
+                                audit::Audited::new(&audit)
+                                    .on(Method::GET, "admin.invitations.list")
+                                    .on(Method::POST, "admin.invitations.create"),
+                            ),
+                    )
+                    .service(
+                        web::resource("/admin/invitations/{invitation_id}")
+                            .route(web::delete().to_async(admin::revoke_invitation))
+                            .wrap(
+                                audit::Audited::new(&audit)
+                                    .on(Method::DELETE, "admin.invitations.revoke")
+                                    .target("invitation", "invitation_id"),
+                            ),
+                    )
+                    .service(
+                        web::scope("/admin/users")
+                            .service(
+                                web::resource("")
+                                    .route(web::get().to_async(admin::list))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::GET, "admin.users.list"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{user_id}")
+                                    .route(web::delete().to_async(admin::delete))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::DELETE, "admin.users.delete")
+                                            .target("user", "user_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{user_id}/role")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(admin::set_role))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "admin.users.set_role")
+                                            .target("user", "user_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{user_id}/disable")
+                                    .route(web::post().to_async(admin::disable))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "admin.users.disable")
+                                            .target("user", "user_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{user_id}/enable")
+                                    .route(web::post().to_async(admin::enable))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "admin.users.enable")
+                                            .target("user", "user_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{user_id}/password-reset")
+                                    .route(web::post().to_async(admin::require_password_reset))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "admin.users.require_password_reset")
+                                            .target("user", "user_id"),
+                                    ),
+                            ),
+                    )
+                    .service(
+                        web::scope("/workers")
+                            .service(
+                                web::resource("")
+                                    .route(web::get().to_async(workers::list))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::GET, "admin.workers.list"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{worker_id}")
+                                    .route(web::delete().to_async(workers::revoke))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::DELETE, "admin.workers.revoke")
+                                            .target("worker", "worker_id"),
+                                    ),
                             ),
                     )
-                    .service(web::scope("/profiles").route("", web::get().to_async(profiles::list)))
                     .service(
                         web::scope("/reports")
-                            .route("", web::get().to_async(reports::list))
-                            .route("/{report_id}", web::get().to_async(reports::by_id))
-                            .route("/create", web::post().to_async(reports::create))
-                            .route("/{report_id}/tasks", web::get().to_async(tasks::list))
-                            .route(
-                                "/{report_id}/file",
-                                web::delete().to_async(reports::discard_file),
+                            .service(
+                                web::resource("")
+                                    .route(web::get().to_async(reports::list))
+                                    .wrap(
+                                        audit::Audited::new(&audit).on(Method::GET, "reports.list"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/create")
+                                    .route(web::post().to_async(reports::create))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "reports.create"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{report_id}")
+                                    .route(web::get().to_async(reports::by_id))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::GET, "reports.view")
+                                            .target("report", "report_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{report_id}/tasks")
+                                    .route(web::get().to_async(tasks::list))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::GET, "tasks.list")
+                                            .target("report", "report_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{report_id}/tasks/{task_id}/cancel")
+                                    .route(web::post().to_async(tasks::cancel))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "tasks.cancel")
+                                            .target("task", "task_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{report_id}/rescan")
+                                    .route(web::post().to_async(reports::rescan))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "reports.rescan")
+                                            .target("report", "report_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{report_id}/profiles")
+                                    .route(web::post().to_async(reports::add_profiles))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "reports.add_profiles")
+                                            .target("report", "report_id"),
+                                    ),
+                            )
+                            .service(
+                                web::resource("/{report_id}/file")
+                                    .route(web::get().to_async(reports::download))
+                                    .route(web::delete().to_async(reports::discard_file))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::GET, "reports.download")
+                                            .on(Method::DELETE, "reports.discard_file")
+                                            .target("report", "report_id"),
+                                    ),
+                            ),
+                    )
+                    .service(
+                        web::scope("/samples")
+                            .service(
+                                web::resource("/lookup")
+                                    .data(web::JsonConfig::default().limit(131_072))
+                                    .route(web::post().to_async(samples::batch)),
+                            )
+                            .route("/{hash}", web::get().to_async(samples::by_hash)),
+                    )
+                    .service(
+                        web::scope("/worker")
+                            .service(
+                                web::resource("/register")
+                                    .data(web::JsonConfig::default().limit(4096))
+                                    .route(web::post().to_async(workers::register))
+                                    .wrap(
+                                        audit::Audited::new(&audit)
+                                            .on(Method::POST, "worker.register"),
+                                    ),
+                            )
+                            .route("/heartbeat", web::post().to_async(workers::heartbeat))
+                            .route("/tasks/claim", web::post().to_async(workers::claim))
+                            .route("/tasks/{task_id}/file", web::get().to_async(workers::file))
+                            .service(
+                                web::resource("/tasks/{task_id}/result")
+                                    .data(web::JsonConfig::default().limit(1_048_576))
+                                    .route(web::post().to_async(workers::submit_result)),
                             ),
                     ),
             )
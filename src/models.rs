@@ -1,20 +1,211 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::Write,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
 use bcrypt::{hash, verify, DEFAULT_COST};
 use chrono::prelude::*;
-use diesel::prelude::*;
+use diesel::{
+    deserialize::{self, FromSql},
+    pg::Pg,
+    prelude::*,
+    serialize::{self, Output, ToSql},
+    sql_types::{BigInt, Binary, Bool, Text},
+};
 use serde::{Deserialize, Serialize};
 
+use crate::audit;
+use crate::backend;
+use crate::oidc;
+use crate::secrets;
+use crate::storage::Digests;
+use crate::totp;
+
+use crate::schema::api_keys;
+use crate::schema::invitations;
+use crate::schema::mfa_challenges;
+use crate::schema::oidc_logins;
+use crate::schema::password_resets;
+use crate::schema::recovery_codes;
 use crate::schema::reports;
+use crate::schema::samples;
 use crate::schema::tasks;
+use crate::schema::tokens;
+use crate::schema::users;
+use crate::schema::workers;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Database(err: diesel::result::Error) {
+            from()
+            cause(err)
+            display("database error: {}", err)
+        }
+        Storage(err: crate::storage::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        Notification(err: crate::notify::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        Authentication(err: crate::backend::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        Oidc(err: crate::oidc::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        UnknownProfile(machine_name: String) {
+            display("no profile is named {}", machine_name)
+        }
+        IllegalTransition(current: TaskStatus, next: TaskStatus) {
+            display("task cannot go from {} to {}", current, next)
+        }
+        Forbidden {
+            display("credential does not allow this request")
+        }
+    }
+}
+
+/// A bcrypt hash at `DEFAULT_COST` that no password is expected to match,
+/// checked against when the username is unknown.
+const DUMMY_HASH: &str = "$2b$12$U3r./Z7yC3jyVFTYLVRNFeWB4iu5UFeWnvUNnzGp8HaTnTpsGRtKm";
+
+/// What a user is for, stored as `users.rank`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    User,
+    Analyst,
+    Admin,
+    WorkerOperator,
+}
+
+/// Something a role may or may not do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    SubmitReports,
+    ReadReports,
+    /// Extends reading reports, their tasks and their files to every user's.
+    ReadAllReports,
+    /// Extends changes to reports, such as discarding files, to every user's.
+    ManageAllReports,
+    ReadProfiles,
+    ManageProfiles,
+    ManageWorkers,
+    ManageUsers,
+    ReadAuditLog,
+}
+
+impl Role {
+    /// Unknown ranks get the least privileged role.
+    pub fn from_rank(rank: i32) -> Self {
+        match rank {
+            1 => Role::Analyst,
+            2 => Role::Admin,
+            3 => Role::WorkerOperator,
+            _ => Role::User,
+        }
+    }
+
+    /// Parses the name a role is serialized under, as used in configuration.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "user" => Some(Role::User),
+            "analyst" => Some(Role::Analyst),
+            "admin" => Some(Role::Admin),
+            "worker_operator" => Some(Role::WorkerOperator),
+            _ => None,
+        }
+    }
+
+    pub fn rank(self) -> i32 {
+        match self {
+            Role::User => 0,
+            Role::Analyst => 1,
+            Role::Admin => 2,
+            Role::WorkerOperator => 3,
+        }
+    }
 
-#[derive(Queryable)]
+    pub fn can(self, permission: Permission) -> bool {
+        use self::Permission::*;
+
+        match self {
+            Role::User => match permission {
+                SubmitReports | ReadReports | ReadProfiles => true,
+                _ => false,
+            },
+            Role::Analyst => match permission {
+                SubmitReports | ReadReports | ReadAllReports | ReadProfiles => true,
+                _ => false,
+            },
+            Role::Admin => true,
+            Role::WorkerOperator => match permission {
+                ReadProfiles | ManageWorkers => true,
+                _ => false,
+            },
+        }
+    }
+}
+
+#[derive(Queryable, Identifiable)]
 pub struct User {
     pub id: i64,
     pub username: String,
     pub hashed_password: String,
     pub rank: i32,
+    pub disabled: bool,
+    pub password_reset_required: bool,
+    pub email: Option<String>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+    pub oidc_subject: Option<String>,
+    pub ldap_dn: Option<String>,
+    /// Whether the user has shown they receive mail at `email`.
+    pub email_verified: bool,
+}
+
+/// What happens to the reports of a deleted user.
+pub enum ReportDisposition {
+    Delete,
+    ReassignTo(i64),
 }
 
 impl User {
+    pub fn role(&self) -> Role {
+        Role::from_rank(self.rank)
+    }
+
+    pub fn require(&self, permission: Permission) -> Result<(), Error> {
+        if self.role().can(permission) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden)
+        }
+    }
+
+    /// The owner to confine report queries to, or `None` when `permission`
+    /// reaches every user's reports.
+    pub fn owner_filter(&self, permission: Permission) -> Option<i64> {
+        if self.role().can(permission) {
+            None
+        } else {
+            Some(self.id)
+        }
+    }
+
     pub fn verify_password(
         conn: &PgConnection,
         username: &str,
@@ -24,9 +215,19 @@ impl User {
 
         let user = dsl::users
             .filter(dsl::username.eq(username))
-            .get_result::<Self>(conn)?;
+            .get_result::<Self>(conn)
+            .optional()?;
 
-        Ok(verify(password, &user.hashed_password).unwrap())
+        match user {
+            Some(user) => Ok(verify(password, &user.hashed_password).unwrap()),
+            None => {
+                // Spend as long as a real check would, so that response
+                // times do not reveal which usernames exist.
+                verify(password, DUMMY_HASH).unwrap();
+
+                Ok(false)
+            }
+        }
     }
 
     pub fn by_username(conn: &PgConnection, username: &str) -> Result<Self, diesel::result::Error> {
@@ -41,6 +242,7 @@ impl User {
         conn: &PgConnection,
         username: &str,
         password: &str,
+        email: Option<&str>,
         rank: i32,
     ) -> Result<i64, diesel::result::Error> {
         use crate::schema::users::dsl;
@@ -49,6 +251,7 @@ impl User {
             .values((
                 dsl::username.eq(username),
                 dsl::hashed_password.eq(&hash(password, DEFAULT_COST).unwrap()),
+                dsl::email.eq(email),
                 dsl::rank.eq(rank),
             ))
             .returning(dsl::id)
@@ -56,185 +259,3493 @@ impl User {
 
         Ok(user_id)
     }
-}
 
-#[derive(Queryable)]
-pub struct Token {
-    pub id: i64,
-    pub user_id: i64,
-    pub token: String,
-    pub created_when: chrono::DateTime<Utc>,
-}
-
-impl Token {
-    pub fn generate(conn: &PgConnection, user_id: i64) -> Result<String, diesel::result::Error> {
-        use crate::schema::tokens::dsl;
-        let token = uuid::Uuid::new_v4().to_simple().to_string().to_lowercase();
+    pub fn by_id(conn: &PgConnection, user_id: i64) -> Result<Self, diesel::result::Error> {
+        use crate::schema::users::dsl;
 
-        diesel::insert_into(dsl::tokens)
-            .values((dsl::user_id.eq(user_id), dsl::token.eq(&token)))
-            .returning(dsl::token)
-            .get_result(conn)
+        dsl::users.find(user_id).get_result::<Self>(conn)
     }
 
-    pub fn destroy(conn: &PgConnection, token: &str) -> Result<(), diesel::result::Error> {
-        use crate::schema::tokens::dsl;
+    /// Replaces the user's password, which also satisfies a pending forced
+    /// reset and voids every reset token issued to them.
+    pub fn set_password(
+        conn: &PgConnection,
+        user_id: i64,
+        password: &str,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::users::dsl;
+
+        conn.transaction(|| {
+            diesel::update(dsl::users.find(user_id))
+                .set((
+                    dsl::hashed_password.eq(&hash(password, DEFAULT_COST).unwrap()),
+                    dsl::password_reset_required.eq(false),
+                ))
+                .execute(conn)?;
 
-        diesel::delete(dsl::tokens.filter(dsl::token.eq(token)))
+            diesel::delete(
+                password_resets::dsl::password_resets
+                    .filter(password_resets::dsl::user_id.eq(user_id)),
+            )
             .execute(conn)
             .map(|_| ())
+        })
     }
 
-    pub fn user_by_token(conn: &PgConnection, token: &str) -> Result<User, diesel::result::Error> {
-        use crate::schema::tokens::dsl;
-        use crate::schema::users;
+    /// Lists users whose name contains `search`, ordered by name.
+    pub fn search(
+        conn: &PgConnection,
+        search: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::users::dsl;
 
-        let token = dsl::tokens
-            .filter(dsl::token.eq(token))
-            .get_result::<Self>(conn)?;
+        let mut query = dsl::users.into_boxed();
 
-        Ok(users::dsl::users
-            .find(token.user_id)
-            .get_result::<User>(conn)?)
-    }
-}
+        if let Some(search) = search {
+            let pattern = format!(
+                "%{}%",
+                search
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            );
 
-#[derive(Queryable, Serialize, Deserialize)]
-pub struct Profile {
-    pub id: i64,
-    pub machine_name: String,
-    pub human_name: String,
-    pub module: String,
-    pub config: Option<serde_json::Value>,
-}
+            query = query.filter(dsl::username.ilike(pattern));
+        }
 
-impl Profile {
-    pub fn list(conn: &PgConnection) -> Result<Vec<Self>, diesel::result::Error> {
-        use crate::schema::profiles::dsl;
+        query
+            .order(dsl::username)
+            .limit(limit)
+            .offset(offset)
+            .get_results::<Self>(conn)
+    }
 
-        dsl::profiles.get_results::<Self>(conn)
+    pub fn set_role(
+        conn: &PgConnection,
+        user_id: i64,
+        role: Role,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::users::dsl;
+
+        diesel::update(dsl::users.find(user_id))
+            .set(dsl::rank.eq(role.rank()))
+            .get_result::<Self>(conn)
     }
 
-    pub fn id_for_machine_name(
+    /// Disabling an account also ends all of its sessions.
+    pub fn set_disabled(
         conn: &PgConnection,
-        machine_name: &str,
-    ) -> Result<i64, diesel::result::Error> {
-        use crate::schema::profiles::dsl;
+        user_id: i64,
+        disabled: bool,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::users::dsl;
 
-        let profile = dsl::profiles
-            .filter(dsl::machine_name.eq(machine_name))
-            .get_result::<Profile>(conn)?;
+        conn.transaction(|| {
+            let user = diesel::update(dsl::users.find(user_id))
+                .set(dsl::disabled.eq(disabled))
+                .get_result::<Self>(conn)?;
 
-        Ok(profile.id)
+            if disabled {
+                Token::revoke_all(conn, user_id)?;
+            }
+
+            Ok(user)
+        })
     }
-}
 
-#[derive(Identifiable, Queryable, Serialize, Deserialize)]
-pub struct Report {
-    pub id: i64,
-    pub user_id: i64,
-    pub created_when: chrono::DateTime<Utc>,
-    pub file_multihash: String,
-    pub file: Option<Vec<u8>>,
-}
+    /// Locks the user out until they choose a new password, ending their
+    /// sessions and revoking their API keys.
+    pub fn require_password_reset(
+        conn: &PgConnection,
+        user_id: i64,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::users::dsl;
 
-impl Report {
-    pub fn list_for_user(
+        conn.transaction(|| {
+            let user = diesel::update(dsl::users.find(user_id))
+                .set(dsl::password_reset_required.eq(true))
+                .get_result::<Self>(conn)?;
+
+            Token::revoke_all(conn, user_id)?;
+            ApiKey::revoke_all(conn, user_id)?;
+
+            Ok(user)
+        })
+    }
+
+    /// Deletes an account with its credentials. Returns the storage keys of
+    /// files no report refers to anymore, for the caller to delete.
+    pub fn delete(
         conn: &PgConnection,
         user_id: i64,
-    ) -> Result<Vec<Self>, diesel::result::Error> {
-        use crate::schema::reports::dsl;
+        reports: ReportDisposition,
+    ) -> Result<Vec<String>, diesel::result::Error> {
+        use crate::schema::api_keys;
+        use crate::schema::users::dsl;
 
-        dsl::reports
-            .filter(dsl::user_id.eq(user_id))
-            .get_results::<Self>(conn)
+        conn.transaction(|| {
+            let user = dsl::users
+                .find(user_id)
+                .for_update()
+                .get_result::<Self>(conn)?;
+
+            let orphaned_keys = match reports {
+                ReportDisposition::Delete => Report::delete_for_user(conn, user.id)?,
+                ReportDisposition::ReassignTo(new_owner) => {
+                    Report::reassign(conn, user.id, new_owner)?;
+                    Vec::new()
+                }
+            };
+
+            Token::revoke_all(conn, user.id)?;
+            diesel::delete(api_keys::dsl::api_keys.filter(api_keys::dsl::user_id.eq(user.id)))
+                .execute(conn)?;
+            diesel::delete(
+                password_resets::dsl::password_resets
+                    .filter(password_resets::dsl::user_id.eq(user.id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                mfa_challenges::dsl::mfa_challenges
+                    .filter(mfa_challenges::dsl::user_id.eq(user.id)),
+            )
+            .execute(conn)?;
+            RecoveryCode::delete_for_user(conn, user.id)?;
+            Invitation::forget_user(conn, user.id)?;
+            diesel::delete(dsl::users.find(user.id)).execute(conn)?;
+
+            Ok(orphaned_keys)
+        })
     }
 
-    pub fn create(
+    /// Finds the account for someone who signed in through the OpenID Connect
+    /// provider, creating it on their first visit.
+    ///
+    /// Accounts are matched by subject, then by email address for existing
+    /// users who have not used single sign-on before, as long as both the
+    /// provider and this account have verified the address. When the
+    /// provider's groups map to a role, the account takes that role.
+    pub fn by_oidc_identity(
+        conn: &PgConnection,
+        identity: &oidc::Identity,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::users::dsl;
+
+        conn.transaction(|| {
+            let user = dsl::users
+                .filter(dsl::oidc_subject.eq(&identity.subject))
+                .for_update()
+                .get_result::<Self>(conn)
+                .optional()?;
+
+            let user = match (user, &identity.email) {
+                (Some(user), _) => user,
+                (None, Some(email)) if identity.email_verified => {
+                    let mut matches = dsl::users
+                        .filter(dsl::email.eq(email))
+                        .filter(dsl::email_verified.eq(true))
+                        .filter(dsl::oidc_subject.is_null())
+                        .for_update()
+                        .get_results::<Self>(conn)?;
+
+                    // Only link when the address points at a single account.
+                    if matches.len() == 1 {
+                        matches.remove(0)
+                    } else {
+                        Self::provision(
+                            conn,
+                            identity.preferred_username.as_ref().or(Some(email)),
+                            Some(email),
+                        )?
+                    }
+                }
+                (None, _) => Self::provision(
+                    conn,
+                    identity
+                        .preferred_username
+                        .as_ref()
+                        .or_else(|| identity.email.as_ref()),
+                    identity.email.as_ref(),
+                )?,
+            };
+
+            let email_verified = user.email_verified
+                || (identity.email_verified
+                    && user.email.is_some()
+                    && user.email == identity.email);
+            let user = diesel::update(dsl::users.find(user.id))
+                .set((
+                    dsl::oidc_subject.eq(&identity.subject),
+                    dsl::email_verified.eq(email_verified),
+                ))
+                .get_result::<Self>(conn)?;
+
+            match identity.role {
+                Some(role) if role.rank() != user.rank => Self::set_role(conn, user.id, role),
+                _ => Ok(user),
+            }
+        })
+    }
+
+    /// Finds the account for someone the directory vouched for, creating it
+    /// on their first login. When their groups map to a role, the account
+    /// takes it.
+    ///
+    /// Accounts are matched by DN. A local account with the same username
+    /// that has not logged in through the directory before is only taken
+    /// over with `link_by_username`; otherwise the login is refused, since
+    /// the directory's user may not be the same person.
+    pub fn by_directory_entry(
+        conn: &PgConnection,
+        entry: &backend::DirectoryEntry,
+        link_by_username: bool,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::users::dsl;
+
+        conn.transaction(|| {
+            let user = dsl::users
+                .filter(dsl::ldap_dn.eq(&entry.dn))
+                .for_update()
+                .get_result::<Self>(conn)
+                .optional()?;
+
+            let user = match user {
+                Some(user) => user,
+                None => {
+                    let local = dsl::users
+                        .filter(dsl::username.eq(&entry.username))
+                        .filter(dsl::ldap_dn.is_null())
+                        .for_update()
+                        .get_result::<Self>(conn)
+                        .optional()?;
+
+                    let user = match local {
+                        Some(user) if link_by_username => user,
+                        Some(_) => return Ok(None),
+                        None => Self::provision(conn, Some(&entry.username), entry.email.as_ref())?,
+                    };
+
+                    diesel::update(dsl::users.find(user.id))
+                        .set(dsl::ldap_dn.eq(&entry.dn))
+                        .get_result::<Self>(conn)?
+                }
+            };
+
+            match entry.role {
+                Some(role) if role.rank() != user.rank => {
+                    Self::set_role(conn, user.id, role).map(Some)
+                }
+                _ => Ok(Some(user)),
+            }
+        })
+    }
+
+    /// Creates an account for someone an outside identity source vouched
+    /// for. It gets a random password nobody knows, and a username derived
+    /// from `name`, made unique if need be.
+    fn provision(
+        conn: &PgConnection,
+        name: Option<&String>,
+        email: Option<&String>,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::users::dsl;
+
+        let base: String = name
+            .map(|name| name.split('@').next().unwrap_or(""))
+            .unwrap_or("")
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '-' || *c == '_')
+            .skip_while(|c| !c.is_ascii_alphanumeric())
+            .take(28)
+            .collect();
+        let base = if base.len() < 3 {
+            "user".to_string()
+        } else {
+            base
+        };
+
+        let mut username = base.clone();
+        let mut suffix = 1;
+        while dsl::users
+            .filter(dsl::username.eq(&username))
+            .count()
+            .get_result::<i64>(conn)?
+            > 0
+        {
+            suffix += 1;
+            username = format!("{}-{}", base, suffix);
+        }
+
+        let user_id = Self::create(
+            conn,
+            &username,
+            &secrets::generate(),
+            email.map(String::as_str),
+            Role::User.rank(),
+        )?;
+
+        Self::by_id(conn, user_id)
+    }
+
+    /// Resolves the credential a request carries, either a session token or
+    /// an API key. API keys must also hold `scope` and be used from an
+    /// allowed address; sessions may do anything their user can.
+    pub fn by_credential(
+        conn: &PgConnection,
+        credential: &str,
+        client: &Client,
+        scope: Scope,
+    ) -> Result<Self, Error> {
+        let user = if credential.starts_with(ApiKey::MARKER) {
+            ApiKey::user_by_key(conn, credential, client, scope)?
+        } else {
+            Token::user_by_token(conn, credential, client)?
+        };
+
+        user.check_mfa_policy(conn)
+    }
+
+    /// Resolves a session token for endpoints API keys cannot reach, such as
+    /// administration. Account settings use `Token::user_by_token` instead,
+    /// so that users held back by the MFA policy can still enroll.
+    pub fn by_session(conn: &PgConnection, token: &str, client: &Client) -> Result<Self, Error> {
+        Token::user_by_token(conn, token, client)?.check_mfa_policy(conn)
+    }
+
+    /// Refuses users whose role requires two-factor authentication until
+    /// they have enrolled.
+    fn check_mfa_policy(self, conn: &PgConnection) -> Result<Self, Error> {
+        if !self.totp_enabled && MfaPolicy::is_required(conn, self.role())? {
+            Err(Error::Forbidden)
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// Starts TOTP enrollment with a fresh secret. Nothing changes for the
+    /// user until a code from it is confirmed.
+    pub fn begin_totp_enrollment(
         conn: &PgConnection,
         user_id: i64,
-        file: Vec<u8>,
-    ) -> Result<i64, diesel::result::Error> {
-        use crate::schema::reports::dsl;
-        use multihash::{encode, Hash};
+    ) -> Result<String, diesel::result::Error> {
+        use crate::schema::users::dsl;
 
-        diesel::insert_into(dsl::reports)
-            .values((
-                dsl::user_id.eq(user_id),
-                dsl::file_multihash.eq(hex::encode(encode(Hash::SHA2256, &file).unwrap())),
-                dsl::file.eq(Some(file)),
+        let secret = totp::generate_secret();
+
+        diesel::update(dsl::users.find(user_id).filter(dsl::totp_enabled.eq(false)))
+            .set((
+                dsl::totp_secret.eq(&secret),
+                dsl::totp_last_step.eq(None::<i64>),
             ))
-            .returning(dsl::id)
-            .get_result(conn)
+            .execute(conn)?;
+
+        Ok(secret)
     }
 
-    pub fn discard_file_check_user(
+    /// Turns TOTP on once the user proves their app has the secret, returning
+    /// fresh recovery codes, or `None` if the code is wrong.
+    pub fn confirm_totp(
         conn: &PgConnection,
         user_id: i64,
-        report_id: i64,
-    ) -> Result<(), diesel::result::Error> {
-        use crate::schema::reports::dsl;
+        code: &str,
+    ) -> Result<Option<Vec<String>>, diesel::result::Error> {
+        use crate::schema::users::dsl;
 
-        diesel::update(
-            dsl::reports
-                .find(report_id)
-                .filter(dsl::user_id.eq(user_id)),
-        )
-        .set(dsl::file.eq::<Option<Vec<u8>>>(None))
-        .execute(conn)
-        .map(|_| ())
+        conn.transaction(|| {
+            let user = dsl::users
+                .find(user_id)
+                .for_update()
+                .get_result::<Self>(conn)?;
+
+            let step = match user.totp_secret {
+                Some(ref secret) if !user.totp_enabled => {
+                    totp::verify(secret, code, Utc::now(), user.totp_last_step)
+                }
+                _ => None,
+            };
+
+            match step {
+                Some(step) => {
+                    diesel::update(&user)
+                        .set((dsl::totp_enabled.eq(true), dsl::totp_last_step.eq(step)))
+                        .execute(conn)?;
+
+                    RecoveryCode::replace_for_user(conn, user.id).map(Some)
+                }
+                None => Ok(None),
+            }
+        })
     }
 
-    pub fn by_id_check_user(
+    pub fn disable_totp(conn: &PgConnection, user_id: i64) -> Result<(), diesel::result::Error> {
+        use crate::schema::users::dsl;
+
+        conn.transaction(|| {
+            diesel::update(dsl::users.find(user_id))
+                .set((
+                    dsl::totp_secret.eq(None::<String>),
+                    dsl::totp_enabled.eq(false),
+                    dsl::totp_last_step.eq(None::<i64>),
+                ))
+                .execute(conn)?;
+
+            RecoveryCode::delete_for_user(conn, user_id)
+        })
+    }
+
+    /// Checks a TOTP code or, failing that, a recovery code, using either up.
+    ///
+    /// The user's row must have been read `for_update` in the surrounding
+    /// transaction, or two requests could both accept the same TOTP code.
+    pub fn verify_second_factor(
+        &self,
         conn: &PgConnection,
-        report_id: i64,
-        user_id: i64,
-    ) -> Result<Self, diesel::result::Error> {
-        use crate::schema::reports::dsl;
+        code: &str,
+    ) -> Result<bool, diesel::result::Error> {
+        use crate::schema::users::dsl;
 
-        dsl::reports
-            .find(report_id)
-            .filter(dsl::user_id.eq(user_id))
-            .get_result::<Self>(conn)
+        let step = match self.totp_secret {
+            Some(ref secret) if self.totp_enabled => {
+                totp::verify(secret, code, Utc::now(), self.totp_last_step)
+            }
+            _ => None,
+        };
+
+        match step {
+            Some(step) => {
+                diesel::update(self)
+                    .set(dsl::totp_last_step.eq(step))
+                    .execute(conn)?;
+
+                Ok(true)
+            }
+            None if self.totp_enabled => RecoveryCode::redeem(conn, self.id, code),
+            None => Ok(false),
+        }
     }
 }
 
-#[derive(Queryable, Identifiable, Serialize, Deserialize)]
-pub struct Task {
-    id: i64,
-    report_id: i64,
-    profile_id: i64,
-    created_when: chrono::DateTime<Utc>,
-    completed_when: Option<chrono::DateTime<Utc>>,
-    status: String,
-    message: Option<String>,
+/// The roles whose members must use two-factor authentication.
+pub struct MfaPolicy;
+
+impl MfaPolicy {
+    pub fn roles(conn: &PgConnection) -> Result<Vec<Role>, diesel::result::Error> {
+        use crate::schema::mfa_policies::dsl;
+
+        Ok(dsl::mfa_policies
+            .select(dsl::rank)
+            .order(dsl::rank)
+            .get_results::<i32>(conn)?
+            .into_iter()
+            .map(Role::from_rank)
+            .collect())
+    }
+
+    pub fn set_roles(conn: &PgConnection, roles: &[Role]) -> Result<(), diesel::result::Error> {
+        use crate::schema::mfa_policies::dsl;
+
+        let ranks: Vec<_> = roles.iter().map(|role| dsl::rank.eq(role.rank())).collect();
+
+        conn.transaction(|| {
+            diesel::delete(dsl::mfa_policies).execute(conn)?;
+            diesel::insert_into(dsl::mfa_policies)
+                .values(&ranks)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+
+            Ok(())
+        })
+    }
+
+    pub fn is_required(conn: &PgConnection, role: Role) -> Result<bool, diesel::result::Error> {
+        use crate::schema::mfa_policies::dsl;
+        use diesel::dsl::exists;
+
+        diesel::select(exists(dsl::mfa_policies.find(role.rank()))).get_result(conn)
+    }
 }
 
-impl Task {
-    pub fn list_for_report(
+/// Single-use codes that stand in for a lost authenticator.
+#[derive(Queryable, Identifiable)]
+pub struct RecoveryCode {
+    pub id: i64,
+    pub user_id: i64,
+    pub code_hash: String,
+    pub used_when: Option<chrono::DateTime<Utc>>,
+}
+
+impl RecoveryCode {
+    const COUNT: usize = 10;
+
+    /// Codes are compared without their separator and case, as people type
+    /// them back.
+    fn normalize(code: &str) -> String {
+        code.chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect::<String>()
+            .to_lowercase()
+    }
+
+    /// Invalidates the user's recovery codes and returns a new set.
+    pub fn replace_for_user(
         conn: &PgConnection,
-        report_id: i64,
-    ) -> Result<Vec<Self>, diesel::result::Error> {
-        use crate::schema::tasks::dsl;
+        user_id: i64,
+    ) -> Result<Vec<String>, diesel::result::Error> {
+        use crate::schema::recovery_codes::dsl;
 
-        dsl::tasks
-            .filter(dsl::report_id.eq(report_id))
-            .get_results::<Self>(conn)
+        Self::delete_for_user(conn, user_id)?;
+
+        let codes: Vec<String> = (0..Self::COUNT)
+            .map(|_| {
+                let secret = secrets::generate();
+                format!("{}-{}", &secret[..5], &secret[5..10])
+            })
+            .collect();
+        let rows: Vec<_> = codes
+            .iter()
+            .map(|code| {
+                (
+                    dsl::user_id.eq(user_id),
+                    dsl::code_hash.eq(secrets::digest(&Self::normalize(code))),
+                )
+            })
+            .collect();
+
+        diesel::insert_into(dsl::recovery_codes)
+            .values(&rows)
+            .execute(conn)?;
+
+        Ok(codes)
     }
 
-    pub fn create(
+    pub fn delete_for_user(conn: &PgConnection, user_id: i64) -> Result<(), diesel::result::Error> {
+        use crate::schema::recovery_codes::dsl;
+
+        diesel::delete(dsl::recovery_codes.filter(dsl::user_id.eq(user_id)))
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    /// Uses up one of the user's recovery codes, returning whether it matched.
+    pub fn redeem(
         conn: &PgConnection,
-        report_id: i64,
-        profile_id: i64,
-    ) -> Result<i64, diesel::result::Error> {
-        use crate::schema::tasks::dsl;
-        diesel::insert_into(dsl::tasks)
-            .values((
-                dsl::report_id.eq(report_id),
-                dsl::profile_id.eq(profile_id),
-                dsl::status.eq("new"),
-            ))
-            .returning(dsl::id)
-            .get_result(conn)
+        user_id: i64,
+        code: &str,
+    ) -> Result<bool, diesel::result::Error> {
+        use crate::schema::recovery_codes::dsl;
+
+        let code = Self::normalize(code);
+
+        let recovery_code = dsl::recovery_codes
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::used_when.is_null())
+            .for_update()
+            .get_results::<Self>(conn)?
+            .into_iter()
+            .find(|candidate| secrets::verify(&code, &candidate.code_hash));
+
+        match recovery_code {
+            Some(recovery_code) => {
+                diesel::update(&recovery_code)
+                    .set(dsl::used_when.eq(Utc::now()))
+                    .execute(conn)?;
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// The second step of logging in to an account with two-factor
+/// authentication: proof that the password was right, traded for tokens
+/// along with a code.
+#[derive(Queryable, Identifiable)]
+pub struct MfaChallenge {
+    pub id: i64,
+    pub user_id: i64,
+    pub token_prefix: String,
+    pub token_hash: String,
+    pub created_when: chrono::DateTime<Utc>,
+    pub attempts: i32,
+}
+
+impl MfaChallenge {
+    const MAX_ATTEMPTS: i32 = 5;
+
+    /// How long a challenge can be answered, from
+    /// `MFA_CHALLENGE_LIFETIME_SECONDS`.
+    pub fn lifetime() -> chrono::Duration {
+        chrono::Duration::seconds(
+            std::env::var("MFA_CHALLENGE_LIFETIME_SECONDS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(300),
+        )
+    }
+
+    pub fn create(conn: &PgConnection, user_id: i64) -> Result<String, diesel::result::Error> {
+        use crate::schema::mfa_challenges::dsl;
+
+        let token = secrets::generate();
+
+        diesel::insert_into(dsl::mfa_challenges)
+            .values((
+                dsl::user_id.eq(user_id),
+                dsl::token_prefix.eq(secrets::prefix(&token)),
+                dsl::token_hash.eq(secrets::digest(&token)),
+            ))
+            .execute(conn)?;
+
+        Ok(token)
+    }
+
+    /// Answers a challenge with a TOTP or recovery code. On success the
+    /// challenge is gone. Each challenge allows a few wrong codes before it
+    /// stops working, and every wrong code counts as a failed login for the
+    /// account, so codes are not checked at all while it is locked out.
+    pub fn answer(
+        conn: &PgConnection,
+        token: &str,
+        code: &str,
+        client: &Client,
+        policy: &LoginPolicy,
+    ) -> Result<MfaAnswer, diesel::result::Error> {
+        use crate::schema::mfa_challenges::dsl;
+        use crate::schema::users;
+
+        conn.transaction(|| {
+            let now = Utc::now();
+            let challenge = dsl::mfa_challenges
+                .filter(dsl::token_prefix.eq(secrets::prefix(token)))
+                .for_update()
+                .get_results::<Self>(conn)?
+                .into_iter()
+                .find(|candidate| secrets::verify(token, &candidate.token_hash))
+                .filter(|challenge| {
+                    challenge.attempts < Self::MAX_ATTEMPTS
+                        && challenge.created_when + Self::lifetime() > now
+                });
+
+            let challenge = match challenge {
+                Some(challenge) => challenge,
+                None => return Ok(MfaAnswer::Invalid),
+            };
+
+            let user = users::dsl::users
+                .find(challenge.user_id)
+                .for_update()
+                .get_result::<User>(conn)?;
+
+            if let Some(until) = LoginAttempt::locked_until(conn, &user.username, client, policy)? {
+                return Ok(MfaAnswer::Locked(until));
+            }
+
+            if user.verify_second_factor(conn, code)? {
+                diesel::delete(&challenge).execute(conn)?;
+
+                Ok(MfaAnswer::Accepted(user))
+            } else {
+                diesel::update(&challenge)
+                    .set(dsl::attempts.eq(dsl::attempts + 1))
+                    .execute(conn)?;
+                LoginAttempt::record(conn, &user.username, client, false)?;
+
+                Ok(MfaAnswer::Rejected)
+            }
+        })
+    }
+}
+
+/// How answering an MFA challenge went.
+pub enum MfaAnswer {
+    Accepted(User),
+    /// The code was wrong.
+    Rejected,
+    /// The challenge is unknown, expired or out of attempts.
+    Invalid,
+    /// The account is locked out until then, so the code was not checked.
+    Locked(chrono::DateTime<Utc>),
+}
+
+/// How long issued tokens stay valid, from `ACCESS_TOKEN_LIFETIME_SECONDS`,
+/// `ACCESS_TOKEN_IDLE_SECONDS` and `REFRESH_TOKEN_LIFETIME_SECONDS`.
+pub struct TokenPolicy {
+    pub access_lifetime: chrono::Duration,
+    pub access_idle: chrono::Duration,
+    pub refresh_lifetime: chrono::Duration,
+}
+
+impl TokenPolicy {
+    pub fn from_env() -> Self {
+        let seconds = |name: &str, default: i64| {
+            chrono::Duration::seconds(
+                std::env::var(name)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(default),
+            )
+        };
+
+        TokenPolicy {
+            access_lifetime: seconds("ACCESS_TOKEN_LIFETIME_SECONDS", 3600),
+            access_idle: seconds("ACCESS_TOKEN_IDLE_SECONDS", 1800),
+            refresh_lifetime: seconds("REFRESH_TOKEN_LIFETIME_SECONDS", 2_592_000),
+        }
+    }
+}
+
+/// Where a request came from, as recorded against the session it used.
+pub struct Client {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// Who the request turned out to be from, once a credential has been
+    /// checked or issued. Shared with the request's pending audit event.
+    pub actor: Arc<Mutex<Option<i64>>>,
+}
+
+impl Client {
+    pub fn identify(&self, user_id: i64) {
+        *self.actor.lock().unwrap() = Some(user_id);
+    }
+}
+
+/// A login as seen by its owner: every token issued from it shares the same
+/// opaque id.
+#[derive(Serialize)]
+pub struct Session {
+    pub id: String,
+    pub created_when: chrono::DateTime<Utc>,
+    pub last_used_when: chrono::DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenPair {
+    pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Queryable, Identifiable)]
+pub struct Token {
+    pub id: i64,
+    pub user_id: i64,
+    pub created_when: chrono::DateTime<Utc>,
+    pub kind: String,
+    pub family: String,
+    pub last_used_when: chrono::DateTime<Utc>,
+    pub used_when: Option<chrono::DateTime<Utc>>,
+    pub token_prefix: String,
+    pub token_hash: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl Token {
+    pub const ACCESS: &'static str = "access";
+    pub const REFRESH: &'static str = "refresh";
+
+    fn insert(
+        conn: &PgConnection,
+        user_id: i64,
+        kind: &str,
+        family: &str,
+        client: &Client,
+    ) -> Result<String, diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+        let token = secrets::generate();
+
+        diesel::insert_into(dsl::tokens)
+            .values((
+                dsl::user_id.eq(user_id),
+                dsl::token_prefix.eq(secrets::prefix(&token)),
+                dsl::token_hash.eq(secrets::digest(&token)),
+                dsl::kind.eq(kind),
+                dsl::family.eq(family),
+                dsl::user_agent.eq(&client.user_agent),
+                dsl::ip_address.eq(&client.ip_address),
+            ))
+            .execute(conn)?;
+
+        client.identify(user_id);
+
+        Ok(token)
+    }
+
+    /// Finds the row a presented token was issued as. Only the prefix is
+    /// matched in SQL; the digests are compared in constant time here.
+    fn find(conn: &PgConnection, token: &str) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+
+        Ok(dsl::tokens
+            .filter(dsl::token_prefix.eq(secrets::prefix(token)))
+            .for_update()
+            .get_results::<Self>(conn)?
+            .into_iter()
+            .find(|candidate| secrets::verify(token, &candidate.token_hash)))
+    }
+
+    /// Starts a new session for the user, returning its first access and
+    /// refresh tokens.
+    pub fn generate(
+        conn: &PgConnection,
+        user_id: i64,
+        client: &Client,
+    ) -> Result<TokenPair, diesel::result::Error> {
+        let family = uuid::Uuid::new_v4().to_simple().to_string();
+
+        Self::generate_in_family(conn, user_id, &family, client)
+    }
+
+    fn generate_in_family(
+        conn: &PgConnection,
+        user_id: i64,
+        family: &str,
+        client: &Client,
+    ) -> Result<TokenPair, diesel::result::Error> {
+        Ok(TokenPair {
+            token: Self::insert(conn, user_id, Self::ACCESS, family, client)?,
+            refresh_token: Self::insert(conn, user_id, Self::REFRESH, family, client)?,
+            expires_in: TokenPolicy::from_env().access_lifetime.num_seconds(),
+        })
+    }
+
+    /// Exchanges a refresh token for a new token pair in the same session.
+    ///
+    /// Each refresh token works once. Presenting one that was already used
+    /// means it leaked, so the whole session is revoked. Returns `None` when
+    /// the refresh is refused.
+    pub fn refresh(
+        conn: &PgConnection,
+        refresh_token: &str,
+        client: &Client,
+    ) -> Result<Option<TokenPair>, diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+
+        conn.transaction(|| {
+            let now = Utc::now();
+            let token = match Self::find(conn, refresh_token)? {
+                Some(ref token) if token.kind != Self::REFRESH => return Ok(None),
+                Some(token) => token,
+                None => return Ok(None),
+            };
+
+            if token.used_when.is_some() {
+                diesel::delete(dsl::tokens.filter(dsl::family.eq(&token.family))).execute(conn)?;
+                return Ok(None);
+            }
+
+            if token.created_when + TokenPolicy::from_env().refresh_lifetime < now {
+                return Ok(None);
+            }
+
+            diesel::update(&token)
+                .set(dsl::used_when.eq(now))
+                .execute(conn)?;
+            diesel::delete(
+                dsl::tokens
+                    .filter(dsl::family.eq(&token.family))
+                    .filter(dsl::kind.eq(Self::ACCESS)),
+            )
+            .execute(conn)?;
+
+            Self::generate_in_family(conn, token.user_id, &token.family, client).map(Some)
+        })
+    }
+
+    /// Ends the session the given token belongs to.
+    pub fn destroy(conn: &PgConnection, token: &str) -> Result<(), diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+
+        conn.transaction(|| {
+            if let Some(token) = Self::find(conn, token)? {
+                diesel::delete(dsl::tokens.filter(dsl::family.eq(&token.family))).execute(conn)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Lists the caller's sessions that can still be used or refreshed.
+    pub fn sessions_for_user(
+        conn: &PgConnection,
+        user_id: i64,
+    ) -> Result<Vec<Session>, diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+
+        let now = Utc::now();
+        let policy = TokenPolicy::from_env();
+        let tokens = dsl::tokens
+            .filter(dsl::user_id.eq(user_id))
+            .order((dsl::family, dsl::last_used_when))
+            .get_results::<Self>(conn)?;
+
+        let mut sessions: Vec<Session> = Vec::new();
+        let mut live: Vec<String> = Vec::new();
+
+        for token in tokens {
+            let is_live = match token.kind.as_str() {
+                Self::REFRESH => {
+                    token.used_when.is_none() && token.created_when + policy.refresh_lifetime > now
+                }
+                _ => {
+                    token.created_when + policy.access_lifetime > now
+                        && token.last_used_when + policy.access_idle > now
+                }
+            };
+
+            if is_live {
+                live.push(token.family.clone());
+            }
+
+            // Rows come ordered by last use within a family, so the latest
+            // token's client details win.
+            match sessions.last_mut() {
+                Some(session) if session.id == token.family => {
+                    session.created_when = session.created_when.min(token.created_when);
+                    session.last_used_when = token.last_used_when;
+                    session.user_agent = token.user_agent;
+                    session.ip_address = token.ip_address;
+                    continue;
+                }
+                _ => {}
+            }
+
+            sessions.push(Session {
+                id: token.family,
+                created_when: token.created_when,
+                last_used_when: token.last_used_when,
+                user_agent: token.user_agent,
+                ip_address: token.ip_address,
+            });
+        }
+
+        sessions.retain(|session| live.contains(&session.id));
+        sessions.sort_by(|a, b| b.last_used_when.cmp(&a.last_used_when));
+
+        Ok(sessions)
+    }
+
+    /// Revokes one of the user's sessions, returning whether it existed.
+    pub fn revoke_session(
+        conn: &PgConnection,
+        user_id: i64,
+        session_id: &str,
+    ) -> Result<bool, diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+
+        let revoked = diesel::delete(
+            dsl::tokens
+                .filter(dsl::user_id.eq(user_id))
+                .filter(dsl::family.eq(session_id)),
+        )
+        .execute(conn)?;
+
+        Ok(revoked > 0)
+    }
+
+    /// Revokes every session of the user except the one `token` belongs to.
+    pub fn revoke_others(
+        conn: &PgConnection,
+        user_id: i64,
+        token: &str,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+
+        conn.transaction(|| {
+            let family = Self::find(conn, token)?
+                .map(|token| token.family)
+                .unwrap_or_default();
+
+            diesel::delete(
+                dsl::tokens
+                    .filter(dsl::user_id.eq(user_id))
+                    .filter(dsl::family.ne(family)),
+            )
+            .execute(conn)
+            .map(|_| ())
+        })
+    }
+
+    /// Revokes every session of the user, logging them out everywhere.
+    pub fn revoke_all(conn: &PgConnection, user_id: i64) -> Result<(), diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+
+        diesel::delete(dsl::tokens.filter(dsl::user_id.eq(user_id)))
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    /// Resolves an access token to its user.
+    ///
+    /// Tokens past their absolute lifetime or left idle for too long are
+    /// rejected as if they did not exist. Every successful use pushes the idle
+    /// deadline back and records the client it came from.
+    pub fn user_by_token(
+        conn: &PgConnection,
+        token: &str,
+        client: &Client,
+    ) -> Result<User, diesel::result::Error> {
+        use crate::schema::tokens::dsl;
+        use crate::schema::users;
+
+        let now = Utc::now();
+        let policy = TokenPolicy::from_env();
+
+        conn.transaction(|| {
+            let token = Self::find(conn, token)?
+                .filter(|token| {
+                    token.kind == Self::ACCESS
+                        && token.created_when > now - policy.access_lifetime
+                        && token.last_used_when > now - policy.access_idle
+                })
+                .ok_or(diesel::result::Error::NotFound)?;
+
+            diesel::update(&token)
+                .set((
+                    dsl::last_used_when.eq(now),
+                    dsl::user_agent.eq(&client.user_agent),
+                    dsl::ip_address.eq(&client.ip_address),
+                ))
+                .execute(conn)?;
+
+            client.identify(token.user_id);
+
+            users::dsl::users
+                .find(token.user_id)
+                .filter(users::dsl::disabled.eq(false))
+                .get_result::<User>(conn)
+        })
+    }
+}
+
+/// A single sign-on attempt waiting for the user to come back from the
+/// identity provider.
+#[derive(Queryable, Identifiable)]
+pub struct OidcLogin {
+    pub id: i64,
+    pub state_prefix: String,
+    pub state_hash: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub created_when: chrono::DateTime<Utc>,
+}
+
+impl OidcLogin {
+    /// How long the user has to sign in at the provider, from
+    /// `OIDC_LOGIN_LIFETIME_SECONDS`.
+    pub fn lifetime() -> chrono::Duration {
+        chrono::Duration::seconds(
+            std::env::var("OIDC_LOGIN_LIFETIME_SECONDS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(600),
+        )
+    }
+
+    pub fn create(
+        conn: &PgConnection,
+        authorization: &oidc::Authorization,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::oidc_logins::dsl;
+
+        diesel::insert_into(dsl::oidc_logins)
+            .values((
+                dsl::state_prefix.eq(secrets::prefix(&authorization.state)),
+                dsl::state_hash.eq(secrets::digest(&authorization.state)),
+                dsl::nonce.eq(&authorization.nonce),
+                dsl::code_verifier.eq(&authorization.code_verifier),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Takes the pending login a callback's `state` belongs to, or `None`
+    /// when it is unknown or expired. Either way it cannot be used again.
+    pub fn redeem(conn: &PgConnection, state: &str) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::oidc_logins::dsl;
+
+        conn.transaction(|| {
+            let now = Utc::now();
+            let login = dsl::oidc_logins
+                .filter(dsl::state_prefix.eq(secrets::prefix(state)))
+                .for_update()
+                .get_results::<Self>(conn)?
+                .into_iter()
+                .find(|candidate| secrets::verify(state, &candidate.state_hash));
+
+            match login {
+                Some(login) => {
+                    diesel::delete(&login).execute(conn)?;
+
+                    if login.created_when + Self::lifetime() > now {
+                        Ok(Some(login))
+                    } else {
+                        Ok(None)
+                    }
+                }
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+/// A single-use proof of access to a user's mailbox, for resetting a
+/// forgotten password.
+#[derive(Queryable, Identifiable)]
+pub struct PasswordReset {
+    pub id: i64,
+    pub user_id: i64,
+    pub token_prefix: String,
+    pub token_hash: String,
+    pub created_when: chrono::DateTime<Utc>,
+    pub used_when: Option<chrono::DateTime<Utc>>,
+}
+
+impl PasswordReset {
+    /// How long a reset token stays valid, from
+    /// `PASSWORD_RESET_LIFETIME_SECONDS`.
+    pub fn lifetime() -> chrono::Duration {
+        chrono::Duration::seconds(
+            std::env::var("PASSWORD_RESET_LIFETIME_SECONDS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(3600),
+        )
+    }
+
+    /// Issues a reset token, unless the user already has
+    /// `PASSWORD_RESET_MAX_PENDING` unused ones (3 by default) that are still
+    /// valid, so that nobody can flood their mailbox.
+    pub fn create(
+        conn: &PgConnection,
+        user_id: i64,
+    ) -> Result<Option<String>, diesel::result::Error> {
+        use crate::schema::password_resets::dsl;
+
+        let max_pending: i64 = std::env::var("PASSWORD_RESET_MAX_PENDING")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(3);
+
+        let pending = dsl::password_resets
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::used_when.is_null())
+            .filter(dsl::created_when.gt(Utc::now() - Self::lifetime()))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        if pending >= max_pending {
+            return Ok(None);
+        }
+
+        let token = secrets::generate();
+
+        diesel::insert_into(dsl::password_resets)
+            .values((
+                dsl::user_id.eq(user_id),
+                dsl::token_prefix.eq(secrets::prefix(&token)),
+                dsl::token_hash.eq(secrets::digest(&token)),
+            ))
+            .execute(conn)?;
+
+        Ok(Some(token))
+    }
+
+    /// Uses up a reset token, returning the user it was issued to, or `None`
+    /// when it is unknown, expired or already used. Having received the
+    /// token verifies the user's email address.
+    pub fn redeem(conn: &PgConnection, token: &str) -> Result<Option<i64>, diesel::result::Error> {
+        use crate::schema::password_resets::dsl;
+        use crate::schema::users;
+
+        conn.transaction(|| {
+            let now = Utc::now();
+            let reset = dsl::password_resets
+                .filter(dsl::token_prefix.eq(secrets::prefix(token)))
+                .for_update()
+                .get_results::<Self>(conn)?
+                .into_iter()
+                .find(|candidate| secrets::verify(token, &candidate.token_hash))
+                .filter(|reset| {
+                    reset.used_when.is_none() && reset.created_when + Self::lifetime() > now
+                });
+
+            match reset {
+                Some(reset) => {
+                    diesel::update(&reset)
+                        .set(dsl::used_when.eq(now))
+                        .execute(conn)?;
+                    diesel::update(users::dsl::users.find(reset.user_id))
+                        .set(users::dsl::email_verified.eq(true))
+                        .execute(conn)?;
+
+                    Ok(Some(reset.user_id))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+/// A single-use code an admin hands out to let someone register while
+/// registration is invite-only.
+#[derive(Queryable, Identifiable)]
+pub struct Invitation {
+    pub id: i64,
+    pub code_prefix: String,
+    pub code_hash: String,
+    pub rank: i32,
+    pub created_by: Option<i64>,
+    pub created_when: chrono::DateTime<Utc>,
+    pub expires_when: Option<chrono::DateTime<Utc>>,
+    pub used_by: Option<i64>,
+    pub used_when: Option<chrono::DateTime<Utc>>,
+}
+
+impl Invitation {
+    pub fn create(
+        conn: &PgConnection,
+        created_by: i64,
+        role: Role,
+        expires_when: Option<chrono::DateTime<Utc>>,
+    ) -> Result<(Self, String), diesel::result::Error> {
+        use crate::schema::invitations::dsl;
+
+        let code = secrets::generate();
+
+        let invitation = diesel::insert_into(dsl::invitations)
+            .values((
+                dsl::code_prefix.eq(secrets::prefix(&code)),
+                dsl::code_hash.eq(secrets::digest(&code)),
+                dsl::rank.eq(role.rank()),
+                dsl::created_by.eq(created_by),
+                dsl::expires_when.eq(expires_when),
+            ))
+            .get_result::<Self>(conn)?;
+
+        Ok((invitation, code))
+    }
+
+    pub fn list(conn: &PgConnection) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::invitations::dsl;
+
+        dsl::invitations
+            .order(dsl::created_when.desc())
+            .get_results::<Self>(conn)
+    }
+
+    /// Deletes an invitation that has not been used yet, returning whether
+    /// there was one.
+    pub fn revoke(conn: &PgConnection, invitation_id: i64) -> Result<bool, diesel::result::Error> {
+        use crate::schema::invitations::dsl;
+
+        let deleted = diesel::delete(
+            dsl::invitations
+                .find(invitation_id)
+                .filter(dsl::used_when.is_null()),
+        )
+        .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Locks the invitation a code belongs to for registration, or returns
+    /// `None` when it is unknown, expired or already used. Must be called
+    /// within a transaction that goes on to `mark_used` it.
+    pub fn claim(conn: &PgConnection, code: &str) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::invitations::dsl;
+
+        let now = Utc::now();
+
+        Ok(dsl::invitations
+            .filter(dsl::code_prefix.eq(secrets::prefix(code)))
+            .for_update()
+            .get_results::<Self>(conn)?
+            .into_iter()
+            .find(|candidate| secrets::verify(code, &candidate.code_hash))
+            .filter(|invitation| {
+                invitation.used_when.is_none()
+                    && invitation
+                        .expires_when
+                        .map_or(true, |expires_when| expires_when > now)
+            }))
+    }
+
+    pub fn mark_used(
+        &self,
+        conn: &PgConnection,
+        user_id: i64,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::invitations::dsl;
+
+        diesel::update(self)
+            .set((dsl::used_by.eq(user_id), dsl::used_when.eq(Utc::now())))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Drops a user's pending invitations and their name from the rest, so
+    /// that the account can be deleted.
+    fn forget_user(conn: &PgConnection, user_id: i64) -> Result<(), diesel::result::Error> {
+        use crate::schema::invitations::dsl;
+
+        diesel::delete(
+            dsl::invitations
+                .filter(dsl::created_by.eq(user_id))
+                .filter(dsl::used_when.is_null()),
+        )
+        .execute(conn)?;
+        diesel::update(dsl::invitations.filter(dsl::created_by.eq(user_id)))
+            .set(dsl::created_by.eq(None::<i64>))
+            .execute(conn)?;
+        diesel::update(dsl::invitations.filter(dsl::used_by.eq(user_id)))
+            .set(dsl::used_by.eq(None::<i64>))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
+
+/// When failed logins start to slow down, from `LOGIN_FREE_ATTEMPTS`,
+/// `LOGIN_FREE_ATTEMPTS_PER_IP`, `LOGIN_ATTEMPT_WINDOW_SECONDS` and
+/// `LOGIN_MAX_LOCKOUT_SECONDS`.
+pub struct LoginPolicy {
+    pub free_attempts: i64,
+    pub free_attempts_per_ip: i64,
+    pub window: chrono::Duration,
+    pub max_lockout: chrono::Duration,
+}
+
+impl LoginPolicy {
+    pub fn from_env() -> Self {
+        let number = |name: &str, default: i64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        LoginPolicy {
+            free_attempts: number("LOGIN_FREE_ATTEMPTS", 5),
+            free_attempts_per_ip: number("LOGIN_FREE_ATTEMPTS_PER_IP", 20),
+            window: chrono::Duration::seconds(number("LOGIN_ATTEMPT_WINDOW_SECONDS", 3600)),
+            max_lockout: chrono::Duration::seconds(number("LOGIN_MAX_LOCKOUT_SECONDS", 900)),
+        }
+    }
+
+    /// How long to refuse logins after `failures` failed attempts: nothing
+    /// for the first `free` of them, then doubling from a second up to
+    /// `max_lockout`.
+    fn lockout(&self, failures: i64, free: i64) -> Option<chrono::Duration> {
+        if failures <= free {
+            return None;
+        }
+
+        let seconds = 1i64 << (failures - free - 1).min(30);
+
+        Some(chrono::Duration::seconds(seconds).min(self.max_lockout))
+    }
+
+    /// When logins will be accepted again at `now`, given how many failures
+    /// count against the account and against the address, and when the last
+    /// of each was.
+    fn locked_until(
+        &self,
+        account: (i64, Option<chrono::DateTime<Utc>>),
+        address: Option<(i64, Option<chrono::DateTime<Utc>>)>,
+        now: chrono::DateTime<Utc>,
+    ) -> Option<chrono::DateTime<Utc>> {
+        let until_for = |(failures, last_failure): (i64, Option<chrono::DateTime<Utc>>), free| {
+            last_failure.and_then(|last_failure| {
+                self.lockout(failures, free)
+                    .map(|lockout| last_failure + lockout)
+            })
+        };
+
+        let account_until = until_for(account, self.free_attempts);
+        let address_until =
+            address.and_then(|address| until_for(address, self.free_attempts_per_ip));
+
+        account_until
+            .max(address_until)
+            .filter(|until| *until > now)
+    }
+}
+
+/// A login attempt against a username, kept as an audit trail and to
+/// throttle guessing. Wrong passwords and wrong second factors are both
+/// failures; only logins that end with tokens are successes.
+#[derive(Queryable, Serialize)]
+pub struct LoginAttempt {
+    pub id: i64,
+    pub username: String,
+    pub ip_address: Option<String>,
+    pub succeeded: bool,
+    pub attempted_when: chrono::DateTime<Utc>,
+}
+
+impl LoginAttempt {
+    pub fn record(
+        conn: &PgConnection,
+        username: &str,
+        client: &Client,
+        succeeded: bool,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::login_attempts::dsl;
+
+        diesel::insert_into(dsl::login_attempts)
+            .values((
+                dsl::username.eq(username),
+                dsl::ip_address.eq(&client.ip_address),
+                dsl::succeeded.eq(succeeded),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// When logins for `username` from `client` will be accepted again, or
+    /// `None` if they are now.
+    ///
+    /// Failures against the account count until it next logs in. Failures
+    /// from the address always count, so that an attacker cannot clear them
+    /// by logging into an account of their own.
+    pub fn locked_until(
+        conn: &PgConnection,
+        username: &str,
+        client: &Client,
+        policy: &LoginPolicy,
+    ) -> Result<Option<chrono::DateTime<Utc>>, diesel::result::Error> {
+        use crate::schema::login_attempts::dsl;
+        use diesel::dsl::{count_star, max};
+
+        let now = Utc::now();
+        let window_start = now - policy.window;
+
+        let last_success = dsl::login_attempts
+            .filter(dsl::username.eq(username))
+            .filter(dsl::succeeded.eq(true))
+            .select(max(dsl::attempted_when))
+            .get_result::<Option<chrono::DateTime<Utc>>>(conn)?;
+
+        let account = dsl::login_attempts
+            .filter(dsl::username.eq(username))
+            .filter(dsl::succeeded.eq(false))
+            .filter(dsl::attempted_when.gt(
+                last_success.map_or(window_start, |last_success| last_success.max(window_start)),
+            ))
+            .select((count_star(), max(dsl::attempted_when)))
+            .get_result::<(i64, Option<chrono::DateTime<Utc>>)>(conn)?;
+
+        let address = match client.ip_address {
+            Some(ref ip_address) => Some(
+                dsl::login_attempts
+                    .filter(dsl::ip_address.eq(ip_address))
+                    .filter(dsl::succeeded.eq(false))
+                    .filter(dsl::attempted_when.gt(window_start))
+                    .select((count_star(), max(dsl::attempted_when)))
+                    .get_result::<(i64, Option<chrono::DateTime<Utc>>)>(conn)?,
+            ),
+            None => None,
+        };
+
+        Ok(policy.locked_until(account, address, now))
+    }
+}
+
+#[derive(Queryable, Serialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub actor_id: Option<i64>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: String,
+    pub occurred_when: chrono::DateTime<Utc>,
+}
+
+pub struct AuditFilter {
+    pub actor_id: Option<i64>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub outcome: Option<audit::Outcome>,
+    pub from: Option<chrono::DateTime<Utc>>,
+    pub to: Option<chrono::DateTime<Utc>>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl AuditEvent {
+    pub fn insert(conn: &PgConnection, event: &audit::Event) -> Result<(), diesel::result::Error> {
+        use crate::schema::audit_events::dsl;
+
+        diesel::insert_into(dsl::audit_events)
+            .values((
+                dsl::actor_id.eq(event.actor_id),
+                dsl::action.eq(event.action),
+                dsl::target.eq(&event.target),
+                dsl::ip_address.eq(&event.ip_address),
+                dsl::user_agent.eq(&event.user_agent),
+                dsl::outcome.eq(event.outcome.as_str()),
+                dsl::occurred_when.eq(event.occurred_when),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Newest first. `action` matches itself and anything under it, so
+    /// `auth.sessions` also finds `auth.sessions.revoke`.
+    pub fn search(
+        conn: &PgConnection,
+        filter: &AuditFilter,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::audit_events::dsl;
+
+        let mut query = dsl::audit_events.into_boxed();
+
+        if let Some(actor_id) = filter.actor_id {
+            query = query.filter(dsl::actor_id.eq(actor_id));
+        }
+
+        if let Some(ref action) = filter.action {
+            let pattern = format!(
+                "{}.%",
+                action
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            );
+
+            query = query.filter(dsl::action.eq(action.clone()).or(dsl::action.like(pattern)));
+        }
+
+        if let Some(ref target) = filter.target {
+            query = query.filter(dsl::target.eq(target.clone()));
+        }
+
+        if let Some(outcome) = filter.outcome {
+            query = query.filter(dsl::outcome.eq(outcome.as_str()));
+        }
+
+        if let Some(from) = filter.from {
+            query = query.filter(dsl::occurred_when.ge(from));
+        }
+
+        if let Some(to) = filter.to {
+            query = query.filter(dsl::occurred_when.lt(to));
+        }
+
+        query
+            .order((dsl::occurred_when.desc(), dsl::id.desc()))
+            .limit(filter.limit)
+            .offset(filter.offset)
+            .get_results::<Self>(conn)
+    }
+}
+
+/// What an API key may be used for.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "reports:read")]
+    ReportsRead,
+    #[serde(rename = "reports:write")]
+    ReportsWrite,
+    #[serde(rename = "files:download")]
+    FilesDownload,
+    #[serde(rename = "files:delete")]
+    FilesDelete,
+    #[serde(rename = "profiles:read")]
+    ProfilesRead,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReportsRead => "reports:read",
+            Scope::ReportsWrite => "reports:write",
+            Scope::FilesDownload => "files:download",
+            Scope::FilesDelete => "files:delete",
+            Scope::ProfilesRead => "profiles:read",
+        }
+    }
+}
+
+/// Parses an address or a CIDR range such as `10.0.0.0/8`.
+fn parse_ip_range(range: &str) -> Option<(IpAddr, u32)> {
+    let mut parts = range.splitn(2, '/');
+    let network: IpAddr = parts.next()?.parse().ok()?;
+    let max_bits = if network.is_ipv4() { 32 } else { 128 };
+    let bits = match parts.next() {
+        Some(bits) => bits.parse().ok()?,
+        None => max_bits,
+    };
+
+    if bits <= max_bits {
+        Some((network, bits))
+    } else {
+        None
+    }
+}
+
+fn ip_in_range(ip: IpAddr, (network, bits): (IpAddr, u32)) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::max_value().checked_shl(32 - bits).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::max_value().checked_shl(128 - bits).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// A long-lived credential for automation, limited to a set of scopes.
+#[derive(Queryable, Identifiable, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub key_prefix: String,
+    #[serde(skip)]
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub allowed_ips: Vec<String>,
+    pub expires_when: Option<chrono::DateTime<Utc>>,
+    pub created_when: chrono::DateTime<Utc>,
+    pub last_used_when: Option<chrono::DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Sets API keys apart from session tokens.
+    pub const MARKER: &'static str = "key_";
+
+    pub fn is_valid_ip_range(range: &str) -> bool {
+        parse_ip_range(range).is_some()
+    }
+
+    /// The part of a key it is looked up by, taken after the marker so that
+    /// all of it tells keys apart.
+    fn prefix(key: &str) -> &str {
+        secrets::prefix(key.get(Self::MARKER.len()..).unwrap_or(key))
+    }
+
+    /// Creates a key and returns it along with the secret, which is not
+    /// stored and cannot be shown again.
+    pub fn create(
+        conn: &PgConnection,
+        user_id: i64,
+        name: &str,
+        scopes: &[Scope],
+        allowed_ips: &[String],
+        expires_when: Option<chrono::DateTime<Utc>>,
+    ) -> Result<(Self, String), diesel::result::Error> {
+        use crate::schema::api_keys::dsl;
+
+        let key = format!("{}{}", Self::MARKER, secrets::generate());
+        let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+
+        let api_key = diesel::insert_into(dsl::api_keys)
+            .values((
+                dsl::user_id.eq(user_id),
+                dsl::name.eq(name),
+                dsl::key_prefix.eq(Self::prefix(&key)),
+                dsl::key_hash.eq(secrets::digest(&key)),
+                dsl::scopes.eq(scopes),
+                dsl::allowed_ips.eq(allowed_ips),
+                dsl::expires_when.eq(expires_when),
+            ))
+            .get_result::<Self>(conn)?;
+
+        Ok((api_key, key))
+    }
+
+    pub fn list_for_user(
+        conn: &PgConnection,
+        user_id: i64,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::api_keys::dsl;
+
+        dsl::api_keys
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::created_when.desc())
+            .get_results::<Self>(conn)
+    }
+
+    /// Deletes one of the user's keys, returning whether it existed.
+    pub fn revoke(
+        conn: &PgConnection,
+        user_id: i64,
+        key_id: i64,
+    ) -> Result<bool, diesel::result::Error> {
+        use crate::schema::api_keys::dsl;
+
+        let revoked = diesel::delete(
+            dsl::api_keys
+                .filter(dsl::user_id.eq(user_id))
+                .filter(dsl::id.eq(key_id)),
+        )
+        .execute(conn)?;
+
+        Ok(revoked > 0)
+    }
+
+    /// Deletes every key of the user.
+    pub fn revoke_all(conn: &PgConnection, user_id: i64) -> Result<(), diesel::result::Error> {
+        use crate::schema::api_keys::dsl;
+
+        diesel::delete(dsl::api_keys.filter(dsl::user_id.eq(user_id)))
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    fn allows_ip(&self, ip_address: Option<&str>) -> bool {
+        if self.allowed_ips.is_empty() {
+            return true;
+        }
+
+        match ip_address.and_then(|ip| ip.parse().ok()) {
+            Some(ip) => self
+                .allowed_ips
+                .iter()
+                .filter_map(|range| parse_ip_range(range))
+                .any(|range| ip_in_range(ip, range)),
+            None => false,
+        }
+    }
+
+    /// Resolves an API key to its user. Unknown and expired keys, and keys of
+    /// users who are disabled or due a password reset, come back as
+    /// `NotFound`; keys used outside their scopes or addresses as `Forbidden`.
+    fn user_by_key(
+        conn: &PgConnection,
+        key: &str,
+        client: &Client,
+        scope: Scope,
+    ) -> Result<User, Error> {
+        use crate::schema::api_keys::dsl;
+        use crate::schema::users;
+
+        let now = Utc::now();
+
+        // Keys created before the prefix skipped the marker are still filed
+        // under the marker and their first few characters.
+        let api_key = dsl::api_keys
+            .filter(dsl::key_prefix.eq_any(vec![Self::prefix(key), secrets::prefix(key)]))
+            .get_results::<Self>(conn)?
+            .into_iter()
+            .find(|candidate| secrets::verify(key, &candidate.key_hash))
+            .filter(|api_key| api_key.expires_when.map_or(true, |expires| expires > now))
+            .ok_or(diesel::result::Error::NotFound)?;
+
+        client.identify(api_key.user_id);
+
+        if !api_key
+            .scopes
+            .iter()
+            .any(|granted| granted == scope.as_str())
+            || !api_key.allows_ip(client.ip_address.as_ref().map(String::as_str))
+        {
+            return Err(Error::Forbidden);
+        }
+
+        diesel::update(&api_key)
+            .set(dsl::last_used_when.eq(now))
+            .execute(conn)?;
+
+        Ok(users::dsl::users
+            .find(api_key.user_id)
+            .filter(users::dsl::disabled.eq(false))
+            .filter(users::dsl::password_reset_required.eq(false))
+            .get_result::<User>(conn)?)
+    }
+}
+
+#[derive(Queryable, Serialize, Deserialize)]
+pub struct Profile {
+    pub id: i64,
+    pub machine_name: String,
+    pub human_name: String,
+    pub module: String,
+    pub config: Option<serde_json::Value>,
+}
+
+impl Profile {
+    pub fn list(conn: &PgConnection) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::profiles::dsl;
+
+        dsl::profiles.get_results::<Self>(conn)
+    }
+
+    pub fn create(
+        conn: &PgConnection,
+        machine_name: &str,
+        human_name: &str,
+        module: &str,
+        config: Option<&serde_json::Value>,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::profiles::dsl;
+
+        diesel::insert_into(dsl::profiles)
+            .values((
+                dsl::machine_name.eq(machine_name),
+                dsl::human_name.eq(human_name),
+                dsl::module.eq(module),
+                dsl::config.eq(config),
+            ))
+            .get_result::<Self>(conn)
+    }
+
+    pub fn by_id(conn: &PgConnection, profile_id: i64) -> Result<Self, diesel::result::Error> {
+        use crate::schema::profiles::dsl;
+
+        dsl::profiles.find(profile_id).get_result::<Self>(conn)
+    }
+
+    pub fn id_for_machine_name(
+        conn: &PgConnection,
+        machine_name: &str,
+    ) -> Result<i64, diesel::result::Error> {
+        use crate::schema::profiles::dsl;
+
+        let profile = dsl::profiles
+            .filter(dsl::machine_name.eq(machine_name))
+            .get_result::<Profile>(conn)?;
+
+        Ok(profile.id)
+    }
+
+    /// Fails with `UnknownProfile` for the first of the names that is
+    /// unknown.
+    pub fn ids_for_machine_names(
+        conn: &PgConnection,
+        machine_names: &[&str],
+    ) -> Result<Vec<i64>, Error> {
+        machine_names
+            .iter()
+            .map(
+                |machine_name| match Self::id_for_machine_name(conn, machine_name) {
+                    Err(diesel::result::Error::NotFound) => {
+                        Err(Error::UnknownProfile(machine_name.to_string()))
+                    }
+                    result => Ok(result?),
+                },
+            )
+            .collect()
+    }
+}
+
+pub struct ReportFilter {
+    pub after: Option<(chrono::DateTime<Utc>, i64)>,
+    pub limit: i64,
+    pub ascending: bool,
+    pub from: Option<chrono::DateTime<Utc>>,
+    pub to: Option<chrono::DateTime<Utc>>,
+    pub multihash: Option<String>,
+    pub profile: Option<String>,
+    pub verdict: Option<Verdict>,
+}
+
+#[derive(Clone, Identifiable, Queryable, Serialize, Deserialize)]
+pub struct Report {
+    pub id: i64,
+    pub user_id: i64,
+    pub created_when: chrono::DateTime<Utc>,
+    pub file_multihash: String,
+    pub has_file: bool,
+    pub file_name: Option<String>,
+}
+
+impl Report {
+    pub fn create(
+        conn: &PgConnection,
+        user_id: i64,
+        file_multihash: &str,
+        file_name: Option<&str>,
+    ) -> Result<i64, diesel::result::Error> {
+        use crate::schema::reports::dsl;
+
+        diesel::insert_into(dsl::reports)
+            .values((
+                dsl::user_id.eq(user_id),
+                dsl::file_multihash.eq(file_multihash),
+                dsl::has_file.eq(true),
+                dsl::file_name.eq(file_name),
+            ))
+            .returning(dsl::id)
+            .get_result(conn)
+    }
+
+    /// Hides reports the user may not act on behind `NotFound`, as if they
+    /// did not exist.
+    fn check_user(
+        self,
+        user: &User,
+        permission: Permission,
+    ) -> Result<Self, diesel::result::Error> {
+        match user.owner_filter(permission) {
+            Some(owner) if owner != self.user_id => Err(diesel::result::Error::NotFound),
+            _ => Ok(self),
+        }
+    }
+
+    /// Deletes all of a user's reports and their tasks, returning the
+    /// storage keys of files no other report refers to.
+    pub fn delete_for_user(
+        conn: &PgConnection,
+        user_id: i64,
+    ) -> Result<Vec<String>, diesel::result::Error> {
+        use crate::schema::reports::dsl;
+        use crate::schema::tasks;
+
+        let reports = dsl::reports
+            .filter(dsl::user_id.eq(user_id))
+            .for_update()
+            .get_results::<Self>(conn)?;
+        let report_ids: Vec<i64> = reports.iter().map(|report| report.id).collect();
+
+        let mut orphaned_keys = Vec::new();
+
+        for report in reports.iter().filter(|report| report.has_file) {
+            if let Some(key) = Sample::release(conn, &report.file_multihash)? {
+                orphaned_keys.push(key);
+            }
+        }
+
+        diesel::delete(tasks::dsl::tasks.filter(tasks::dsl::report_id.eq_any(&report_ids)))
+            .execute(conn)?;
+        diesel::delete(dsl::reports.filter(dsl::id.eq_any(&report_ids))).execute(conn)?;
+
+        Ok(orphaned_keys)
+    }
+
+    /// Hands all of a user's reports over to another user, who must exist.
+    pub fn reassign(
+        conn: &PgConnection,
+        user_id: i64,
+        new_owner: i64,
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::reports::dsl;
+
+        User::by_id(conn, new_owner)?;
+
+        diesel::update(dsl::reports.filter(dsl::user_id.eq(user_id)))
+            .set(dsl::user_id.eq(new_owner))
+            .execute(conn)
+    }
+
+    /// Detaches the stored file from a report and cancels its tasks that no
+    /// worker has claimed yet.
+    ///
+    /// Returns the storage key once no other report refers to the sample any
+    /// more. The caller should delete the bytes before committing, while the
+    /// sample row is still locked against concurrent uploads.
+    pub fn discard_file_check_user(
+        conn: &PgConnection,
+        report_id: i64,
+        user: &User,
+    ) -> Result<Option<String>, diesel::result::Error> {
+        use crate::schema::reports::dsl;
+
+        conn.transaction(|| {
+            let report = dsl::reports
+                .find(report_id)
+                .for_update()
+                .get_result::<Self>(conn)?
+                .check_user(user, Permission::ManageAllReports)?;
+
+            if !report.has_file {
+                return Ok(None);
+            }
+
+            diesel::update(&report)
+                .set(dsl::has_file.eq(false))
+                .execute(conn)?;
+
+            // Tasks still waiting for a worker could never fetch the file.
+            diesel::update(
+                tasks::dsl::tasks
+                    .filter(tasks::dsl::report_id.eq(report.id))
+                    .filter(tasks::dsl::status.eq_any(vec![TaskStatus::New, TaskStatus::Queued])),
+            )
+            .set((
+                tasks::dsl::status.eq(TaskStatus::Cancelled),
+                tasks::dsl::message.eq("file discarded"),
+                tasks::dsl::completed_when.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+
+            Sample::release(conn, &report.file_multihash)
+        })
+    }
+
+    /// Queues new tasks scanning the report's stored file again with the
+    /// given profiles, or with every profile it already has tasks for.
+    /// Returns `None` if the file has been discarded.
+    pub fn rescan_check_user(
+        conn: &PgConnection,
+        report_id: i64,
+        user: &User,
+        profile_machine_names: Option<&[&str]>,
+    ) -> Result<Option<Vec<i64>>, Error> {
+        conn.transaction(|| {
+            let report = Self::lock_check_user(conn, report_id, user)?;
+
+            if !report.has_file {
+                return Ok(None);
+            }
+
+            let mut profile_ids = match profile_machine_names {
+                Some(machine_names) => Profile::ids_for_machine_names(conn, machine_names)?,
+                None => Self::profile_ids(conn, report.id)?,
+            };
+
+            profile_ids.sort();
+            profile_ids.dedup();
+
+            profile_ids
+                .into_iter()
+                .map(|profile_id| Task::create(conn, report.id, profile_id))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Some)
+        })
+    }
+
+    /// Queues tasks for whichever of the given profiles the report has not
+    /// been scanned with yet. Returns `None` if the file has been discarded.
+    pub fn add_profiles_check_user(
+        conn: &PgConnection,
+        report_id: i64,
+        user: &User,
+        profile_machine_names: &[&str],
+    ) -> Result<Option<Vec<i64>>, Error> {
+        conn.transaction(|| {
+            let report = Self::lock_check_user(conn, report_id, user)?;
+
+            if !report.has_file {
+                return Ok(None);
+            }
+
+            let present = Self::profile_ids(conn, report.id)?;
+            let mut profile_ids = Profile::ids_for_machine_names(conn, profile_machine_names)?;
+
+            profile_ids.retain(|profile_id| !present.contains(profile_id));
+            profile_ids.sort();
+            profile_ids.dedup();
+
+            profile_ids
+                .into_iter()
+                .map(|profile_id| Task::create(conn, report.id, profile_id))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Some)
+        })
+    }
+
+    /// Locks a report the user may change, so that its file cannot be
+    /// discarded while tasks for it are being created.
+    fn lock_check_user(
+        conn: &PgConnection,
+        report_id: i64,
+        user: &User,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::reports::dsl;
+
+        dsl::reports
+            .find(report_id)
+            .for_update()
+            .get_result::<Self>(conn)?
+            .check_user(user, Permission::ManageAllReports)
+    }
+
+    /// The profiles the report has tasks for.
+    fn profile_ids(conn: &PgConnection, report_id: i64) -> Result<Vec<i64>, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+
+        dsl::tasks
+            .select(dsl::profile_id)
+            .filter(dsl::report_id.eq(report_id))
+            .distinct()
+            .get_results::<i64>(conn)
+    }
+
+    /// Returns one page of a user's reports, most recent first unless
+    /// `filter.ascending` is set.
+    ///
+    /// Pages are keyed on `(created_when, id)` so that reports created while a
+    /// client is paging do not shift the pages it has not fetched yet.
+    pub fn page_for_user(
+        conn: &PgConnection,
+        user: &User,
+        filter: &ReportFilter,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::reports::dsl;
+
+        let mut query = Self::matching(filter);
+
+        if let Some(owner) = user.owner_filter(Permission::ReadAllReports) {
+            query = query.filter(dsl::user_id.eq(owner));
+        }
+
+        query = match (filter.after, filter.ascending) {
+            (Some((created_when, id)), false) => query.filter(
+                dsl::created_when
+                    .lt(created_when)
+                    .or(dsl::created_when.eq(created_when).and(dsl::id.lt(id))),
+            ),
+            (Some((created_when, id)), true) => query.filter(
+                dsl::created_when
+                    .gt(created_when)
+                    .or(dsl::created_when.eq(created_when).and(dsl::id.gt(id))),
+            ),
+            (None, _) => query,
+        };
+
+        query = if filter.ascending {
+            query.order((dsl::created_when.asc(), dsl::id.asc()))
+        } else {
+            query.order((dsl::created_when.desc(), dsl::id.desc()))
+        };
+
+        query.limit(filter.limit).get_results::<Self>(conn)
+    }
+
+    /// Every report meeting the criteria of `filter`, before ownership and
+    /// paging are applied.
+    fn matching(filter: &ReportFilter) -> reports::BoxedQuery<'static, Pg> {
+        use crate::schema::reports::dsl;
+        use crate::schema::{profiles, tasks};
+        use diesel::dsl::not;
+
+        let with_verdict = |verdict: Verdict| {
+            tasks::dsl::tasks
+                .select(tasks::dsl::report_id)
+                .filter(tasks::dsl::verdict.eq(verdict))
+        };
+
+        let mut query = dsl::reports.into_boxed();
+
+        if let Some(from) = filter.from {
+            query = query.filter(dsl::created_when.ge(from));
+        }
+
+        if let Some(to) = filter.to {
+            query = query.filter(dsl::created_when.lt(to));
+        }
+
+        if let Some(ref multihash) = filter.multihash {
+            query = query.filter(dsl::file_multihash.eq(multihash.clone()));
+        }
+
+        if let Some(ref profile) = filter.profile {
+            query = query.filter(
+                dsl::id.eq_any(
+                    tasks::dsl::tasks
+                        .inner_join(profiles::table)
+                        .select(tasks::dsl::report_id)
+                        .filter(profiles::dsl::machine_name.eq(profile.clone())),
+                ),
+            );
+        }
+
+        if let Some(verdict) = filter.verdict {
+            query = query.filter(dsl::id.eq_any(with_verdict(verdict)));
+
+            for more_severe in &[Verdict::Clean, Verdict::Suspicious, Verdict::Malicious] {
+                if more_severe.severity() > verdict.severity() {
+                    query = query.filter(not(dsl::id.eq_any(with_verdict(*more_severe))));
+                }
+            }
+        }
+
+        query
+    }
+
+    pub fn list_for_user_by_multihashes(
+        conn: &PgConnection,
+        user: &User,
+        multihashes: &[String],
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::reports::dsl;
+
+        let mut query = dsl::reports
+            .filter(dsl::file_multihash.eq_any(multihashes))
+            .into_boxed();
+
+        if let Some(owner) = user.owner_filter(Permission::ReadAllReports) {
+            query = query.filter(dsl::user_id.eq(owner));
+        }
+
+        query
+            .order(dsl::created_when.desc())
+            .get_results::<Self>(conn)
+    }
+
+    /// Returns a report along with the storage key of its file, which is
+    /// `None` once the file has been discarded.
+    pub fn with_file_key_check_user(
+        conn: &PgConnection,
+        report_id: i64,
+        user: &User,
+    ) -> Result<(Self, Option<String>), diesel::result::Error> {
+        use crate::schema::reports::dsl;
+        use crate::schema::samples;
+
+        let (report, file_key) = dsl::reports
+            .inner_join(samples::table)
+            .filter(dsl::id.eq(report_id))
+            .select((reports::all_columns, samples::dsl::file_key))
+            .get_result::<(Self, Option<String>)>(conn)?;
+        let report = report.check_user(user, Permission::ReadAllReports)?;
+
+        let file_key = if report.has_file { file_key } else { None };
+
+        Ok((report, file_key))
+    }
+
+    pub fn by_id_check_user(
+        conn: &PgConnection,
+        report_id: i64,
+        user: &User,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::reports::dsl;
+
+        dsl::reports
+            .find(report_id)
+            .get_result::<Self>(conn)?
+            .check_user(user, Permission::ReadAllReports)
+    }
+}
+
+#[derive(Clone, Queryable, Identifiable, Serialize, Deserialize)]
+#[primary_key(multihash)]
+pub struct Sample {
+    pub multihash: String,
+    #[serde(skip)]
+    pub file_key: Option<String>,
+    pub size: Option<i64>,
+    pub reference_count: i32,
+    pub created_when: chrono::DateTime<Utc>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+    pub sha256: Option<String>,
+    pub sha512: Option<String>,
+}
+
+impl Sample {
+    /// Takes a reference on the sample with the given content hash, creating
+    /// it if this content has never been seen.
+    ///
+    /// The row stays locked until the surrounding transaction ends. A returned
+    /// sample without a `file_key` has no stored bytes yet, and the caller is
+    /// expected to store them and call `set_file_key`.
+    pub fn acquire(
+        conn: &PgConnection,
+        multihash: &str,
+        digests: &Digests,
+        size: i64,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::samples::dsl;
+
+        let details = (
+            dsl::size.eq(size),
+            dsl::md5.eq(&digests.md5),
+            dsl::sha1.eq(&digests.sha1),
+            dsl::sha256.eq(&digests.sha256),
+            dsl::sha512.eq(&digests.sha512),
+        );
+
+        diesel::insert_into(dsl::samples)
+            .values((
+                dsl::multihash.eq(multihash),
+                dsl::reference_count.eq(1),
+                details,
+            ))
+            .on_conflict(dsl::multihash)
+            .do_update()
+            .set((dsl::reference_count.eq(dsl::reference_count + 1), details))
+            .get_result::<Self>(conn)
+    }
+
+    /// Finds samples matching any of the given lowercase hex digests, whether
+    /// multihash, MD5, SHA-1, SHA-256 or SHA-512.
+    pub fn by_hashes(
+        conn: &PgConnection,
+        hashes: &[String],
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::samples::dsl;
+
+        dsl::samples
+            .filter(
+                dsl::multihash
+                    .eq_any(hashes)
+                    .or(dsl::md5.eq_any(hashes))
+                    .or(dsl::sha1.eq_any(hashes))
+                    .or(dsl::sha256.eq_any(hashes))
+                    .or(dsl::sha512.eq_any(hashes)),
+            )
+            .get_results::<Self>(conn)
+    }
+
+    pub fn matches(&self, hash: &str) -> bool {
+        self.multihash == hash
+            || [&self.md5, &self.sha1, &self.sha256, &self.sha512]
+                .iter()
+                .any(|digest| digest.as_ref().map(String::as_str) == Some(hash))
+    }
+
+    pub fn set_file_key(
+        conn: &PgConnection,
+        multihash: &str,
+        file_key: &str,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::samples::dsl;
+
+        diesel::update(dsl::samples.find(multihash))
+            .set(dsl::file_key.eq(file_key))
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    /// Drops a reference on a sample. Returns the storage key of its bytes
+    /// when this was the last reference.
+    pub fn release(
+        conn: &PgConnection,
+        multihash: &str,
+    ) -> Result<Option<String>, diesel::result::Error> {
+        use crate::schema::samples::dsl;
+
+        let sample = diesel::update(dsl::samples.find(multihash))
+            .set(dsl::reference_count.eq(dsl::reference_count - 1))
+            .get_result::<Self>(conn)?;
+
+        if sample.reference_count > 0 {
+            return Ok(None);
+        }
+
+        diesel::update(&sample)
+            .set(dsl::file_key.eq(None::<String>))
+            .execute(conn)?;
+
+        Ok(sample.file_key)
+    }
+
+    /// Records where the bytes of a sample first stored from a report's old
+    /// `file` column went, unless another report's copy got there first.
+    /// Returns whether the caller's copy is the one that was kept.
+    pub fn backfill(
+        conn: &PgConnection,
+        multihash: &str,
+        file_key: &str,
+        digests: &Digests,
+        size: i64,
+    ) -> Result<bool, diesel::result::Error> {
+        use crate::schema::samples::dsl;
+
+        let sample = dsl::samples
+            .find(multihash)
+            .for_update()
+            .get_result::<Self>(conn)?;
+
+        if sample.file_key.is_some() {
+            return Ok(false);
+        }
+
+        diesel::update(&sample)
+            .set((
+                dsl::file_key.eq(file_key),
+                dsl::size.eq(size),
+                dsl::md5.eq(&digests.md5),
+                dsl::sha1.eq(&digests.sha1),
+                dsl::sha256.eq(&digests.sha256),
+                dsl::sha512.eq(&digests.sha512),
+            ))
+            .execute(conn)
+            .map(|_| true)
+    }
+}
+
+/// A report whose bytes are still in the `reports.file` column from before
+/// samples moved to the blob store. The column is not in the schema, so it is
+/// only reached through raw SQL until the backfill has emptied it.
+#[derive(QueryableByName)]
+pub struct LegacyFile {
+    #[sql_type = "BigInt"]
+    pub id: i64,
+    #[sql_type = "Text"]
+    pub file_multihash: String,
+    /// False once the file was discarded, in which case the bytes are only
+    /// waiting to be cleared.
+    #[sql_type = "Bool"]
+    pub has_file: bool,
+    #[sql_type = "Binary"]
+    pub file: Vec<u8>,
+}
+
+impl LegacyFile {
+    /// Takes the next report still holding its file, locked until the
+    /// surrounding transaction ends. Other backfills skip it meanwhile.
+    pub fn next(conn: &PgConnection) -> Result<Option<Self>, diesel::result::Error> {
+        diesel::sql_query(
+            "SELECT id, file_multihash, has_file, file FROM reports WHERE file IS NOT NULL \
+             ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED",
+        )
+        .get_result::<Self>(conn)
+        .optional()
+    }
+
+    pub fn clear(&self, conn: &PgConnection) -> Result<(), diesel::result::Error> {
+        diesel::sql_query("UPDATE reports SET file = NULL WHERE id = $1")
+            .bind::<BigInt, _>(self.id)
+            .execute(conn)
+            .map(|_| ())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[sql_type = "Text"]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    New,
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+impl TaskStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaskStatus::New => "new",
+            TaskStatus::Queued => "queued",
+            TaskStatus::Running => "running",
+            TaskStatus::Succeeded => "succeeded",
+            TaskStatus::Failed => "failed",
+            TaskStatus::Cancelled => "cancelled",
+            TaskStatus::TimedOut => "timed_out",
+        }
+    }
+
+    /// Whether a task in this status may move to `next`.
+    ///
+    /// Running tasks may go back to `New` so the reaper can requeue them;
+    /// every other backwards move is refused, and finished tasks never move.
+    pub fn can_transition_to(self, next: TaskStatus) -> bool {
+        use TaskStatus::*;
+
+        match (self, next) {
+            (New, Queued) | (New, Running) | (New, Cancelled) | (New, Failed) => true,
+            (Queued, New) | (Queued, Running) | (Queued, Cancelled) | (Queued, Failed) => true,
+            (Running, New)
+            | (Running, Succeeded)
+            | (Running, Failed)
+            | (Running, Cancelled)
+            | (Running, TimedOut) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ToSql<Text, Pg> for TaskStatus {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for TaskStatus {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match <String as FromSql<Text, Pg>>::from_sql(bytes)?.as_str() {
+            "new" => Ok(TaskStatus::New),
+            "queued" => Ok(TaskStatus::Queued),
+            "running" => Ok(TaskStatus::Running),
+            "succeeded" => Ok(TaskStatus::Succeeded),
+            "failed" => Ok(TaskStatus::Failed),
+            "cancelled" => Ok(TaskStatus::Cancelled),
+            "timed_out" => Ok(TaskStatus::TimedOut),
+            other => Err(format!("unrecognized task status: {}", other).into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[sql_type = "Text"]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Clean,
+    Malicious,
+    Suspicious,
+    Error,
+}
+
+impl Verdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Clean => "clean",
+            Verdict::Malicious => "malicious",
+            Verdict::Suspicious => "suspicious",
+            Verdict::Error => "error",
+        }
+    }
+
+    /// Ranks verdicts so that a report's overall verdict is the most severe
+    /// one among its tasks.
+    fn severity(self) -> u8 {
+        match self {
+            Verdict::Error => 0,
+            Verdict::Clean => 1,
+            Verdict::Suspicious => 2,
+            Verdict::Malicious => 3,
+        }
+    }
+
+    pub fn aggregate<I: IntoIterator<Item = Verdict>>(verdicts: I) -> Option<Verdict> {
+        verdicts
+            .into_iter()
+            .max_by_key(|verdict| verdict.severity())
+    }
+}
+
+impl ToSql<Text, Pg> for Verdict {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for Verdict {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match <String as FromSql<Text, Pg>>::from_sql(bytes)?.as_str() {
+            "clean" => Ok(Verdict::Clean),
+            "malicious" => Ok(Verdict::Malicious),
+            "suspicious" => Ok(Verdict::Suspicious),
+            "error" => Ok(Verdict::Error),
+            other => Err(format!("unrecognized verdict: {}", other).into()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TaskResult {
+    pub detections: Vec<String>,
+    pub engine_version: Option<String>,
+    pub details: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Queryable, Identifiable, Serialize, Deserialize)]
+pub struct Task {
+    pub id: i64,
+    pub report_id: i64,
+    pub profile_id: i64,
+    pub created_when: chrono::DateTime<Utc>,
+    pub completed_when: Option<chrono::DateTime<Utc>>,
+    pub status: TaskStatus,
+    pub message: Option<String>,
+    pub worker_id: Option<i64>,
+    pub verdict: Option<Verdict>,
+    pub result: Option<serde_json::Value>,
+    pub attempts: i32,
+}
+
+impl Task {
+    /// Reports handled per statement by `queue_for_reports`, keeping well
+    /// under Postgres' limit of 65,535 parameters.
+    const QUEUE_CHUNK_SIZE: usize = 1_000;
+
+    pub fn list_for_report(
+        conn: &PgConnection,
+        report_id: i64,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+
+        dsl::tasks
+            .filter(dsl::report_id.eq(report_id))
+            .get_results::<Self>(conn)
+    }
+
+    pub fn list_for_reports(
+        conn: &PgConnection,
+        report_ids: &[i64],
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+
+        dsl::tasks
+            .filter(dsl::report_id.eq_any(report_ids))
+            .get_results::<Self>(conn)
+    }
+
+    /// Returns the overall verdict of each of the given reports that has at
+    /// least one task with a verdict.
+    pub fn verdicts_for_reports(
+        conn: &PgConnection,
+        report_ids: &[i64],
+    ) -> Result<HashMap<i64, Verdict>, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+
+        let verdicts = dsl::tasks
+            .select((dsl::report_id, dsl::verdict))
+            .filter(dsl::report_id.eq_any(report_ids))
+            .filter(dsl::verdict.is_not_null())
+            .get_results::<(i64, Option<Verdict>)>(conn)?;
+
+        let mut by_report: HashMap<i64, Vec<Verdict>> = HashMap::new();
+        for (report_id, verdict) in verdicts {
+            by_report
+                .entry(report_id)
+                .or_insert_with(Vec::new)
+                .extend(verdict);
+        }
+
+        Ok(by_report
+            .into_iter()
+            .filter_map(|(report_id, verdicts)| {
+                Verdict::aggregate(verdicts).map(|verdict| (report_id, verdict))
+            })
+            .collect())
+    }
+
+    pub fn create(
+        conn: &PgConnection,
+        report_id: i64,
+        profile_id: i64,
+    ) -> Result<i64, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+        diesel::insert_into(dsl::tasks)
+            .values((
+                dsl::report_id.eq(report_id),
+                dsl::profile_id.eq(profile_id),
+                dsl::status.eq(TaskStatus::New),
+            ))
+            .returning(dsl::id)
+            .get_result(conn)
+    }
+
+    /// Claims the oldest new task matching one of the worker's capabilities
+    /// whose report still has its file.
+    ///
+    /// Rows locked by a concurrent claim are skipped rather than waited on, so
+    /// several workers polling at once never receive the same task.
+    pub fn claim_for_worker(
+        conn: &PgConnection,
+        worker_id: i64,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::reports;
+        use crate::schema::tasks::dsl;
+        use crate::schema::worker_capabilities;
+
+        conn.transaction(|| {
+            let task = dsl::tasks
+                .filter(dsl::status.eq_any(vec![TaskStatus::New, TaskStatus::Queued]))
+                .filter(
+                    dsl::report_id.eq_any(
+                        reports::dsl::reports
+                            .select(reports::dsl::id)
+                            .filter(reports::dsl::has_file.eq(true)),
+                    ),
+                )
+                .filter(
+                    dsl::profile_id.eq_any(
+                        worker_capabilities::dsl::worker_capabilities
+                            .select(worker_capabilities::dsl::profile_id)
+                            .filter(worker_capabilities::dsl::worker_id.eq(worker_id)),
+                    ),
+                )
+                .order((dsl::created_when.asc(), dsl::id.asc()))
+                .limit(1)
+                .for_update()
+                .skip_locked()
+                .get_result::<Self>(conn)
+                .optional()?;
+
+            match task {
+                Some(ref task) if task.status.can_transition_to(TaskStatus::Running) => {
+                    diesel::update(task)
+                        .set((
+                            dsl::status.eq(TaskStatus::Running),
+                            dsl::worker_id.eq(worker_id),
+                        ))
+                        .get_result::<Self>(conn)
+                        .map(Some)
+                }
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// Returns the storage key of the sample behind a task running on the given
+    /// worker, or `None` if its file has been discarded.
+    pub fn sample_for_worker(
+        conn: &PgConnection,
+        task_id: i64,
+        worker_id: i64,
+    ) -> Result<Option<String>, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+        use crate::schema::{reports, samples};
+
+        dsl::tasks
+            .inner_join(reports::table.inner_join(samples::table))
+            .filter(dsl::id.eq(task_id))
+            .filter(dsl::worker_id.eq(worker_id))
+            .filter(dsl::status.eq(TaskStatus::Running))
+            .select((reports::dsl::has_file, samples::dsl::file_key))
+            .get_result::<(bool, Option<String>)>(conn)
+            .map(|(has_file, file_key)| if has_file { file_key } else { None })
+    }
+
+    /// Finds the most recent successful result for this content and profile,
+    /// whichever report it was scanned for.
+    pub fn latest_succeeded_for_sample(
+        conn: &PgConnection,
+        file_multihash: &str,
+        profile_id: i64,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::reports;
+        use crate::schema::tasks::dsl;
+
+        dsl::tasks
+            .inner_join(reports::table)
+            .filter(reports::dsl::file_multihash.eq(file_multihash))
+            .filter(dsl::profile_id.eq(profile_id))
+            .filter(dsl::status.eq(TaskStatus::Succeeded))
+            .order(dsl::completed_when.desc())
+            .select(tasks::all_columns)
+            .first::<Self>(conn)
+            .optional()
+    }
+
+    /// Creates an already completed task carrying over the outcome of
+    /// `previous`, instead of scanning the same content again.
+    pub fn create_reused(
+        conn: &PgConnection,
+        report_id: i64,
+        previous: &Self,
+    ) -> Result<i64, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+
+        diesel::insert_into(dsl::tasks)
+            .values((
+                dsl::report_id.eq(report_id),
+                dsl::profile_id.eq(previous.profile_id),
+                dsl::status.eq(previous.status),
+                dsl::verdict.eq(previous.verdict),
+                dsl::result.eq(previous.result.clone()),
+                dsl::message.eq(format!("reused result of task {}", previous.id)),
+                dsl::completed_when.eq(Utc::now()),
+            ))
+            .returning(dsl::id)
+            .get_result(conn)
+    }
+
+    /// Moves a task to `next`, refusing transitions the status machine does
+    /// not allow.
+    pub fn transition(
+        conn: &PgConnection,
+        task_id: i64,
+        next: TaskStatus,
+        message: Option<&str>,
+    ) -> Result<Self, Error> {
+        use crate::schema::tasks::dsl;
+
+        conn.transaction(|| {
+            let task = dsl::tasks
+                .find(task_id)
+                .for_update()
+                .get_result::<Self>(conn)?;
+
+            if !task.status.can_transition_to(next) {
+                return Err(Error::IllegalTransition(task.status, next));
+            }
+
+            let completed_when = match next {
+                TaskStatus::New | TaskStatus::Queued | TaskStatus::Running => None,
+                _ => Some(Utc::now()),
+            };
+
+            Ok(diesel::update(&task)
+                .set((
+                    dsl::status.eq(next),
+                    dsl::message.eq(message),
+                    dsl::completed_when.eq(completed_when),
+                ))
+                .get_result::<Self>(conn)?)
+        })
+    }
+
+    /// Cancels a task of the given report on behalf of its owner. Fails with
+    /// `IllegalTransition` once the task has finished.
+    ///
+    /// A running task keeps its worker, which learns of the cancellation on
+    /// its next heartbeat.
+    pub fn cancel_check_user(
+        conn: &PgConnection,
+        report_id: i64,
+        task_id: i64,
+        user: &User,
+    ) -> Result<Self, Error> {
+        use crate::schema::reports;
+        use crate::schema::tasks::dsl;
+
+        conn.transaction(|| {
+            reports::dsl::reports
+                .find(report_id)
+                .get_result::<Report>(conn)?
+                .check_user(user, Permission::ManageAllReports)?;
+
+            let task = dsl::tasks
+                .find(task_id)
+                .filter(dsl::report_id.eq(report_id))
+                .get_result::<Self>(conn)?;
+
+            Self::transition(
+                conn,
+                task.id,
+                TaskStatus::Cancelled,
+                Some(&format!("cancelled by {}", user.username)),
+            )
+        })
+    }
+
+    /// Queues a task with the profile for every report matching `filter`, and
+    /// owned by `owner` if given, that has its file and has not been scanned
+    /// with the profile yet. Paging fields of the filter are ignored. Returns
+    /// the number of tasks queued.
+    pub fn queue_for_reports(
+        conn: &PgConnection,
+        profile_id: i64,
+        owner: Option<i64>,
+        filter: &ReportFilter,
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::reports;
+        use crate::schema::tasks::dsl;
+        use diesel::dsl::not;
+
+        conn.transaction(|| {
+            let mut query = Report::matching(filter)
+                .select(reports::dsl::id)
+                .filter(reports::dsl::has_file.eq(true))
+                .filter(not(reports::dsl::id.eq_any(
+                    dsl::tasks
+                        .select(dsl::report_id)
+                        .filter(dsl::profile_id.eq(profile_id)),
+                )));
+
+            if let Some(owner) = owner {
+                query = query.filter(reports::dsl::user_id.eq(owner));
+            }
+
+            let report_ids = query.get_results::<i64>(conn)?;
+            let mut queued = 0;
+
+            for chunk in report_ids.chunks(Self::QUEUE_CHUNK_SIZE) {
+                // Locked, the reports keep their files until the tasks are
+                // in. Any discarded in the meantime drop out here.
+                let rows: Vec<_> = reports::dsl::reports
+                    .select(reports::dsl::id)
+                    .filter(reports::dsl::id.eq_any(chunk.to_vec()))
+                    .filter(reports::dsl::has_file.eq(true))
+                    .for_update()
+                    .get_results::<i64>(conn)?
+                    .into_iter()
+                    .map(|report_id| {
+                        (
+                            dsl::report_id.eq(report_id),
+                            dsl::profile_id.eq(profile_id),
+                            dsl::status.eq(TaskStatus::New),
+                        )
+                    })
+                    .collect();
+
+                if !rows.is_empty() {
+                    queued += diesel::insert_into(dsl::tasks)
+                        .values(&rows)
+                        .execute(conn)?;
+                }
+            }
+
+            Ok(queued)
+        })
+    }
+
+    /// Returns the ids of cancelled tasks still handed to the worker, and
+    /// takes them back from it so that each is only reported once.
+    pub fn take_cancelled_for_worker(
+        conn: &PgConnection,
+        worker_id: i64,
+    ) -> Result<Vec<i64>, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+
+        diesel::update(
+            dsl::tasks
+                .filter(dsl::worker_id.eq(worker_id))
+                .filter(dsl::status.eq(TaskStatus::Cancelled)),
+        )
+        .set(dsl::worker_id.eq(None::<i64>))
+        .returning(dsl::id)
+        .get_results::<i64>(conn)
+    }
+
+    /// Records the outcome of a task claimed by the given worker.
+    ///
+    /// Fails with `NotFound` unless the task was handed to that worker, and
+    /// with `IllegalTransition` if it is no longer running, which also
+    /// rejects duplicate submissions.
+    pub fn complete(
+        conn: &PgConnection,
+        task_id: i64,
+        worker_id: i64,
+        verdict: Verdict,
+        result: &TaskResult,
+        message: Option<&str>,
+    ) -> Result<Self, Error> {
+        use crate::schema::tasks::dsl;
+
+        let next = match verdict {
+            Verdict::Error => TaskStatus::Failed,
+            _ => TaskStatus::Succeeded,
+        };
+
+        conn.transaction(|| {
+            let task = dsl::tasks
+                .find(task_id)
+                .filter(dsl::worker_id.eq(worker_id))
+                .for_update()
+                .get_result::<Self>(conn)?;
+
+            // Requeued tasks lose their worker, so this only has to refuse
+            // results for tasks that were already finished or cancelled.
+            if !task.status.can_transition_to(next) {
+                return Err(Error::IllegalTransition(task.status, next));
+            }
+
+            Ok(diesel::update(&task)
+                .set((
+                    dsl::status.eq(next),
+                    dsl::verdict.eq(verdict),
+                    dsl::result.eq(serde_json::to_value(result).unwrap()),
+                    dsl::message.eq(message),
+                    dsl::completed_when.eq(Utc::now()),
+                ))
+                .get_result::<Self>(conn)?)
+        })
+    }
+
+    /// What a stale task that has been handed out `attempts` times before
+    /// becomes: back to `New`, or `Failed` on its last allowed attempt.
+    fn stale_status(attempts: i32, max_attempts: i32) -> TaskStatus {
+        if attempts >= max_attempts - 1 {
+            TaskStatus::Failed
+        } else {
+            TaskStatus::New
+        }
+    }
+
+    /// Takes running tasks away from workers whose last sign of life is older
+    /// than `lease` and puts them back in the queue.
+    ///
+    /// A task that has already been handed out `max_attempts` times is marked
+    /// failed instead. Returns the number of tasks that were requeued.
+    pub fn reap_stale(
+        conn: &PgConnection,
+        lease: chrono::Duration,
+        max_attempts: i32,
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::tasks::dsl;
+        use crate::schema::workers;
+
+        let cutoff = Utc::now() - lease;
+        let stale_workers = || {
+            workers::dsl::workers
+                .select(workers::dsl::id.nullable())
+                .filter(workers::dsl::last_active.lt(cutoff))
+        };
+
+        conn.transaction(|| {
+            // Locked so that a result submitted meanwhile either lands first
+            // or waits for the task to be requeued and is then refused.
+            let stale = dsl::tasks
+                .filter(dsl::status.eq(TaskStatus::Running))
+                .filter(dsl::worker_id.eq_any(stale_workers()))
+                .for_update()
+                .get_results::<Self>(conn)?;
+
+            let mut requeued = 0;
+
+            for task in stale {
+                let next = Self::stale_status(task.attempts, max_attempts);
+
+                if !task.status.can_transition_to(next) {
+                    continue;
+                }
+
+                if next == TaskStatus::Failed {
+                    diesel::update(&task)
+                        .set((
+                            dsl::status.eq(next),
+                            dsl::attempts.eq(dsl::attempts + 1),
+                            dsl::completed_when.eq(Utc::now()),
+                            dsl::message.eq(format!(
+                                "worker stopped responding, gave up after {} attempts",
+                                max_attempts
+                            )),
+                        ))
+                        .execute(conn)?;
+                } else {
+                    requeued += diesel::update(&task)
+                        .set((
+                            dsl::status.eq(next),
+                            dsl::attempts.eq(dsl::attempts + 1),
+                            dsl::worker_id.eq(None::<i64>),
+                        ))
+                        .execute(conn)?;
+                }
+            }
+
+            Ok(requeued)
+        })
+    }
+}
+
+#[derive(Queryable, Identifiable, Serialize, Deserialize)]
+pub struct Worker {
+    pub id: i64,
+    pub last_active: chrono::DateTime<Utc>,
+    pub name: Option<String>,
+    pub registered_when: chrono::DateTime<Utc>,
+    pub token_prefix: Option<String>,
+    #[serde(skip)]
+    pub token_hash: Option<String>,
+}
+
+impl Worker {
+    pub fn list(conn: &PgConnection) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::workers::dsl;
+
+        dsl::workers.order(dsl::id).get_results::<Self>(conn)
+    }
+
+    /// Withdraws a worker's credential and capabilities. Tasks it was running
+    /// are picked up again by the reaper once its lease runs out.
+    pub fn revoke(conn: &PgConnection, worker_id: i64) -> Result<bool, diesel::result::Error> {
+        use crate::schema::worker_capabilities;
+        use crate::schema::workers::dsl;
+
+        conn.transaction(|| {
+            let revoked = diesel::update(dsl::workers.find(worker_id))
+                .set((
+                    dsl::token_prefix.eq(None::<String>),
+                    dsl::token_hash.eq(None::<String>),
+                ))
+                .execute(conn)?;
+
+            diesel::delete(
+                worker_capabilities::dsl::worker_capabilities
+                    .filter(worker_capabilities::dsl::worker_id.eq(worker_id)),
+            )
+            .execute(conn)?;
+
+            Ok(revoked > 0)
+        })
+    }
+
+    /// Creates a worker serving the given profiles and returns its id along
+    /// with the credential it must present on every subsequent request.
+    pub fn register(
+        conn: &PgConnection,
+        name: &str,
+        profile_machine_names: &[String],
+    ) -> Result<(i64, String), diesel::result::Error> {
+        use crate::schema::worker_capabilities;
+        use crate::schema::workers::dsl;
+
+        let token = secrets::generate();
+
+        conn.transaction(|| {
+            let worker_id = diesel::insert_into(dsl::workers)
+                .values((
+                    dsl::name.eq(name),
+                    dsl::token_prefix.eq(secrets::prefix(&token)),
+                    dsl::token_hash.eq(secrets::digest(&token)),
+                ))
+                .returning(dsl::id)
+                .get_result::<i64>(conn)?;
+
+            for profile_machine_name in profile_machine_names {
+                let profile_id = Profile::id_for_machine_name(conn, profile_machine_name)?;
+
+                diesel::insert_into(worker_capabilities::dsl::worker_capabilities)
+                    .values((
+                        worker_capabilities::dsl::worker_id.eq(worker_id),
+                        worker_capabilities::dsl::profile_id.eq(profile_id),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+
+            Ok((worker_id, token.clone()))
+        })
+    }
+
+    /// Resolves a worker credential, recording the request as a sign of life.
+    pub fn by_token(conn: &PgConnection, token: &str) -> Result<Self, diesel::result::Error> {
+        use crate::schema::workers::dsl;
+
+        let worker = dsl::workers
+            .filter(dsl::token_prefix.eq(secrets::prefix(token)))
+            .get_results::<Self>(conn)?
+            .into_iter()
+            .find(|candidate| match candidate.token_hash {
+                Some(ref hash) => secrets::verify(token, hash),
+                None => false,
+            })
+            .ok_or(diesel::result::Error::NotFound)?;
+
+        diesel::update(&worker)
+            .set(dsl::last_active.eq(Utc::now()))
+            .get_result::<Self>(conn)
+    }
+
+    /// Resolves a worker credential like `by_token`, along with the tasks it
+    /// was given that have been cancelled and not yet reported to it.
+    pub fn heartbeat(
+        conn: &PgConnection,
+        token: &str,
+    ) -> Result<(Self, Vec<i64>), diesel::result::Error> {
+        conn.transaction(|| {
+            let worker = Self::by_token(conn, token)?;
+            let cancelled = Task::take_cancelled_for_worker(conn, worker.id)?;
+
+            Ok((worker, cancelled))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TASK_STATUSES: [TaskStatus; 7] = [
+        TaskStatus::New,
+        TaskStatus::Queued,
+        TaskStatus::Running,
+        TaskStatus::Succeeded,
+        TaskStatus::Failed,
+        TaskStatus::Cancelled,
+        TaskStatus::TimedOut,
+    ];
+
+    #[test]
+    fn task_transition_table() {
+        use TaskStatus::*;
+
+        let allowed = [
+            (New, Queued),
+            (New, Running),
+            (New, Cancelled),
+            (New, Failed),
+            (Queued, New),
+            (Queued, Running),
+            (Queued, Cancelled),
+            (Queued, Failed),
+            (Running, New),
+            (Running, Succeeded),
+            (Running, Failed),
+            (Running, Cancelled),
+            (Running, TimedOut),
+        ];
+
+        for &from in &TASK_STATUSES {
+            for &to in &TASK_STATUSES {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    allowed.contains(&(from, to)),
+                    "{} to {}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn finished_tasks_never_move() {
+        for &from in &[
+            TaskStatus::Succeeded,
+            TaskStatus::Failed,
+            TaskStatus::Cancelled,
+            TaskStatus::TimedOut,
+        ] {
+            assert!(TASK_STATUSES.iter().all(|&to| !from.can_transition_to(to)));
+        }
+    }
+
+    #[test]
+    fn task_status_names_round_trip() {
+        for &status in &TASK_STATUSES {
+            let name = serde_json::to_value(status).unwrap();
+
+            assert_eq!(name, status.as_str());
+            assert_eq!(serde_json::from_value::<TaskStatus>(name).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn stale_tasks_fail_on_their_last_attempt() {
+        assert_eq!(Task::stale_status(0, 3), TaskStatus::New);
+        assert_eq!(Task::stale_status(1, 3), TaskStatus::New);
+        assert_eq!(Task::stale_status(2, 3), TaskStatus::Failed);
+        assert_eq!(Task::stale_status(5, 3), TaskStatus::Failed);
+        assert_eq!(Task::stale_status(0, 1), TaskStatus::Failed);
+
+        // Whatever the reaper decides is a move a running task may make.
+        for attempts in 0..5 {
+            assert!(TaskStatus::Running.can_transition_to(Task::stale_status(attempts, 3)));
+        }
+    }
+
+    fn api_key(allowed_ips: &[&str]) -> ApiKey {
+        ApiKey {
+            id: 1,
+            user_id: 1,
+            name: "test".into(),
+            key_prefix: String::new(),
+            key_hash: String::new(),
+            scopes: vec![],
+            allowed_ips: allowed_ips.iter().map(|range| range.to_string()).collect(),
+            expires_when: None,
+            created_when: Utc::now(),
+            last_used_when: None,
+        }
+    }
+
+    #[test]
+    fn valid_ip_ranges() {
+        for range in &[
+            "10.0.0.1",
+            "10.0.0.0/8",
+            "0.0.0.0/0",
+            "192.168.1.1/32",
+            "::1",
+            "2001:db8::/32",
+            "::/0",
+            "2001:db8::1/128",
+        ] {
+            assert!(ApiKey::is_valid_ip_range(range), "{}", range);
+        }
+    }
+
+    #[test]
+    fn invalid_ip_ranges() {
+        for range in &[
+            "",
+            "/8",
+            "junk",
+            "10.0.0",
+            "10.0.0.256",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0.0 /8",
+            "2001:db8::/129",
+            "2001:db8::g/64",
+            "example.com",
+        ] {
+            assert!(!ApiKey::is_valid_ip_range(range), "{}", range);
+        }
+    }
+
+    #[test]
+    fn keys_without_ranges_allow_any_address() {
+        let key = api_key(&[]);
+
+        assert!(key.allows_ip(Some("203.0.113.7")));
+        assert!(key.allows_ip(Some("2001:db8::1")));
+        assert!(key.allows_ip(None));
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        let key = api_key(&["10.0.0.0/8", "192.168.1.1/32"]);
+
+        assert!(key.allows_ip(Some("10.0.0.1")));
+        assert!(key.allows_ip(Some("10.255.255.255")));
+        assert!(key.allows_ip(Some("192.168.1.1")));
+        assert!(!key.allows_ip(Some("11.0.0.1")));
+        assert!(!key.allows_ip(Some("192.168.1.2")));
+        assert!(!key.allows_ip(Some("::ffff:10.0.0.1")));
+
+        // The host bits of a range do not matter.
+        assert!(api_key(&["10.1.2.3/8"]).allows_ip(Some("10.9.9.9")));
+
+        let any = api_key(&["0.0.0.0/0"]);
+        assert!(any.allows_ip(Some("203.0.113.7")));
+        assert!(!any.allows_ip(Some("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        let key = api_key(&["2001:db8::/32", "::1"]);
+
+        assert!(key.allows_ip(Some("2001:db8::1")));
+        assert!(key.allows_ip(Some("2001:db8:ffff::1")));
+        assert!(key.allows_ip(Some("::1")));
+        assert!(!key.allows_ip(Some("2001:db9::1")));
+        assert!(!key.allows_ip(Some("::2")));
+        assert!(!key.allows_ip(Some("10.0.0.1")));
+
+        let any = api_key(&["::/0"]);
+        assert!(any.allows_ip(Some("2001:db8::1")));
+        assert!(!any.allows_ip(Some("10.0.0.1")));
+    }
+
+    #[test]
+    fn unusable_addresses_and_ranges_are_refused() {
+        let key = api_key(&["junk", "10.0.0.0/8"]);
+
+        // A bad range is skipped rather than matching everything.
+        assert!(key.allows_ip(Some("10.0.0.1")));
+        assert!(!key.allows_ip(Some("11.0.0.1")));
+        assert!(!api_key(&["junk"]).allows_ip(Some("10.0.0.1")));
+
+        // Without a readable client address nothing matches.
+        assert!(!key.allows_ip(None));
+        assert!(!key.allows_ip(Some("")));
+        assert!(!key.allows_ip(Some("10.0.0.1:8080")));
+        assert!(!key.allows_ip(Some("unknown")));
+    }
+
+    #[test]
+    fn api_key_prefix_skips_marker() {
+        let key = format!("{}{}", ApiKey::MARKER, "abcdefghijklmnop");
+
+        assert_eq!(ApiKey::prefix(&key), secrets::prefix("abcdefghijklmnop"));
+        assert_ne!(ApiKey::prefix(&key), secrets::prefix(&key));
+    }
+
+    fn login_policy() -> LoginPolicy {
+        LoginPolicy {
+            free_attempts: 5,
+            free_attempts_per_ip: 20,
+            window: chrono::Duration::hours(1),
+            max_lockout: chrono::Duration::minutes(15),
+        }
+    }
+
+    #[test]
+    fn lockout_doubles_after_free_attempts() {
+        let policy = login_policy();
+        let seconds = |failures| {
+            policy
+                .lockout(failures, 5)
+                .map(|lockout| lockout.num_seconds())
+        };
+
+        assert_eq!(seconds(0), None);
+        assert_eq!(seconds(5), None);
+        assert_eq!(seconds(6), Some(1));
+        assert_eq!(seconds(7), Some(2));
+        assert_eq!(seconds(8), Some(4));
+        assert_eq!(seconds(15), Some(512));
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        let policy = login_policy();
+
+        assert_eq!(policy.lockout(16, 5), Some(policy.max_lockout));
+        assert_eq!(policy.lockout(36, 5), Some(policy.max_lockout));
+        // Far past where the doubling would overflow.
+        assert_eq!(policy.lockout(10_000, 5), Some(policy.max_lockout));
+        assert_eq!(
+            policy.lockout(i64::max_value(), 5),
+            Some(policy.max_lockout)
+        );
+    }
+
+    #[test]
+    fn locked_until_last_failure_plus_lockout() {
+        let policy = login_policy();
+        let now = Utc::now();
+        let last_failure = now - chrono::Duration::milliseconds(500);
+
+        assert_eq!(
+            policy.locked_until((6, Some(last_failure)), None, now),
+            Some(last_failure + chrono::Duration::seconds(1))
+        );
+        assert_eq!(
+            policy.locked_until((8, Some(last_failure)), None, now),
+            Some(last_failure + chrono::Duration::seconds(4))
+        );
+    }
+
+    #[test]
+    fn locked_until_is_none_below_threshold_or_once_over() {
+        let policy = login_policy();
+        let now = Utc::now();
+
+        assert_eq!(policy.locked_until((0, None), None, now), None);
+        assert_eq!(policy.locked_until((5, Some(now)), None, now), None);
+        assert_eq!(
+            policy.locked_until((6, Some(now - chrono::Duration::seconds(1))), None, now),
+            None
+        );
+        assert_eq!(
+            policy.locked_until((6, Some(now - chrono::Duration::seconds(2))), None, now),
+            None
+        );
+    }
+
+    #[test]
+    fn addresses_have_their_own_threshold() {
+        let policy = login_policy();
+        let now = Utc::now();
+
+        // Ten failures lock the account, but not the address.
+        assert_eq!(
+            policy.locked_until((10, Some(now)), Some((10, Some(now))), now),
+            Some(now + chrono::Duration::seconds(16))
+        );
+        assert_eq!(
+            policy.locked_until((0, None), Some((10, Some(now))), now),
+            None
+        );
+        assert_eq!(
+            policy.locked_until((0, None), Some((20, Some(now))), now),
+            None
+        );
+        assert_eq!(
+            policy.locked_until((0, None), Some((21, Some(now))), now),
+            Some(now + chrono::Duration::seconds(1))
+        );
+    }
+
+    #[test]
+    fn longer_of_account_and_address_lockout_wins() {
+        let policy = login_policy();
+        let now = Utc::now();
+
+        assert_eq!(
+            policy.locked_until((8, Some(now)), Some((22, Some(now))), now),
+            Some(now + chrono::Duration::seconds(4))
+        );
+        assert_eq!(
+            policy.locked_until((6, Some(now)), Some((24, Some(now))), now),
+            Some(now + chrono::Duration::seconds(8))
+        );
     }
 }
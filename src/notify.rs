@@ -0,0 +1,247 @@
+use std::{
+    env,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use lettre::{
+    smtp::authentication::Credentials, ClientSecurity, ClientTlsParameters, SmtpClient, Transport,
+};
+use lettre_email::EmailBuilder;
+use log::{error, info};
+use native_tls::TlsConnector;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Smtp(err: lettre::smtp::error::Error) {
+            from()
+            cause(err)
+            display("could not send mail: {}", err)
+        }
+        Message(err: lettre_email::error::Error) {
+            from()
+            cause(err)
+            display("could not build mail: {}", err)
+        }
+        Tls(err: native_tls::Error) {
+            from()
+            cause(err)
+            display("could not set up tls for mail: {}", err)
+        }
+    }
+}
+
+/// Delivers messages to users out of band.
+pub trait Notifier: Send + Sync {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), Error>;
+}
+
+pub type Notify = Arc<dyn Notifier>;
+
+/// Builds the notifier selected by `NOTIFIER` (`log` or `smtp`), behind a
+/// queue.
+///
+/// `SMTP_SECURITY` is `required` (STARTTLS, the default), `opportunistic`
+/// (STARTTLS when offered), `tls` (TLS from the start, port 465 by default)
+/// or `none`.
+pub fn from_env() -> Notify {
+    let notifier: Box<dyn Notifier> = match env::var("NOTIFIER").as_ref().map(String::as_str) {
+        Ok("smtp") => {
+            let security = match env::var("SMTP_SECURITY").as_ref().map(String::as_str) {
+                Ok("required") | Err(_) => Security::Required,
+                Ok("opportunistic") => Security::Opportunistic,
+                Ok("tls") => Security::Tls,
+                Ok("none") => Security::None,
+                Ok(other) => panic!("unknown smtp security: {}", other),
+            };
+
+            Box::new(SmtpNotifier {
+                host: env::var("SMTP_HOST").expect("incomplete smtp configuration"),
+                port: env::var("SMTP_PORT")
+                    .ok()
+                    .and_then(|port| port.parse().ok())
+                    .unwrap_or(if security == Security::Tls { 465 } else { 25 }),
+                security,
+                credentials: match (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+                    (Ok(username), Ok(password)) => Some((username, password)),
+                    _ => None,
+                },
+                from: env::var("SMTP_FROM").expect("incomplete smtp configuration"),
+            })
+        }
+        Ok("log") | Err(_) => Box::new(LogNotifier),
+        Ok(other) => panic!("unknown notifier: {}", other),
+    };
+
+    Arc::new(QueuedNotifier::spawn(notifier))
+}
+
+struct Message {
+    to: String,
+    subject: String,
+    body: String,
+}
+
+/// Hands messages to a background thread that delivers them with another
+/// notifier, so that requests neither wait for the mail server nor take
+/// longer when they send something. Delivery failures are only logged.
+pub struct QueuedNotifier {
+    sender: Mutex<mpsc::Sender<Message>>,
+}
+
+impl QueuedNotifier {
+    pub fn spawn(notifier: Box<dyn Notifier>) -> Self {
+        let (sender, receiver) = mpsc::channel::<Message>();
+
+        thread::spawn(move || {
+            for message in receiver {
+                if let Err(e) = notifier.send(&message.to, &message.subject, &message.body) {
+                    error!("could not deliver message to {}: {}", message.to, e);
+                }
+            }
+        });
+
+        QueuedNotifier {
+            sender: Mutex::new(sender),
+        }
+    }
+}
+
+impl Notifier for QueuedNotifier {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
+        let message = Message {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+        };
+
+        if self.sender.lock().unwrap().send(message).is_err() {
+            error!("notifier has stopped, a message to {} was lost", to);
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes messages to the log instead of sending them, for development.
+/// Anything secret in them ends up in the log too.
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
+        info!("message for {}: {}\n{}", to, subject, body);
+
+        Ok(())
+    }
+}
+
+/// How the connection to the SMTP relay is protected.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Security {
+    /// Cleartext, for a relay that is local or on a trusted network.
+    None,
+    /// STARTTLS if the relay offers it, cleartext otherwise.
+    Opportunistic,
+    /// STARTTLS, refusing to send without it.
+    Required,
+    /// TLS from the start of the connection.
+    Tls,
+}
+
+/// Sends plain-text mail through an SMTP relay.
+pub struct SmtpNotifier {
+    host: String,
+    port: u16,
+    security: Security,
+    credentials: Option<(String, String)>,
+    from: String,
+}
+
+impl SmtpNotifier {
+    fn client_security(&self) -> Result<ClientSecurity, Error> {
+        let tls = || -> Result<_, Error> {
+            Ok(ClientTlsParameters::new(
+                self.host.clone(),
+                TlsConnector::new()?,
+            ))
+        };
+
+        Ok(match self.security {
+            Security::None => ClientSecurity::None,
+            Security::Opportunistic => ClientSecurity::Opportunistic(tls()?),
+            Security::Required => ClientSecurity::Required(tls()?),
+            Security::Tls => ClientSecurity::Wrapper(tls()?),
+        })
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
+        let email = EmailBuilder::new()
+            .to(to)
+            .from(self.from.as_str())
+            .subject(subject)
+            .text(body)
+            .build()?;
+
+        let mut client = SmtpClient::new((self.host.as_str(), self.port), self.client_security()?)?;
+
+        if let Some((ref username, ref password)) = self.credentials {
+            client = client.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        client.transport().send(email.into())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends a message to a local SMTP sink and reads it back from the
+    /// sink's API. Run with `cargo test -- --ignored` against MailHog, for
+    /// example `docker run -p 1025:1025 -p 8025:8025 mailhog/mailhog`.
+    #[test]
+    #[ignore]
+    fn smtp_send_against_mailhog() {
+        let notifier = SmtpNotifier {
+            host: env::var("SMTP_TEST_HOST").unwrap_or_else(|_| "127.0.0.1".into()),
+            port: env::var("SMTP_TEST_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(1025),
+            security: Security::None,
+            credentials: None,
+            from: "web-api@example.org".into(),
+        };
+        let api = env::var("MAILHOG_TEST_API").unwrap_or_else(|_| "http://127.0.0.1:8025".into());
+
+        let to = format!("{}@example.org", uuid::Uuid::new_v4().to_simple());
+        notifier
+            .send(&to, "Test message", "Sent by the notifier test.")
+            .unwrap();
+
+        let found: serde_json::Value = reqwest::Client::new()
+            .get(&format!("{}/api/v2/search", api))
+            .query(&[("kind", "to"), ("query", to.as_str())])
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+
+        assert_eq!(found["total"], 1);
+        let content = &found["items"][0]["Content"];
+        assert_eq!(content["Headers"]["Subject"][0], "Test message");
+        assert!(content["Headers"]["From"][0]
+            .as_str()
+            .unwrap()
+            .contains("web-api@example.org"));
+        assert!(content["Body"]
+            .as_str()
+            .unwrap()
+            .contains("Sent by the notifier test."));
+    }
+}
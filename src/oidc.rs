@@ -0,0 +1,649 @@
+use std::{collections::HashMap, env, sync::Arc};
+
+use chrono::prelude::*;
+use ring::signature::{RsaPublicKeyComponents, RSA_PKCS1_2048_8192_SHA256};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::models::Role;
+use crate::secrets;
+
+/// How far the provider's clock may run ahead of ours, in seconds.
+const CLOCK_SKEW: i64 = 60;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Http(err: reqwest::Error) {
+            from()
+            cause(err)
+            display("identity provider request failed: {}", err)
+        }
+        Url(err: reqwest::UrlError) {
+            from()
+            cause(err)
+            display("identity provider endpoint is not a valid url: {}", err)
+        }
+        Status(code: u16) {
+            display("identity provider answered with status {}", code)
+        }
+        InvalidToken(reason: &'static str) {
+            display("id token rejected: {}", reason)
+        }
+    }
+}
+
+/// The OpenID Connect provider users can sign in with, if one is set up.
+pub type Oidc = Option<Arc<Provider>>;
+
+/// Reads the provider from `OIDC_ISSUER`, `OIDC_CLIENT_ID`,
+/// `OIDC_CLIENT_SECRET`, `OIDC_REDIRECT_URI`, `OIDC_SCOPES`,
+/// `OIDC_GROUPS_CLAIM` and `OIDC_ROLE_MAPPING`. Single sign-on is off unless
+/// `OIDC_ISSUER` is set.
+///
+/// `OIDC_ROLE_MAPPING` is a comma-separated list of `group=role` pairs. The
+/// first pair whose group the user is in decides their role.
+///
+/// Endpoints are discovered from the issuer on every login, so a local mock
+/// issuer on plain HTTP works as well as a real one.
+pub fn from_env() -> Oidc {
+    let issuer = env::var("OIDC_ISSUER").ok()?;
+
+    let role_mapping = env::var("OIDC_ROLE_MAPPING")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let group = parts.next().unwrap().trim().to_string();
+            let role = parts
+                .next()
+                .and_then(|role| Role::from_name(role.trim()))
+                .unwrap_or_else(|| panic!("invalid oidc role mapping: {}", pair));
+
+            (group, role)
+        })
+        .collect();
+
+    Some(Arc::new(Provider {
+        issuer,
+        client_id: env::var("OIDC_CLIENT_ID").expect("incomplete oidc configuration"),
+        client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+        redirect_uri: env::var("OIDC_REDIRECT_URI").expect("incomplete oidc configuration"),
+        scopes: env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid email profile".into()),
+        groups_claim: env::var("OIDC_GROUPS_CLAIM").unwrap_or_else(|_| "groups".into()),
+        role_mapping,
+        client: reqwest::Client::new(),
+    }))
+}
+
+fn decode_base64url(value: &str) -> Result<Vec<u8>, Error> {
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| Error::InvalidToken("malformed"))
+}
+
+pub struct Provider {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    scopes: String,
+    groups_claim: String,
+    role_mapping: Vec<(String, Role)>,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    preferred_username: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+/// Where to send the user to sign in, and what to remember until they come
+/// back.
+pub struct Authorization {
+    pub url: String,
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+/// Who the provider says signed in.
+pub struct Identity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+    /// The role their groups map to, if any of them do.
+    pub role: Option<Role>,
+}
+
+impl Provider {
+    fn get<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, Error> {
+        let mut response = self.client.get(url).send()?;
+
+        if !response.status().is_success() {
+            return Err(Error::Status(response.status().as_u16()));
+        }
+
+        Ok(response.json()?)
+    }
+
+    fn discover(&self) -> Result<Discovery, Error> {
+        let discovery: Discovery = self.get(&format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer.trim_end_matches('/')
+        ))?;
+
+        if discovery.issuer != self.issuer {
+            return Err(Error::InvalidToken(
+                "discovery document is for another issuer",
+            ));
+        }
+
+        Ok(discovery)
+    }
+
+    /// Starts an authorization code flow with PKCE.
+    pub fn authorize(&self) -> Result<Authorization, Error> {
+        let discovery = self.discover()?;
+
+        let state = secrets::generate();
+        let nonce = secrets::generate();
+        let code_verifier = secrets::generate();
+        let code_challenge = base64::encode_config(
+            &Sha256::digest(code_verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+
+        let url = reqwest::Url::parse_with_params(
+            &discovery.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.client_id.as_str()),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("scope", self.scopes.as_str()),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(Authorization {
+            url: url.into_string(),
+            state,
+            nonce,
+            code_verifier,
+        })
+    }
+
+    /// Trades the code the provider sent the user back with for their
+    /// identity, checking the ID token it comes with.
+    pub fn exchange(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<Identity, Error> {
+        let discovery = self.discover()?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.redirect_uri.as_str()),
+            ("client_id", self.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(ref client_secret) = self.client_secret {
+            form.push(("client_secret", client_secret.as_str()));
+        }
+
+        let mut response = self
+            .client
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()?;
+
+        if !response.status().is_success() {
+            return Err(Error::Status(response.status().as_u16()));
+        }
+
+        let tokens: TokenResponse = response.json()?;
+        let claims = self.verify(&discovery, &tokens.id_token, nonce)?;
+
+        let groups: Vec<&str> = claims
+            .other
+            .get(&self.groups_claim)
+            .and_then(serde_json::Value::as_array)
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter_map(serde_json::Value::as_str)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let role = self
+            .role_mapping
+            .iter()
+            .find(|(group, _)| groups.contains(&group.as_str()))
+            .map(|(_, role)| *role);
+
+        Ok(Identity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            preferred_username: claims.preferred_username,
+            role,
+        })
+    }
+
+    /// Checks an RS256 ID token's signature against the provider's published
+    /// keys, then its issuer, audience, expiry and nonce.
+    fn verify(&self, discovery: &Discovery, id_token: &str, nonce: &str) -> Result<Claims, Error> {
+        let jwks: Jwks = self.get(&discovery.jwks_uri)?;
+
+        self.verify_with(&jwks, id_token, nonce)
+    }
+
+    /// Does the checks of `verify` against keys that were already fetched.
+    fn verify_with(&self, jwks: &Jwks, id_token: &str, nonce: &str) -> Result<Claims, Error> {
+        let parts: Vec<&str> = id_token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(Error::InvalidToken("malformed"));
+        }
+
+        let header: Header = serde_json::from_slice(&decode_base64url(parts[0])?)
+            .map_err(|_| Error::InvalidToken("malformed"))?;
+        if header.alg != "RS256" {
+            return Err(Error::InvalidToken("unsupported signing algorithm"));
+        }
+
+        let key = jwks
+            .keys
+            .iter()
+            .filter(|key| key.kty == "RSA")
+            .find(|key| header.kid.is_none() || key.kid == header.kid)
+            .ok_or(Error::InvalidToken("unknown signing key"))?;
+
+        let (n, e) = match (&key.n, &key.e) {
+            (Some(n), Some(e)) => (decode_base64url(n)?, decode_base64url(e)?),
+            _ => return Err(Error::InvalidToken("unknown signing key")),
+        };
+
+        RsaPublicKeyComponents { n, e }
+            .verify(
+                &RSA_PKCS1_2048_8192_SHA256,
+                format!("{}.{}", parts[0], parts[1]).as_bytes(),
+                &decode_base64url(parts[2])?,
+            )
+            .map_err(|_| Error::InvalidToken("bad signature"))?;
+
+        let claims: Claims = serde_json::from_slice(&decode_base64url(parts[1])?)
+            .map_err(|_| Error::InvalidToken("malformed"))?;
+
+        let is_audience = match claims.aud {
+            Audience::One(ref audience) => *audience == self.client_id,
+            Audience::Many(ref audiences) => audiences.contains(&self.client_id),
+        };
+
+        if claims.iss != self.issuer {
+            Err(Error::InvalidToken("wrong issuer"))
+        } else if !is_audience {
+            Err(Error::InvalidToken("wrong audience"))
+        } else if claims.exp + CLOCK_SKEW < Utc::now().timestamp() {
+            Err(Error::InvalidToken("expired"))
+        } else if !claims.nonce.as_ref().map_or(false, |claimed| {
+            secrets::matches(claimed.as_bytes(), nonce.as_bytes())
+        }) {
+            Err(Error::InvalidToken("wrong nonce"))
+        } else {
+            Ok(claims)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{rand::SystemRandom, signature::RsaKeyPair};
+    use serde_json::json;
+
+    use super::*;
+
+    const ISSUER: &str = "https://issuer.example";
+    const CLIENT_ID: &str = "web-api";
+    const NONCE: &str = "the nonce";
+
+    /// A 2048-bit RSA key as PKCS#8, only ever used to sign test tokens.
+    const SIGNING_KEY: &str = concat!(
+        "MIIEvQIBADANBgkqhkiG9w0BAQEFAASCBKcwggSjAgEAAoIBAQDBAGSYaOD63imU",
+        "YjQIW2kcHjEk+S2Clh9ERsp8nEzhJNG2rwh1GSplTJsXzo+DhfRDVlcMjJU1D9Pa",
+        "P4BNpfW8HH9GyG/KgurgmLt2I7VPG8jfbjcaCbYxvLjVbGpoZSrMHqAhN665CqX8",
+        "sbXprfe2hTx6EjWJYV9YSuq6a2woCNe4CzcpG6As7PlzUXXj44JuokncLM19EfbJ",
+        "Fm/0dk6eOZT6pd/X6Vn5Rr8aT523zBonHuHduM3fGRfVCFPPnkdr4sdqSkwJ3u8X",
+        "rczc1s9KprFI271M2onn3DRvr9PoprwMIAZbSGeCrWkrbnMJHKNbNg65senMCXjM",
+        "kMPM6gnDAgMBAAECggEAQfafaxPVaQiLT4Ks4/pSF2EeTNO5QTuL3isPw70r7XtH",
+        "DdgO3O7H9a1UCLP5ius6i9UzlT9rM6KANsSjVmaVcUCQI6IXyzAplK++ymbz7eDC",
+        "K9MglA6jraYhyhfu2lVRZkhFVHUSi33fM1mNohtqYYByJgqZL6YPDz8zsJlMcvXS",
+        "oiUWOeJo6ejkx83b07gZRDJGYtIMn+V0R+39tEYa7LPUxLvZWopsRI78DWsVxDYK",
+        "TvQf5PfjsRabUrZ0inqq3MW8OxAYrCovKJ0d1Riugzngfa/e/dnhENeFyHlwQx4O",
+        "mfscbhDo0u7GlHJiUNjqWS+gWf2uvOhC7Gv3gfkWwQKBgQDoxCbL2qUzvjcRGqQC",
+        "6pBnycpG9LYTtoUYeT4mntIqHDB1DTtZn8ZBGfskAEjsIirHN+iA6pe/6FvflUiI",
+        "9kV5JN0O4XQ9BCTLwFu+ALJCWyyEJb6njinTXTbm+oyNdQQ7Fa6qET357hyq+eKp",
+        "yxn4rwNyDpCiUL/Q/nQJnhPR4wKBgQDURCOYSqwbt3V5VtSEl0lsfuGyUQ1VQedI",
+        "JvirUw91nGTWdG6mMYLCwRu2BiNSSRGni0KZH2KO+b3X938/3FbEeklP+N8oxOQ9",
+        "NRlF9QScV8k5G7/IFmMZXz4l4PIfKeEXtjnhCTYK7SJJVCF/lENXdNL3oB+XP/WY",
+        "tf+D7gbuoQKBgGI5pEWSVvSm6IYHP/8bDSU/OmUHD27yLuhNAbU6gGXUz34Vv13q",
+        "1rbSR/DBTgSXxoWbSjpcxPzvCRYo4RsrEdY1gDOvYGX/QafZ3N0pXhAyeW6qnvIG",
+        "RBA13d1LDdUD9G0akUKjVAwMN3zPRp9wXd1bvZB8JPzaJpgDSORmGOcRAoGBAMnR",
+        "S2KVxNPJd4j5tK+oQ+hDLwXiqGGiemsQmqgSHgW9v+Zi531eX6wZ/OXxKg4naQC6",
+        "mISL4aJ88zJun+8w6NKATkoaHttdcIB9ht0L2+lmUezZZFuyZWCPPMC5Apv8roZj",
+        "/N3oaxiyZEICGqN5bRmu+pL0DHU7G6Cpkhp26uJhAoGAZ9UDfS+lyn1dczNiOd8X",
+        "R0gtVA3MkUclhpUpxGyCx0FIQ3BHH5slMDLdqOcw2sBekW+DowiV3aTb4JJx9gra",
+        "9tL9lEtuvytp07dIOKRSJR21zZzgLIxXEqjAcaiFNTVMWt5Kvebn/CnxbzuBBFR9",
+        "J9ixH5x5P1vgLUqGvjuwpDA="
+    );
+
+    /// The modulus of `SIGNING_KEY`.
+    const SIGNING_KEY_N: &str = concat!(
+        "wQBkmGjg-t4plGI0CFtpHB4xJPktgpYfREbKfJxM4STRtq8IdRkqZUybF86Pg4X0",
+        "Q1ZXDIyVNQ_T2j-ATaX1vBx_RshvyoLq4Ji7diO1TxvI3243Ggm2Mby41WxqaGUq",
+        "zB6gITeuuQql_LG16a33toU8ehI1iWFfWErqumtsKAjXuAs3KRugLOz5c1F14-OC",
+        "bqJJ3CzNfRH2yRZv9HZOnjmU-qXf1-lZ-Ua_Gk-dt8waJx7h3bjN3xkX1QhTz55H",
+        "a-LHakpMCd7vF63M3NbPSqaxSNu9TNqJ59w0b6_T6Ka8DCAGW0hngq1pK25zCRyj",
+        "WzYOubHpzAl4zJDDzOoJww"
+    );
+
+    /// The modulus of another key, which signs nothing.
+    const OTHER_KEY_N: &str = concat!(
+        "vigWVjiCiCaSrzBd2X9WZAatphKbdJmnjoCbv4AQuNmVxUnwMwyhteUnhf1D-eoG",
+        "XiaakmOe_cVDII1IQj7B5XpXhJOZIY4P4J50A1JyMq5Yy4jlorJtyIMON6ijijVH",
+        "rey7iTrnqkmTXkIhT4hLH0IAQ4q-_rJio-2u9kwkHeaY7hOPeXViryKphBfD5B21",
+        "FrSmQODXucnVteCTirQBoyedoUkHu6HAORyKPHFG08o-3ifp8oiwlNcXUVJezf-d",
+        "WmMcjJvvs6XBnAMQji3nIPM-iwsq1WgeQJkZa3WdFKvY9aKM9pYRX3stxz9xJkcS",
+        "smdqtFdGQ0eoPNr1HHMG4Q"
+    );
+
+    fn provider() -> Provider {
+        Provider {
+            issuer: ISSUER.into(),
+            client_id: CLIENT_ID.into(),
+            client_secret: None,
+            redirect_uri: "https://app.example/callback".into(),
+            scopes: "openid".into(),
+            groups_claim: "groups".into(),
+            role_mapping: vec![],
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// The provider's keys, the one that signs listed after one that does
+    /// not.
+    fn jwks() -> Jwks {
+        serde_json::from_value(json!({
+            "keys": [
+                { "kty": "RSA", "kid": "other", "n": OTHER_KEY_N, "e": "AQAB" },
+                { "kty": "RSA", "kid": "signing", "n": SIGNING_KEY_N, "e": "AQAB" },
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn claims() -> serde_json::Value {
+        json!({
+            "iss": ISSUER,
+            "sub": "subject",
+            "aud": CLIENT_ID,
+            "exp": Utc::now().timestamp() + 300,
+            "nonce": NONCE,
+        })
+    }
+
+    fn encode(value: &serde_json::Value) -> String {
+        base64::encode_config(&serde_json::to_vec(value).unwrap(), base64::URL_SAFE_NO_PAD)
+    }
+
+    fn sign(header: serde_json::Value, claims: serde_json::Value) -> String {
+        let key = RsaKeyPair::from_pkcs8(&base64::decode(SIGNING_KEY).unwrap()).unwrap();
+        let message = format!("{}.{}", encode(&header), encode(&claims));
+
+        let mut signature = vec![0; key.public_modulus_len()];
+        key.sign(
+            &ring::signature::RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut signature,
+        )
+        .unwrap();
+
+        format!(
+            "{}.{}",
+            message,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn token(claims: serde_json::Value) -> String {
+        sign(json!({ "alg": "RS256", "kid": "signing" }), claims)
+    }
+
+    fn rejection(id_token: &str) -> &'static str {
+        match provider().verify_with(&jwks(), id_token, NONCE) {
+            Err(Error::InvalidToken(reason)) => reason,
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("token accepted"),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let claims = provider()
+            .verify_with(&jwks(), &token(claims()), NONCE)
+            .unwrap();
+
+        assert_eq!(claims.sub, "subject");
+    }
+
+    #[test]
+    fn accepts_audience_list() {
+        let mut claims = claims();
+        claims["aud"] = json!(["someone-else", CLIENT_ID]);
+
+        assert!(provider()
+            .verify_with(&jwks(), &token(claims), NONCE)
+            .is_ok());
+    }
+
+    #[test]
+    fn accepts_token_without_kid() {
+        // Without a kid the first RSA key is tried, which is the wrong one.
+        let id_token = sign(json!({ "alg": "RS256" }), claims());
+        assert_eq!(rejection(&id_token), "bad signature");
+
+        let jwks: Jwks = serde_json::from_value(json!({
+            "keys": [{ "kty": "RSA", "n": SIGNING_KEY_N, "e": "AQAB" }]
+        }))
+        .unwrap();
+        assert!(provider().verify_with(&jwks, &id_token, NONCE).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let id_token = token(claims());
+        let (message, _) = id_token.split_at(id_token.rfind('.').unwrap());
+        let forged = format!("{}.{}", message, encode(&json!("not a signature")));
+        assert_eq!(rejection(&forged), "bad signature");
+
+        // Claims swapped after signing.
+        let mut parts: Vec<String> = id_token.split('.').map(String::from).collect();
+        let mut claims = claims();
+        claims["sub"] = json!("someone else");
+        parts[1] = encode(&claims);
+        assert_eq!(rejection(&parts.join(".")), "bad signature");
+    }
+
+    #[test]
+    fn rejects_key_selection() {
+        let other = sign(json!({ "alg": "RS256", "kid": "other" }), claims());
+        assert_eq!(rejection(&other), "bad signature");
+
+        let unknown = sign(json!({ "alg": "RS256", "kid": "unknown" }), claims());
+        assert_eq!(rejection(&unknown), "unknown signing key");
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let id_token = sign(json!({ "alg": "none", "kid": "signing" }), claims());
+        assert_eq!(rejection(&id_token), "unsupported signing algorithm");
+
+        let id_token = sign(json!({ "alg": "HS256", "kid": "signing" }), claims());
+        assert_eq!(rejection(&id_token), "unsupported signing algorithm");
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(rejection("not a token"), "malformed");
+        assert_eq!(rejection("a.b.c.d"), "malformed");
+        assert_eq!(rejection("!!.!!.!!"), "malformed");
+    }
+
+    #[test]
+    fn rejects_wrong_issuer() {
+        let mut claims = claims();
+        claims["iss"] = json!("https://other.example");
+
+        assert_eq!(rejection(&token(claims)), "wrong issuer");
+    }
+
+    #[test]
+    fn rejects_wrong_audience() {
+        let mut claims = claims();
+        claims["aud"] = json!("someone-else");
+        assert_eq!(rejection(&token(claims.clone())), "wrong audience");
+
+        claims["aud"] = json!(["someone-else"]);
+        assert_eq!(rejection(&token(claims)), "wrong audience");
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let mut claims = claims();
+        claims["exp"] = json!(Utc::now().timestamp() - CLOCK_SKEW - 1);
+        assert_eq!(rejection(&token(claims.clone())), "expired");
+
+        // Within the allowed skew.
+        claims["exp"] = json!(Utc::now().timestamp() - CLOCK_SKEW + 5);
+        assert!(provider()
+            .verify_with(&jwks(), &token(claims), NONCE)
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_wrong_nonce() {
+        let mut claims = claims();
+        claims["nonce"] = json!("another nonce");
+        assert_eq!(rejection(&token(claims.clone())), "wrong nonce");
+
+        claims.as_object_mut().unwrap().remove("nonce");
+        assert_eq!(rejection(&token(claims)), "wrong nonce");
+    }
+
+    /// Signs in through a mock issuer. Run with `cargo test -- --ignored`
+    /// against one, for example
+    /// `docker run -p 8080:8080 ghcr.io/navikt/mock-oauth2-server`, which
+    /// sends the user straight back with a code.
+    #[test]
+    #[ignore]
+    fn flow_against_mock_issuer() {
+        let issuer =
+            env::var("OIDC_TEST_ISSUER").unwrap_or_else(|_| "http://localhost:8080/default".into());
+        let provider = Provider {
+            issuer,
+            ..provider()
+        };
+
+        let authorization = provider.authorize().unwrap();
+
+        let response = reqwest::Client::builder()
+            .redirect(reqwest::RedirectPolicy::none())
+            .build()
+            .unwrap()
+            .get(&authorization.url)
+            .send()
+            .unwrap();
+        assert!(response.status().is_redirection());
+
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let callback = reqwest::Url::parse(location).unwrap();
+        let params: HashMap<_, _> = callback.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], authorization.state);
+
+        let identity = provider
+            .exchange(
+                &params["code"],
+                &authorization.code_verifier,
+                &authorization.nonce,
+            )
+            .unwrap();
+        assert!(!identity.subject.is_empty());
+
+        // Nor for a login that expected another nonce.
+        assert!(provider
+            .exchange(&params["code"], &authorization.code_verifier, "other nonce")
+            .is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use actix_web::{http::header, web, Error as AWError, HttpRequest, HttpResponse};
 use diesel::{
     r2d2::{ConnectionManager, Pool},
-    PgConnection,
+    Connection, PgConnection,
 };
 use futures::{
     future::{ok, Either},
@@ -9,6 +9,8 @@ use futures::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::audit;
+use crate::auth;
 use crate::models;
 
 #[derive(Serialize, Deserialize)]
@@ -22,29 +24,97 @@ pub fn list(
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
     if let Some(token) = req.headers().get(header::AUTHORIZATION) {
         let token = token.to_str().unwrap().to_string();
+        let client = auth::client(&req);
 
         Either::A(
-            web::block(move || {
-                models::Token::user_by_token(&db.get().unwrap(), &token)?;
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
 
-                Ok(db)
+                let user = models::User::by_credential(
+                    conn,
+                    &token,
+                    &client,
+                    models::Scope::ProfilesRead,
+                )?;
+                user.require(models::Permission::ReadProfiles)?;
+
+                Ok(models::Profile::list(conn)?)
             })
-            .map_err(
-                |_: actix_web::error::BlockingError<diesel::result::Error>| {
-                    actix_web::error::ErrorInternalServerError(String::new())
+            .and_then(|profiles| Ok(HttpResponse::Ok().json(ListResponse { profiles })))
+            .or_else(
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
                 },
-            )
-            .and_then(move |db| {
-                web::block(move || models::Profile::list(&db.get().unwrap()))
-                    .map_err(
-                        |_: actix_web::error::BlockingError<diesel::result::Error>| {
-                            actix_web::error::ErrorInternalServerError(String::new())
-                        },
-                    )
-                    .and_then(|profiles| HttpResponse::Ok().json(ListResponse { profiles }))
-                    .or_else(|_| HttpResponse::InternalServerError().finish())
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateRequest {
+    machine_name: String,
+    human_name: String,
+    module: String,
+    config: Option<serde_json::Value>,
+}
+
+pub fn create(
+    req: HttpRequest,
+    create: web::Json<CreateRequest>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+        let pending = audit::pending(&req);
+        pending.set_target(format!("profile:{}", create.machine_name));
+        let client = auth::client(&req);
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                let user = models::User::by_session(conn, &token, &client)?;
+                user.require(models::Permission::ManageProfiles)?;
+
+                conn.transaction(|| {
+                    let profile = models::Profile::create(
+                        conn,
+                        &create.machine_name,
+                        &create.human_name,
+                        &create.module,
+                        create.config.as_ref(),
+                    )?;
+                    pending.record(conn, audit::Outcome::Success)?;
+
+                    Ok(profile)
+                })
             })
-            .or_else(|_| HttpResponse::Unauthorized().finish()),
+            .and_then(|profile| Ok(HttpResponse::Ok().json(profile)))
+            .or_else(
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::DatabaseError(
+                            diesel::result::DatabaseErrorKind::UniqueViolation,
+                            _,
+                        ),
+                    )) => Ok(HttpResponse::Conflict().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
         )
     } else {
         Either::B(ok(HttpResponse::Unauthorized().finish()))
@@ -0,0 +1,48 @@
+use std::{env, thread, time::Duration};
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    Connection, PgConnection,
+};
+use log::{error, info};
+
+use crate::models;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Starts the background thread that recovers tasks left behind by workers
+/// that died mid-scan.
+pub fn spawn(pool: Pool<ConnectionManager<PgConnection>>) -> thread::JoinHandle<()> {
+    let interval = Duration::from_secs(env_or("TASK_REAPER_INTERVAL_SECONDS", 60));
+    let lease = chrono::Duration::seconds(env_or("TASK_LEASE_SECONDS", 300));
+    let max_attempts = env_or("TASK_MAX_ATTEMPTS", 3);
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("task reaper could not get a database connection: {}", e);
+                continue;
+            }
+        };
+
+        match models::Task::reap_stale(&conn, lease, max_attempts) {
+            Ok(0) => {}
+            Ok(requeued) => {
+                info!("task reaper requeued {} stale tasks", requeued);
+
+                if let Err(e) = conn.execute("NOTIFY tasks_created") {
+                    error!("task reaper could not notify workers: {}", e);
+                }
+            }
+            Err(e) => error!("task reaper failed: {}", e),
+        }
+    })
+}
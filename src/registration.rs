@@ -0,0 +1,160 @@
+use std::{
+    collections::HashSet,
+    env,
+    fs::File,
+    io::{BufRead, BufReader},
+    sync::Arc,
+};
+
+use log::info;
+use sha1::{Digest, Sha1};
+
+use crate::validation::FieldError;
+
+const USERNAME_MIN_LENGTH: usize = 3;
+const USERNAME_MAX_LENGTH: usize = 32;
+/// bcrypt ignores anything past this many bytes.
+const PASSWORD_MAX_LENGTH: usize = 72;
+
+/// Who may create an account, from `REGISTRATION_MODE`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Open,
+    Invite,
+    Closed,
+}
+
+/// Rules for new accounts and passwords.
+pub struct Policy {
+    pub mode: Mode,
+    pub min_password_length: usize,
+    /// Uppercase hex SHA-1 digests of passwords known from breaches.
+    breached: HashSet<String>,
+}
+
+pub type Registration = Arc<Policy>;
+
+/// Builds the policy from `REGISTRATION_MODE` (`open`, `invite` or
+/// `closed`), `PASSWORD_MIN_LENGTH` and `BREACHED_PASSWORDS_FILE`.
+///
+/// The breached password file holds one SHA-1 digest per line, optionally
+/// followed by `:` and a count, as in the Pwned Passwords downloads.
+pub fn from_env() -> Registration {
+    let mode = match env::var("REGISTRATION_MODE").as_ref().map(String::as_str) {
+        Ok("open") | Err(_) => Mode::Open,
+        Ok("invite") => Mode::Invite,
+        Ok("closed") => Mode::Closed,
+        Ok(other) => panic!("unknown registration mode: {}", other),
+    };
+
+    let min_password_length = env::var("PASSWORD_MIN_LENGTH")
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(8);
+
+    let breached = match env::var("BREACHED_PASSWORDS_FILE") {
+        Ok(path) => {
+            let file = File::open(&path).expect("could not open breached passwords file");
+            let breached: HashSet<_> = BufReader::new(file)
+                .lines()
+                .map(|line| line.expect("could not read breached passwords file"))
+                .filter_map(|line| {
+                    let digest = line.split(':').next().unwrap_or("").trim();
+
+                    if digest.len() == 40 {
+                        Some(digest.to_ascii_uppercase())
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+
+            info!("loaded {} breached password digests", breached.len());
+
+            breached
+        }
+        Err(_) => HashSet::new(),
+    };
+
+    Arc::new(Policy {
+        mode,
+        min_password_length,
+        breached,
+    })
+}
+
+impl Policy {
+    /// Usernames are 3 to 32 ASCII letters, digits, `.`, `-` or `_`, starting
+    /// with a letter or digit.
+    pub fn check_username(&self, username: &str) -> Option<FieldError> {
+        if username.len() < USERNAME_MIN_LENGTH || username.len() > USERNAME_MAX_LENGTH {
+            return Some(FieldError::new(
+                "username",
+                "length",
+                format!(
+                    "must be between {} and {} characters",
+                    USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+                ),
+            ));
+        }
+
+        let is_valid = username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+            && username.starts_with(|c: char| c.is_ascii_alphanumeric());
+
+        if is_valid {
+            None
+        } else {
+            Some(FieldError::new(
+                "username",
+                "characters",
+                "may only contain letters, digits, '.', '-' and '_', and must start with a letter or digit",
+            ))
+        }
+    }
+
+    /// Checks a new password, reported against `field`. When the account's
+    /// username is known, the password may not simply repeat it.
+    pub fn check_password(
+        &self,
+        field: &'static str,
+        password: &str,
+        username: Option<&str>,
+    ) -> Option<FieldError> {
+        if password.chars().count() < self.min_password_length {
+            Some(FieldError::new(
+                field,
+                "too_short",
+                format!("must be at least {} characters", self.min_password_length),
+            ))
+        } else if password.len() > PASSWORD_MAX_LENGTH {
+            Some(FieldError::new(
+                field,
+                "too_long",
+                format!("must be at most {} bytes", PASSWORD_MAX_LENGTH),
+            ))
+        } else if username.map_or(false, |username| password.eq_ignore_ascii_case(username)) {
+            Some(FieldError::new(
+                field,
+                "same_as_username",
+                "must not be the username",
+            ))
+        } else if self.is_breached(password) {
+            Some(FieldError::new(
+                field,
+                "breached",
+                "has appeared in a data breach and must not be used",
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn is_breached(&self, password: &str) -> bool {
+        !self.breached.is_empty()
+            && self
+                .breached
+                .contains(&hex::encode_upper(Sha1::digest(password.as_bytes())))
+    }
+}
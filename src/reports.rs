@@ -1,131 +1,335 @@
+use std::env;
+
 use actix_web::error::PayloadError;
 use actix_web::{http::header, web, Error as AWError, HttpRequest, HttpResponse};
-use bytes::BytesMut;
+use chrono::prelude::*;
 use diesel::{
     r2d2::{ConnectionManager, Pool},
     Connection, PgConnection, RunQueryDsl,
 };
 use futures::{
     future::{ok, Either},
-    Future, Stream,
+    Future,
 };
+use log::error;
 use serde::{Deserialize, Serialize};
 
+use crate::archive;
+use crate::auth;
 use crate::models;
+use crate::storage;
+use crate::validation;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    order: Option<Order>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    multihash: Option<String>,
+    profile: Option<String>,
+    verdict: Option<models::Verdict>,
+}
 
-#[derive(Serialize, Deserialize)]
+/// Cursors point just past the last report of a page, as
+/// `<created_when in microseconds>_<id>`.
+fn encode_cursor(report: &models::Report) -> String {
+    format!(
+        "{}_{}",
+        report.created_when.timestamp() * 1_000_000
+            + i64::from(report.created_when.timestamp_subsec_micros()),
+        report.id
+    )
+}
+
+fn decode_cursor(cursor: &str) -> Option<(DateTime<Utc>, i64)> {
+    let mut parts = cursor.splitn(2, '_');
+    let micros: i64 = parts.next()?.parse().ok()?;
+    let id: i64 = parts.next()?.parse().ok()?;
+
+    Utc.timestamp_opt(
+        micros.div_euclid(1_000_000),
+        (micros.rem_euclid(1_000_000) * 1_000) as u32,
+    )
+    .single()
+    .map(|created_when| (created_when, id))
+}
+
+#[derive(Serialize)]
+pub struct ReportSummary {
+    #[serde(flatten)]
+    report: models::Report,
+    verdict: Option<models::Verdict>,
+}
+
+#[derive(Serialize)]
 pub struct ListResponse {
-    reports: Vec<models::Report>,
+    reports: Vec<ReportSummary>,
+    next_cursor: Option<String>,
 }
 
 pub fn list(
     req: HttpRequest,
+    query: web::Query<ListQuery>,
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
-    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
-        let token = token.to_str().unwrap().to_string();
+    let client = auth::client(&req);
 
-        Either::A(
-            web::block(move || {
-                let user = models::Token::user_by_token(&db.get().unwrap(), &token)?;
+    let query = query.into_inner();
+    let after = match query.cursor {
+        Some(ref cursor) => decode_cursor(cursor).map(Some),
+        None => Some(None),
+    };
 
-                Ok((db, user))
-            })
-            .and_then(move |(db, user)| {
-                web::block(move || models::Report::list_for_user(&db.get().unwrap(), user.id))
-                    .map(|reports| HttpResponse::Ok().json(ListResponse { reports }))
-            })
-            .or_else(|_| HttpResponse::InternalServerError().finish()),
-        )
-    } else {
-        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    match (req.headers().get(header::AUTHORIZATION), after) {
+        (Some(token), Some(after)) => {
+            let token = token.to_str().unwrap().to_string();
+            let limit = query
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .max(1)
+                .min(MAX_PAGE_SIZE);
+            let filter = models::ReportFilter {
+                after,
+                // One more than asked for, to tell whether there is a next page.
+                limit: limit + 1,
+                ascending: match query.order {
+                    Some(Order::Asc) => true,
+                    Some(Order::Desc) | None => false,
+                },
+                from: query.from,
+                to: query.to,
+                multihash: query.multihash,
+                profile: query.profile,
+                verdict: query.verdict,
+            };
+
+            Either::A(
+                web::block(move || {
+                    let user = models::User::by_credential(
+                        &db.get().unwrap(),
+                        &token,
+                        &client,
+                        models::Scope::ReportsRead,
+                    )?;
+
+                    user.require(models::Permission::ReadReports)?;
+
+                    Ok((db, user))
+                })
+                .and_then(move |(db, user)| {
+                    web::block(move || -> Result<_, models::Error> {
+                        let conn = &db.get().unwrap();
+
+                        let mut reports = models::Report::page_for_user(conn, &user, &filter)?;
+
+                        let next_cursor = if reports.len() as i64 > limit {
+                            reports.truncate(limit as usize);
+                            reports.last().map(encode_cursor)
+                        } else {
+                            None
+                        };
+
+                        let report_ids: Vec<i64> = reports.iter().map(|r| r.id).collect();
+                        let verdicts = models::Task::verdicts_for_reports(conn, &report_ids)?;
+
+                        Ok(ListResponse {
+                            reports: reports
+                                .into_iter()
+                                .map(|report| ReportSummary {
+                                    verdict: verdicts.get(&report.id).cloned(),
+                                    report,
+                                })
+                                .collect(),
+                            next_cursor,
+                        })
+                    })
+                    .map(|response| HttpResponse::Ok().json(response))
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::Unauthorized().finish()),
+                        actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                            Ok(HttpResponse::Forbidden().finish())
+                        }
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                ),
+            )
+        }
+        (Some(_), None) => Either::B(ok(HttpResponse::BadRequest().finish())),
+        (None, _) => Either::B(ok(HttpResponse::Unauthorized().finish())),
     }
 }
 
 #[derive(Deserialize)]
 pub struct CreateQuery {
     profiles: String,
+    filename: Option<String>,
+    reuse_results: Option<bool>,
 }
 
-const MAX_SIZE: usize = 104_857_600;
+const MAX_SIZE: u64 = 104_857_600;
 
 #[derive(Serialize)]
 pub struct CreateResponse {
     report_id: i64,
 }
 
+/// Submits a sample to be scanned with the comma-separated `profiles`. Fails
+/// with `422 Unprocessable Entity` if a profile is unknown, before the sample
+/// is stored.
 pub fn create(
     req: HttpRequest,
     query: web::Query<CreateQuery>,
     payload: web::Payload,
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    store: web::Data<storage::Store>,
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
     if let Some(token) = req.headers().get(header::AUTHORIZATION) {
         let token = token.to_str().unwrap().to_string();
 
         Either::A(
             web::block(move || {
-                let user = models::Token::user_by_token(&db.get().unwrap(), &token)?;
+                let user = models::User::by_credential(
+                    &db.get().unwrap(),
+                    &token,
+                    &client,
+                    models::Scope::ReportsWrite,
+                )?;
+
+                user.require(models::Permission::SubmitReports)?;
 
                 Ok((db, user))
             })
             .and_then(|(db, user)| {
-                payload
-                    .fold(
-                        BytesMut::new(),
-                        |mut body, chunk| -> Result<_, PayloadError> {
-                            if body.len() + chunk.len() > MAX_SIZE {
-                                Err(PayloadError::Overflow)
-                            } else {
-                                body.extend_from_slice(&chunk);
-                                Ok(body)
-                            }
-                        },
-                    )
-                    .and_then(|body| {
-                        web::block(move || {
+                storage::spool(payload, MAX_SIZE)
+                    .and_then(|upload| {
+                        web::block(move || -> Result<_, models::Error> {
                             let conn = &db.get().unwrap();
 
-                            let trans = conn.transaction(|| -> Result<_, diesel::result::Error> {
-                                let report_id =
-                                    models::Report::create(conn, user.id, body.to_vec())?;
+                            let mut profile_names: Vec<&str> = query.profiles.split(',').collect();
+
+                            profile_names.dedup();
+
+                            // Refuse unknown profiles before anything is stored.
+                            let profile_ids =
+                                models::Profile::ids_for_machine_names(conn, &profile_names)?;
 
-                                let mut profile_names: Vec<&str> =
-                                    query.profiles.split(',').collect();
+                            let report_id = conn.transaction(|| -> Result<_, models::Error> {
+                                let sample = models::Sample::acquire(
+                                    conn,
+                                    &upload.multihash,
+                                    &upload.digests,
+                                    upload.size as i64,
+                                )?;
 
-                                profile_names.dedup();
+                                let store_file = sample.file_key.is_none();
 
-                                for profile_machine_name in profile_names {
-                                    let profile_id = models::Profile::id_for_machine_name(
+                                if store_file {
+                                    store.put_file(&upload.multihash, &upload.path)?;
+                                }
+
+                                let create = || -> Result<_, models::Error> {
+                                    if store_file {
+                                        models::Sample::set_file_key(
+                                            conn,
+                                            &upload.multihash,
+                                            &upload.multihash,
+                                        )?;
+                                    }
+
+                                    let report_id = models::Report::create(
                                         conn,
-                                        profile_machine_name,
+                                        user.id,
+                                        &upload.multihash,
+                                        query.filename.as_ref().map(String::as_str),
                                     )?;
 
-                                    models::Task::create(conn, report_id, profile_id)?;
+                                    for profile_id in profile_ids {
+                                        let previous = if query.reuse_results.unwrap_or(false) {
+                                            models::Task::latest_succeeded_for_sample(
+                                                conn,
+                                                &upload.multihash,
+                                                profile_id,
+                                            )?
+                                        } else {
+                                            None
+                                        };
+
+                                        match previous {
+                                            Some(previous) => models::Task::create_reused(
+                                                conn, report_id, &previous,
+                                            )?,
+                                            None => {
+                                                models::Task::create(conn, report_id, profile_id)?
+                                            }
+                                        };
+                                    }
+
+                                    Ok(report_id)
+                                };
+
+                                let created = create();
+
+                                // Nothing will refer to the file after the
+                                // rollback. The sample row stays locked until
+                                // then, so no other upload can be relying on it.
+                                if created.is_err() && store_file {
+                                    if let Err(e) = store.delete(&upload.multihash) {
+                                        error!(
+                                            "could not delete orphaned sample {}: {}",
+                                            upload.multihash, e
+                                        );
+                                    }
                                 }
 
-                                Ok(report_id)
-                            });
+                                created
+                            })?;
 
-                            conn.execute("NOTIFY tasks_created").unwrap();
+                            conn.execute("NOTIFY tasks_created")?;
 
-                            trans
+                            Ok(report_id)
                         })
                         .and_then(|report_id| {
                             Ok(HttpResponse::Ok().json(CreateResponse { report_id }))
                         })
                         .or_else(
-                            |e: actix_web::error::BlockingError<diesel::result::Error>| {
-                                Ok(HttpResponse::InternalServerError().finish())
+                            |e: actix_web::error::BlockingError<models::Error>| match e {
+                                actix_web::error::BlockingError::Error(
+                                    models::Error::UnknownProfile(machine_name),
+                                ) => Ok(unknown_profile(&machine_name)),
+                                _ => Ok(HttpResponse::InternalServerError().finish()),
                             },
                         )
                     })
-                    .or_else(|_| Ok(HttpResponse::InternalServerError().finish()))
+                    .or_else(|e| match e {
+                        PayloadError::Overflow => Ok(HttpResponse::PayloadTooLarge().finish()),
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    })
             })
             .or_else(
-                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
-                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
-                        Ok(HttpResponse::Unauthorized().finish())
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
                     }
                     _ => Ok(HttpResponse::InternalServerError().finish()),
                 },
@@ -146,18 +350,27 @@ pub fn by_id(
     path: web::Path<ByIdPath>,
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
     if let Some(token) = req.headers().get(header::AUTHORIZATION) {
         let token = token.to_str().unwrap().to_string();
 
         Either::A(
             web::block(move || {
-                let user = models::Token::user_by_token(&db.get().unwrap(), &token)?;
+                let user = models::User::by_credential(
+                    &db.get().unwrap(),
+                    &token,
+                    &client,
+                    models::Scope::ReportsRead,
+                )?;
+
+                user.require(models::Permission::ReadReports)?;
 
                 Ok((db, user))
             })
             .and_then(|(db, user)| {
                 web::block(move || {
-                    models::Report::by_id_check_user(&db.get().unwrap(), path.report_id, user.id)
+                    models::Report::by_id_check_user(&db.get().unwrap(), path.report_id, &user)
                 })
                 .and_then(|report| Ok(HttpResponse::Ok().json(report)))
                 .or_else(
@@ -170,9 +383,12 @@ pub fn by_id(
                 )
             })
             .or_else(
-                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
-                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
-                        Ok(HttpResponse::Unauthorized().finish())
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
                     }
                     _ => Ok(HttpResponse::InternalServerError().finish()),
                 },
@@ -183,42 +399,388 @@ pub fn by_id(
     }
 }
 
-pub fn discard_file(
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadFormat {
+    Raw,
+    Zip,
+}
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    format: Option<DownloadFormat>,
+}
+
+/// Carries the password for a ZIP download. It is a header rather than part
+/// of the query so that it stays out of access logs.
+pub const ARCHIVE_PASSWORD_HEADER: &str = "x-archive-password";
+
+/// Keeps only characters that are safe inside a quoted `Content-Disposition`
+/// file name and on common filesystems.
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_control() && !"\\/\":*?<>|".contains(*c))
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_owned()
+}
+
+pub fn download(
     req: HttpRequest,
     path: web::Path<ByIdPath>,
+    query: web::Query<DownloadQuery>,
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    store: web::Data<storage::Store>,
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    let password = req
+        .headers()
+        .get(ARCHIVE_PASSWORD_HEADER)
+        .and_then(|password| password.to_str().ok())
+        .map(str::to_owned);
+
     if let Some(token) = req.headers().get(header::AUTHORIZATION) {
         let token = token.to_str().unwrap().to_string();
 
         Either::A(
             web::block(move || {
-                let user = models::Token::user_by_token(&db.get().unwrap(), &token)?;
+                let user = models::User::by_credential(
+                    &db.get().unwrap(),
+                    &token,
+                    &client,
+                    models::Scope::FilesDownload,
+                )?;
+
+                user.require(models::Permission::ReadReports)?;
 
                 Ok((db, user))
             })
-            .and_then(|(db, user)| {
-                web::block(move || {
-                    models::Report::discard_file_check_user(
+            .and_then(move |(db, user)| {
+                web::block(move || -> Result<_, models::Error> {
+                    let (report, file_key) = models::Report::with_file_key_check_user(
                         &db.get().unwrap(),
                         path.report_id,
-                        user.id,
-                    )
+                        &user,
+                    )?;
+
+                    match file_key {
+                        Some(file_key) => Ok(Some((report, store.get(&file_key)?))),
+                        None => Ok(None),
+                    }
+                })
+                .and_then(move |file| match file {
+                    Some((report, reader)) => {
+                        let name = report
+                            .file_name
+                            .as_ref()
+                            .map(|name| sanitize_file_name(name))
+                            .filter(|name| !name.is_empty())
+                            .unwrap_or_else(|| report.file_multihash.clone());
+
+                        match query.format {
+                            Some(DownloadFormat::Raw) => Ok(HttpResponse::Ok()
+                                .content_type("application/octet-stream")
+                                .header(
+                                    header::CONTENT_DISPOSITION,
+                                    format!("attachment; filename=\"{}\"", name),
+                                )
+                                .streaming(storage::BlobStream::new(reader))),
+                            Some(DownloadFormat::Zip) | None => {
+                                let password = password.unwrap_or_else(|| {
+                                    env::var("SAMPLE_ZIP_PASSWORD")
+                                        .unwrap_or_else(|_| "infected".into())
+                                });
+
+                                Ok(HttpResponse::Ok()
+                                    .content_type("application/zip")
+                                    .header(
+                                        header::CONTENT_DISPOSITION,
+                                        format!("attachment; filename=\"{}.zip\"", name),
+                                    )
+                                    .streaming(archive::EncryptedZipStream::new(
+                                        storage::BlobStream::new(reader),
+                                        &name,
+                                        &password,
+                                        report.created_when,
+                                    )))
+                            }
+                        }
+                    }
+                    None => Ok(HttpResponse::Gone().finish()),
                 })
-                .and_then(|report| Ok(HttpResponse::Ok().finish()))
                 .or_else(
-                    |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
-                        actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
-                            Ok(HttpResponse::NotFound().finish())
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::NotFound().finish()),
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                )
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+pub fn discard_file(
+    req: HttpRequest,
+    path: web::Path<ByIdPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    store: web::Data<storage::Store>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let user = models::User::by_credential(
+                    &db.get().unwrap(),
+                    &token,
+                    &client,
+                    models::Scope::FilesDelete,
+                )?;
+
+                user.require(models::Permission::SubmitReports)?;
+
+                Ok((db, user))
+            })
+            .and_then(|(db, user)| {
+                web::block(move || -> Result<_, models::Error> {
+                    let conn = &db.get().unwrap();
+
+                    conn.transaction(|| {
+                        let orphaned_key =
+                            models::Report::discard_file_check_user(conn, path.report_id, &user)?;
+
+                        if let Some(key) = orphaned_key {
+                            store.delete(&key)?;
                         }
+
+                        Ok(())
+                    })
+                })
+                .and_then(|_| Ok(HttpResponse::Ok().finish()))
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::NotFound().finish()),
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                )
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RescanQuery {
+    profiles: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct QueuedResponse {
+    task_ids: Vec<i64>,
+}
+
+/// Refuses a request naming a profile that does not exist.
+fn unknown_profile(machine_name: &str) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(validation::invalid(validation::FieldError::new(
+        "profiles",
+        "unknown",
+        format!("has no profile named {}", machine_name),
+    )))
+}
+
+/// Scans a report's file again, with the comma-separated `profiles` or with
+/// every profile it was scanned with before. Fails with `410 Gone` once the
+/// file has been discarded, and with `422 Unprocessable Entity` if a profile
+/// is unknown.
+pub fn rescan(
+    req: HttpRequest,
+    path: web::Path<ByIdPath>,
+    query: web::Query<RescanQuery>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let user = models::User::by_credential(
+                    &db.get().unwrap(),
+                    &token,
+                    &client,
+                    models::Scope::ReportsWrite,
+                )?;
+
+                user.require(models::Permission::SubmitReports)?;
+
+                Ok((db, user))
+            })
+            .and_then(move |(db, user)| {
+                web::block(move || -> Result<_, models::Error> {
+                    let conn = &db.get().unwrap();
+
+                    let profile_names: Option<Vec<&str>> = query
+                        .profiles
+                        .as_ref()
+                        .map(|profiles| profiles.split(',').collect());
+
+                    let task_ids = models::Report::rescan_check_user(
+                        conn,
+                        path.report_id,
+                        &user,
+                        profile_names.as_ref().map(Vec::as_slice),
+                    )?;
+
+                    if task_ids.is_some() {
+                        conn.execute("NOTIFY tasks_created")?;
+                    }
+
+                    Ok(task_ids)
+                })
+                .and_then(|task_ids| match task_ids {
+                    Some(task_ids) => Ok(HttpResponse::Ok().json(QueuedResponse { task_ids })),
+                    None => Ok(HttpResponse::Gone().finish()),
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::NotFound().finish()),
+                        actix_web::error::BlockingError::Error(models::Error::UnknownProfile(
+                            machine_name,
+                        )) => Ok(unknown_profile(&machine_name)),
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                )
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AddProfilesQuery {
+    profiles: String,
+}
+
+/// Scans a report's file with the comma-separated `profiles` it has not been
+/// scanned with yet, such as engines added since it was submitted. Fails
+/// with `410 Gone` once the file has been discarded, and with
+/// `422 Unprocessable Entity` if a profile is unknown.
+pub fn add_profiles(
+    req: HttpRequest,
+    path: web::Path<ByIdPath>,
+    query: web::Query<AddProfilesQuery>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let user = models::User::by_credential(
+                    &db.get().unwrap(),
+                    &token,
+                    &client,
+                    models::Scope::ReportsWrite,
+                )?;
+
+                user.require(models::Permission::SubmitReports)?;
+
+                Ok((db, user))
+            })
+            .and_then(move |(db, user)| {
+                web::block(move || -> Result<_, models::Error> {
+                    let conn = &db.get().unwrap();
+
+                    let profile_names: Vec<&str> = query.profiles.split(',').collect();
+
+                    let task_ids = models::Report::add_profiles_check_user(
+                        conn,
+                        path.report_id,
+                        &user,
+                        &profile_names,
+                    )?;
+
+                    if task_ids
+                        .as_ref()
+                        .map_or(false, |task_ids| !task_ids.is_empty())
+                    {
+                        conn.execute("NOTIFY tasks_created")?;
+                    }
+
+                    Ok(task_ids)
+                })
+                .and_then(|task_ids| match task_ids {
+                    Some(task_ids) => Ok(HttpResponse::Ok().json(QueuedResponse { task_ids })),
+                    None => Ok(HttpResponse::Gone().finish()),
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::NotFound().finish()),
+                        actix_web::error::BlockingError::Error(models::Error::UnknownProfile(
+                            machine_name,
+                        )) => Ok(unknown_profile(&machine_name)),
                         _ => Ok(HttpResponse::InternalServerError().finish()),
                     },
                 )
             })
             .or_else(
-                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
-                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
-                        Ok(HttpResponse::Unauthorized().finish())
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
                     }
                     _ => Ok(HttpResponse::InternalServerError().finish()),
                 },
@@ -228,3 +790,83 @@ pub fn discard_file(
         Either::B(ok(HttpResponse::Unauthorized().finish()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(id: i64, created_when: DateTime<Utc>) -> models::Report {
+        models::Report {
+            id,
+            user_id: 1,
+            created_when,
+            file_multihash: "1220ab".into(),
+            has_file: true,
+            file_name: None,
+        }
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        for &(created_when, id) in &[
+            (Utc.ymd(2019, 7, 14).and_hms_micro(13, 45, 30, 123_456), 42),
+            (Utc.ymd(2019, 7, 14).and_hms(13, 45, 30), 1),
+            (Utc.timestamp(0, 0), i64::max_value()),
+            // Before the epoch the microseconds count down from a negative
+            // number of seconds.
+            (Utc.ymd(1969, 12, 31).and_hms_micro(23, 59, 59, 500_000), 7),
+        ] {
+            let cursor = encode_cursor(&report(id, created_when));
+
+            assert_eq!(decode_cursor(&cursor), Some((created_when, id)));
+        }
+    }
+
+    #[test]
+    fn cursor_format() {
+        let created_when = Utc.timestamp(1_563_111_930, 123_456_000);
+
+        assert_eq!(
+            encode_cursor(&report(42, created_when)),
+            "1563111930123456_42"
+        );
+        assert_eq!(
+            encode_cursor(&report(7, Utc.timestamp(-1, 500_000_000))),
+            "-500000_7"
+        );
+    }
+
+    #[test]
+    fn cursor_drops_nanoseconds() {
+        let created_when = Utc.timestamp(1_563_111_930, 123_456_789);
+        let cursor = encode_cursor(&report(42, created_when));
+
+        assert_eq!(
+            decode_cursor(&cursor),
+            Some((Utc.timestamp(1_563_111_930, 123_456_000), 42))
+        );
+    }
+
+    #[test]
+    fn malformed_cursors_are_refused() {
+        for cursor in &[
+            "",
+            "_",
+            "garbage",
+            "1563111930123456",
+            "1563111930123456_",
+            "_42",
+            "1563111930123456_x",
+            "x_42",
+            "1563111930.5_42",
+            "1563111930123456_42_1",
+            "99999999999999999999_42",
+            "1563111930123456_99999999999999999999",
+        ] {
+            assert_eq!(decode_cursor(cursor), None, "{}", cursor);
+        }
+
+        // Parses, but is past any date chrono can represent.
+        assert_eq!(decode_cursor(&format!("{}_42", i64::max_value())), None);
+    }
+}
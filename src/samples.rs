@@ -0,0 +1,224 @@
+use actix_web::{http::header, web, Error as AWError, HttpRequest, HttpResponse};
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    PgConnection,
+};
+use futures::{
+    future::{ok, Either},
+    Future,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::models;
+
+const MAX_BATCH_SIZE: usize = 500;
+
+/// Lowercases a hex digest, rejecting anything that is not MD5, SHA-1,
+/// SHA-256, SHA-512 or a SHA2-256 multihash.
+fn normalize_hash(hash: &str) -> Option<String> {
+    match hash.len() {
+        32 | 40 | 64 | 68 | 128 if hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Some(hash.to_lowercase())
+        }
+        _ => None,
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReportResult {
+    report: models::Report,
+    tasks: Vec<models::Task>,
+}
+
+#[derive(Serialize)]
+pub struct LookupResult {
+    hash: String,
+    sample: Option<models::Sample>,
+    reports: Vec<ReportResult>,
+}
+
+/// Resolves each hash to the reports of the matching sample the caller can
+/// see. A sample is only disclosed when at least one such report exists.
+fn lookup(
+    conn: &PgConnection,
+    user: &models::User,
+    hashes: Vec<String>,
+) -> Result<Vec<LookupResult>, diesel::result::Error> {
+    let samples = models::Sample::by_hashes(conn, &hashes)?;
+    let multihashes: Vec<String> = samples.iter().map(|s| s.multihash.clone()).collect();
+
+    let reports = models::Report::list_for_user_by_multihashes(conn, user, &multihashes)?;
+    let report_ids: Vec<i64> = reports.iter().map(|r| r.id).collect();
+    let tasks = models::Task::list_for_reports(conn, &report_ids)?;
+
+    Ok(hashes
+        .into_iter()
+        .map(|hash| {
+            let sample = samples.iter().find(|sample| sample.matches(&hash));
+            let matching: Vec<ReportResult> = match sample {
+                Some(sample) => reports
+                    .iter()
+                    .filter(|report| report.file_multihash == sample.multihash)
+                    .map(|report| ReportResult {
+                        report: report.clone(),
+                        tasks: tasks
+                            .iter()
+                            .filter(|task| task.report_id == report.id)
+                            .cloned()
+                            .collect(),
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+
+            LookupResult {
+                hash,
+                sample: if matching.is_empty() {
+                    None
+                } else {
+                    sample.cloned()
+                },
+                reports: matching,
+            }
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+pub struct ByHashPath {
+    pub hash: String,
+}
+
+pub fn by_hash(
+    req: HttpRequest,
+    path: web::Path<ByHashPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let hash = normalize_hash(&path.hash);
+
+    match (req.headers().get(header::AUTHORIZATION), hash) {
+        (Some(token), Some(hash)) => {
+            let token = token.to_str().unwrap().to_string();
+            let client = auth::client(&req);
+
+            Either::A(
+                web::block(move || {
+                    let conn = &db.get().unwrap();
+
+                    let user = models::User::by_credential(
+                        conn,
+                        &token,
+                        &client,
+                        models::Scope::ReportsRead,
+                    )?;
+
+                    user.require(models::Permission::ReadReports)?;
+
+                    Ok((db, user))
+                })
+                .and_then(move |(db, user)| {
+                    web::block(move || lookup(&db.get().unwrap(), &user, vec![hash]))
+                        .and_then(|mut results| {
+                            let result = results.pop().unwrap();
+
+                            if result.reports.is_empty() {
+                                Ok(HttpResponse::NotFound().finish())
+                            } else {
+                                Ok(HttpResponse::Ok().json(result))
+                            }
+                        })
+                        .or_else(
+                            |_: actix_web::error::BlockingError<diesel::result::Error>| {
+                                Ok(HttpResponse::InternalServerError().finish())
+                            },
+                        )
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::Unauthorized().finish()),
+                        actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                            Ok(HttpResponse::Forbidden().finish())
+                        }
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                ),
+            )
+        }
+        (Some(_), None) => Either::B(ok(HttpResponse::BadRequest().finish())),
+        (None, _) => Either::B(ok(HttpResponse::Unauthorized().finish())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    hashes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    results: Vec<LookupResult>,
+}
+
+pub fn batch(
+    req: HttpRequest,
+    batch: web::Json<BatchRequest>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let hashes: Option<Vec<String>> = batch
+        .hashes
+        .iter()
+        .map(|hash| normalize_hash(hash))
+        .collect();
+
+    match (req.headers().get(header::AUTHORIZATION), hashes) {
+        (Some(_), Some(ref hashes)) if hashes.len() > MAX_BATCH_SIZE => {
+            Either::B(ok(HttpResponse::PayloadTooLarge().finish()))
+        }
+        (Some(token), Some(hashes)) => {
+            let token = token.to_str().unwrap().to_string();
+            let client = auth::client(&req);
+
+            Either::A(
+                web::block(move || {
+                    let conn = &db.get().unwrap();
+
+                    let user = models::User::by_credential(
+                        conn,
+                        &token,
+                        &client,
+                        models::Scope::ReportsRead,
+                    )?;
+
+                    user.require(models::Permission::ReadReports)?;
+
+                    Ok((db, user))
+                })
+                .and_then(move |(db, user)| {
+                    web::block(move || lookup(&db.get().unwrap(), &user, hashes))
+                        .and_then(|results| Ok(HttpResponse::Ok().json(BatchResponse { results })))
+                        .or_else(
+                            |_: actix_web::error::BlockingError<diesel::result::Error>| {
+                                Ok(HttpResponse::InternalServerError().finish())
+                            },
+                        )
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::Unauthorized().finish()),
+                        actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                            Ok(HttpResponse::Forbidden().finish())
+                        }
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                ),
+            )
+        }
+        (Some(_), None) => Either::B(ok(HttpResponse::BadRequest().finish())),
+        (None, _) => Either::B(ok(HttpResponse::Unauthorized().finish())),
+    }
+}
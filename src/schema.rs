@@ -1,3 +1,94 @@
+table! {
+    api_keys (id) {
+        id -> Int8,
+        user_id -> Int8,
+        name -> Text,
+        key_prefix -> Text,
+        key_hash -> Text,
+        scopes -> Array<Text>,
+        allowed_ips -> Array<Text>,
+        expires_when -> Nullable<Timestamptz>,
+        created_when -> Timestamptz,
+        last_used_when -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    audit_events (id) {
+        id -> Int8,
+        actor_id -> Nullable<Int8>,
+        action -> Text,
+        target -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        outcome -> Text,
+        occurred_when -> Timestamptz,
+    }
+}
+
+table! {
+    invitations (id) {
+        id -> Int8,
+        code_prefix -> Text,
+        code_hash -> Text,
+        rank -> Int4,
+        created_by -> Nullable<Int8>,
+        created_when -> Timestamptz,
+        expires_when -> Nullable<Timestamptz>,
+        used_by -> Nullable<Int8>,
+        used_when -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    login_attempts (id) {
+        id -> Int8,
+        username -> Text,
+        ip_address -> Nullable<Text>,
+        succeeded -> Bool,
+        attempted_when -> Timestamptz,
+    }
+}
+
+table! {
+    mfa_challenges (id) {
+        id -> Int8,
+        user_id -> Int8,
+        token_prefix -> Text,
+        token_hash -> Text,
+        created_when -> Timestamptz,
+        attempts -> Int4,
+    }
+}
+
+table! {
+    mfa_policies (rank) {
+        rank -> Int4,
+    }
+}
+
+table! {
+    oidc_logins (id) {
+        id -> Int8,
+        state_prefix -> Text,
+        state_hash -> Text,
+        nonce -> Text,
+        code_verifier -> Text,
+        created_when -> Timestamptz,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Int8,
+        user_id -> Int8,
+        token_prefix -> Text,
+        token_hash -> Text,
+        created_when -> Timestamptz,
+        used_when -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     profiles (id) {
         id -> Int8,
@@ -8,13 +99,37 @@ table! {
     }
 }
 
+table! {
+    recovery_codes (id) {
+        id -> Int8,
+        user_id -> Int8,
+        code_hash -> Text,
+        used_when -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     reports (id) {
         id -> Int8,
         user_id -> Int8,
         created_when -> Timestamptz,
         file_multihash -> Text,
-        file -> Nullable<Bytea>,
+        has_file -> Bool,
+        file_name -> Nullable<Text>,
+    }
+}
+
+table! {
+    samples (multihash) {
+        multihash -> Text,
+        file_key -> Nullable<Text>,
+        size -> Nullable<Int8>,
+        reference_count -> Int4,
+        created_when -> Timestamptz,
+        md5 -> Nullable<Text>,
+        sha1 -> Nullable<Text>,
+        sha256 -> Nullable<Text>,
+        sha512 -> Nullable<Text>,
     }
 }
 
@@ -27,6 +142,10 @@ table! {
         completed_when -> Nullable<Timestamptz>,
         status -> Text,
         message -> Nullable<Text>,
+        worker_id -> Nullable<Int8>,
+        verdict -> Nullable<Text>,
+        result -> Nullable<Jsonb>,
+        attempts -> Int4,
     }
 }
 
@@ -34,8 +153,15 @@ table! {
     tokens (id) {
         id -> Int8,
         user_id -> Int8,
-        token -> Text,
         created_when -> Timestamptz,
+        kind -> Text,
+        family -> Text,
+        last_used_when -> Timestamptz,
+        used_when -> Nullable<Timestamptz>,
+        token_prefix -> Text,
+        token_hash -> Text,
+        user_agent -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
     }
 }
 
@@ -45,6 +171,15 @@ table! {
         username -> Text,
         hashed_password -> Text,
         rank -> Int4,
+        disabled -> Bool,
+        password_reset_required -> Bool,
+        email -> Nullable<Text>,
+        totp_secret -> Nullable<Text>,
+        totp_enabled -> Bool,
+        totp_last_step -> Nullable<Int8>,
+        oidc_subject -> Nullable<Text>,
+        ldap_dn -> Nullable<Text>,
+        email_verified -> Bool,
     }
 }
 
@@ -60,15 +195,36 @@ table! {
     workers (id) {
         id -> Int8,
         last_active -> Timestamptz,
+        name -> Nullable<Text>,
+        registered_when -> Timestamptz,
+        token_prefix -> Nullable<Text>,
+        token_hash -> Nullable<Text>,
     }
 }
 
+joinable!(api_keys -> users (user_id));
+joinable!(mfa_challenges -> users (user_id));
+joinable!(password_resets -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(reports -> samples (file_multihash));
+joinable!(tasks -> profiles (profile_id));
 joinable!(tasks -> reports (report_id));
+joinable!(tasks -> workers (worker_id));
 joinable!(worker_capabilities -> workers (worker_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_keys,
+    audit_events,
+    invitations,
+    login_attempts,
+    mfa_challenges,
+    mfa_policies,
+    oidc_logins,
+    password_resets,
     profiles,
+    recovery_codes,
     reports,
+    samples,
     tasks,
     tokens,
     users,
@@ -0,0 +1,35 @@
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// How many leading characters of a credential are stored in the clear to
+/// find its row again.
+pub const PREFIX_LEN: usize = 8;
+
+/// Draws a new bearer credential: 256 bits from the operating system's CSPRNG,
+/// hex-encoded.
+pub fn generate() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    hex::encode(bytes)
+}
+
+/// The non-secret part of a credential, used to look it up.
+pub fn prefix(secret: &str) -> &str {
+    secret.get(..PREFIX_LEN).unwrap_or(secret)
+}
+
+/// What gets stored in place of a credential.
+pub fn digest(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Compares two secrets without short-circuiting on the first mismatch.
+pub fn matches(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks a presented credential against a stored digest.
+pub fn verify(secret: &str, digest: &str) -> bool {
+    matches(self::digest(secret).as_bytes(), digest.as_bytes())
+}
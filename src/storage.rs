@@ -0,0 +1,487 @@
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_web::{
+    error::{BlockingError, PayloadError},
+    web,
+};
+use bytes::Bytes;
+use chrono::prelude::*;
+use futures::{
+    future::{err, Either},
+    Async, Future, Poll, Stream,
+};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            display("storage i/o error: {}", err)
+        }
+        Http(err: reqwest::Error) {
+            from()
+            cause(err)
+            display("storage request failed: {}", err)
+        }
+        Status(code: u16) {
+            display("storage backend answered with status {}", code)
+        }
+    }
+}
+
+/// Somewhere to keep sample bytes outside of Postgres.
+///
+/// Keys are the hex multihash of the content, so storing the same bytes twice
+/// is harmless.
+pub trait BlobStore: Send + Sync {
+    /// Moves the file at `path` into the store under `key`.
+    fn put_file(&self, key: &str, path: &Path) -> Result<(), Error>;
+
+    fn get(&self, key: &str) -> Result<Box<dyn Read + Send>, Error>;
+
+    /// Removes `key` from the store. Removing a missing key is not an error.
+    fn delete(&self, key: &str) -> Result<(), Error>;
+}
+
+pub type Store = Arc<dyn BlobStore>;
+
+/// Builds the store selected by `BLOB_STORE` (`local` or `s3`).
+pub fn from_env() -> Store {
+    match env::var("BLOB_STORE").as_ref().map(String::as_str) {
+        Ok("s3") => Arc::new(S3Store {
+            endpoint: env::var("S3_ENDPOINT")
+                .expect("incomplete s3 configuration")
+                .trim_end_matches('/')
+                .to_owned(),
+            bucket: env::var("S3_BUCKET").expect("incomplete s3 configuration"),
+            region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".into()),
+            access_key: env::var("S3_ACCESS_KEY").expect("incomplete s3 configuration"),
+            secret_key: env::var("S3_SECRET_KEY").expect("incomplete s3 configuration"),
+            client: reqwest::Client::new(),
+        }),
+        Ok("local") | Err(_) => Arc::new(LocalStore {
+            root: env::var("BLOB_STORE_PATH")
+                .unwrap_or_else(|_| "samples".into())
+                .into(),
+        }),
+        Ok(other) => panic!("unknown blob store: {}", other),
+    }
+}
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.root
+            .join(&key[key.len().saturating_sub(2)..])
+            .join(key)
+    }
+}
+
+impl BlobStore for LocalStore {
+    fn put_file(&self, key: &str, path: &Path) -> Result<(), Error> {
+        let target = self.path_for(key);
+
+        if target.exists() {
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+
+        fs::create_dir_all(target.parent().unwrap())?;
+
+        // The spool directory may live on another filesystem, in which case
+        // renaming fails and the bytes have to be copied over.
+        if fs::rename(path, &target).is_err() {
+            let partial = target.with_extension("partial");
+
+            fs::copy(path, &partial)?;
+            fs::rename(&partial, &target)?;
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Box<dyn Read + Send>, Error> {
+        Ok(Box::new(fs::File::open(self.path_for(key))?))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path_for(key)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Any S3-compatible service, addressed path-style and signed with AWS
+/// Signature Version 4.
+pub struct S3Store {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    client: reqwest::Client,
+}
+
+impl S3Store {
+    /// Percent-encodes a path the way Signature Version 4 expects for S3:
+    /// everything but unreserved characters and the `/` between segments.
+    fn uri_encode(path: &str) -> String {
+        path.bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                    (byte as char).to_string()
+                }
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
+    fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+        mac.input(data.as_bytes());
+        mac.result().code().to_vec()
+    }
+
+    fn request(&self, method: reqwest::Method, key: &str) -> reqwest::RequestBuilder {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let host = self
+            .endpoint
+            .splitn(2, "://")
+            .last()
+            .unwrap_or(&self.endpoint);
+        // The same encoded path is signed and requested, so that the
+        // service sees exactly what was signed.
+        let uri = Self::uri_encode(&format!("/{}/{}", self.bucket, key));
+        let payload_hash = "UNSIGNED-PAYLOAD";
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, uri, host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            Self::hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date),
+            |key, part| Self::hmac(&key, part),
+        );
+        let signature = hex::encode(Self::hmac(&signing_key, &string_to_sign));
+
+        self.client
+            .request(method, &format!("{}{}", self.endpoint, uri)[..])
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                    self.access_key, scope, signature
+                ),
+            )
+    }
+}
+
+impl BlobStore for S3Store {
+    fn put_file(&self, key: &str, path: &Path) -> Result<(), Error> {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+
+        let response = self
+            .request(reqwest::Method::PUT, key)
+            .body(reqwest::Body::sized(file, len))
+            .send()?;
+
+        if !response.status().is_success() {
+            return Err(Error::Status(response.status().as_u16()));
+        }
+
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Box<dyn Read + Send>, Error> {
+        let response = self.request(reqwest::Method::GET, key).send()?;
+
+        if !response.status().is_success() {
+            return Err(Error::Status(response.status().as_u16()));
+        }
+
+        Ok(Box::new(response))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        let response = self.request(reqwest::Method::DELETE, key).send()?;
+
+        if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
+            return Err(Error::Status(response.status().as_u16()));
+        }
+
+        Ok(())
+    }
+}
+
+const CHUNK_SIZE: usize = 65_536;
+
+/// Runs file or network I/O on the thread pool meant for blocking calls,
+/// instead of on the event loop.
+fn blocking<F, T>(f: F) -> impl Future<Item = T, Error = io::Error>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => {
+            io::Error::new(io::ErrorKind::Other, "blocking i/o was cancelled")
+        }
+    })
+}
+
+/// Spools a request body to disk, refusing it once it grows past
+/// `max_size` bytes.
+pub fn spool<S>(
+    payload: S,
+    max_size: u64,
+) -> impl Future<Item = SpooledUpload, Error = PayloadError>
+where
+    S: Stream<Item = Bytes, Error = PayloadError>,
+{
+    blocking(Upload::new)
+        .map_err(PayloadError::Io)
+        .and_then(move |upload| {
+            payload.fold(upload, move |upload, chunk| {
+                if upload.spool.size + chunk.len() as u64 > max_size {
+                    Either::A(err(PayloadError::Overflow))
+                } else {
+                    let write = blocking(move || upload.write_all(&chunk));
+
+                    Either::B(write.map_err(PayloadError::Io))
+                }
+            })
+        })
+        .and_then(|upload| blocking(move || upload.finish()).map_err(PayloadError::Io))
+}
+
+/// An upload being spooled to disk, hashed as its chunks arrive.
+pub struct Upload {
+    file: fs::File,
+    md5: Md5,
+    sha1: Sha1,
+    sha256: Sha256,
+    sha512: Sha512,
+    spool: SpooledUpload,
+}
+
+impl Upload {
+    pub fn new() -> io::Result<Self> {
+        let dir = env::var("UPLOAD_SPOOL_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir());
+        let path = dir.join(format!(
+            "upload-{}",
+            uuid::Uuid::new_v4().to_simple().to_string()
+        ));
+
+        Ok(Upload {
+            file: fs::File::create(&path)?,
+            md5: Md5::new(),
+            sha1: Sha1::new(),
+            sha256: Sha256::new(),
+            sha512: Sha512::new(),
+            spool: SpooledUpload {
+                path,
+                multihash: String::new(),
+                digests: Digests::default(),
+                size: 0,
+            },
+        })
+    }
+
+    /// Appends a chunk, with no regard for size limits.
+    pub fn write_all(mut self, chunk: &[u8]) -> io::Result<Self> {
+        self.file.write_all(chunk)?;
+        self.md5.input(chunk);
+        self.sha1.input(chunk);
+        self.sha256.input(chunk);
+        self.sha512.input(chunk);
+        self.spool.size += chunk.len() as u64;
+
+        Ok(self)
+    }
+
+    pub fn finish(mut self) -> io::Result<SpooledUpload> {
+        self.file.flush()?;
+
+        let sha256 = self.sha256.result();
+
+        // SHA2-256 multihash: function code 0x12 followed by the digest length.
+        let mut multihash = vec![0x12, 0x20];
+        multihash.extend_from_slice(&sha256);
+        self.spool.multihash = hex::encode(multihash);
+
+        self.spool.digests = Digests {
+            md5: hex::encode(self.md5.result()),
+            sha1: hex::encode(self.sha1.result()),
+            sha256: hex::encode(sha256),
+            sha512: hex::encode(self.sha512.result()),
+        };
+
+        Ok(self.spool)
+    }
+}
+
+/// Hex digests of an upload, for looking samples up by the hashes other
+/// tools report.
+#[derive(Default)]
+pub struct Digests {
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+    pub sha512: String,
+}
+
+/// A received upload waiting to be handed to a store. The spool file is
+/// removed on drop unless a store has already taken it.
+pub struct SpooledUpload {
+    pub path: PathBuf,
+    pub multihash: String,
+    pub digests: Digests,
+    pub size: u64,
+}
+
+impl Drop for SpooledUpload {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Streams a stored blob into a response body, reading it on the blocking
+/// thread pool.
+pub struct BlobStream {
+    reader: Option<Box<dyn Read + Send>>,
+    pending: Option<Box<dyn Future<Item = (Box<dyn Read + Send>, Bytes), Error = io::Error>>>,
+}
+
+impl BlobStream {
+    pub fn new(reader: Box<dyn Read + Send>) -> Self {
+        BlobStream {
+            reader: Some(reader),
+            pending: None,
+        }
+    }
+}
+
+impl Stream for BlobStream {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        if self.pending.is_none() {
+            let mut reader = match self.reader.take() {
+                Some(reader) => reader,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            self.pending = Some(Box::new(blocking(move || {
+                let mut buf = vec![0; CHUNK_SIZE];
+                let n = reader.read(&mut buf)?;
+                buf.truncate(n);
+
+                Ok((reader, Bytes::from(buf)))
+            })));
+        }
+
+        let (reader, chunk) = match self.pending.as_mut().unwrap().poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(read)) => {
+                self.pending = None;
+                read
+            }
+            Err(e) => {
+                self.pending = None;
+                return Err(e);
+            }
+        };
+
+        if chunk.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            self.reader = Some(reader);
+            Ok(Async::Ready(Some(chunk)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s3_paths_are_encoded_per_segment() {
+        assert_eq!(S3Store::uri_encode("/samples/1220ab"), "/samples/1220ab");
+        assert_eq!(
+            S3Store::uri_encode("/samples/a b+c/ä~"),
+            "/samples/a%20b%2Bc/%C3%A4~"
+        );
+    }
+
+    /// Round-trips a blob through a real S3-compatible service. Run with
+    /// `cargo test -- --ignored` against MinIO, for example
+    /// `docker run -p 9000:9000 minio/minio server /data`, after creating
+    /// the bucket named in `S3_TEST_BUCKET`.
+    #[test]
+    #[ignore]
+    fn s3_store_round_trip() {
+        let store = S3Store {
+            endpoint: env::var("S3_TEST_ENDPOINT")
+                .unwrap_or_else(|_| "http://127.0.0.1:9000".into()),
+            bucket: env::var("S3_TEST_BUCKET").unwrap_or_else(|_| "samples".into()),
+            region: "us-east-1".into(),
+            access_key: env::var("S3_TEST_ACCESS_KEY").unwrap_or_else(|_| "minioadmin".into()),
+            secret_key: env::var("S3_TEST_SECRET_KEY").unwrap_or_else(|_| "minioadmin".into()),
+            client: reqwest::Client::new(),
+        };
+        let key = format!("test/{} key+ä", uuid::Uuid::new_v4().to_simple());
+
+        let path = env::temp_dir().join(format!("s3-test-{}", uuid::Uuid::new_v4().to_simple()));
+        fs::write(&path, b"sample bytes").unwrap();
+
+        store.put_file(&key, &path).unwrap();
+        assert!(!path.exists());
+
+        let mut content = Vec::new();
+        store.get(&key).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"sample bytes");
+
+        store.delete(&key).unwrap();
+        match store.get(&key) {
+            Err(Error::Status(404)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("deleted blob is still there"),
+        }
+        store.delete(&key).unwrap();
+    }
+}
@@ -11,6 +11,7 @@ use futures::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::auth;
 use crate::models;
 
 #[derive(Serialize)]
@@ -28,34 +29,114 @@ pub fn list(
     path: web::Path<ByIdPath>,
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
 ) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let user = models::User::by_credential(
+                    &db.get().unwrap(),
+                    &token,
+                    &client,
+                    models::Scope::ReportsRead,
+                )?;
+
+                user.require(models::Permission::ReadReports)?;
+
+                Ok((db, user))
+            })
+            .and_then(move |(db, user)| {
+                web::block(move || {
+                    let conn = &db.get().unwrap();
+
+                    let report = models::Report::by_id_check_user(conn, path.report_id, &user)?;
+
+                    models::Task::list_for_report(conn, report.id)
+                })
+                .and_then(|tasks| Ok(HttpResponse::Ok().json(ListResponse { tasks })))
+                .or_else(|e: BlockingError<diesel::result::Error>| match e {
+                    BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::NotFound().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                })
+            })
+            .or_else(|e: BlockingError<models::Error>| match e {
+                BlockingError::Error(models::Error::Database(diesel::result::Error::NotFound)) => {
+                    Ok(HttpResponse::Unauthorized().finish())
+                }
+                BlockingError::Error(models::Error::Forbidden) => {
+                    Ok(HttpResponse::Forbidden().finish())
+                }
+                _ => Ok(HttpResponse::InternalServerError().finish()),
+            }),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TaskPath {
+    pub report_id: i64,
+    pub task_id: i64,
+}
+
+/// Cancels a task that has not finished yet.
+pub fn cancel(
+    req: HttpRequest,
+    path: web::Path<TaskPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let client = auth::client(&req);
+
     if let Some(token) = req.headers().get(header::AUTHORIZATION) {
         let token = token.to_str().unwrap().to_string();
 
         Either::A(
             web::block(move || {
-                let conn = &db.get().unwrap();
+                let user = models::User::by_credential(
+                    &db.get().unwrap(),
+                    &token,
+                    &client,
+                    models::Scope::ReportsWrite,
+                )?;
 
-                let user = models::Token::user_by_token(conn, &token)?;
-                let report = models::Report::by_id_check_user(conn, path.report_id, user.id)?;
+                user.require(models::Permission::SubmitReports)?;
 
-                Ok((db, report))
+                Ok((db, user))
             })
-            .map_err(
-                |_: actix_web::error::BlockingError<diesel::result::Error>| {
-                    actix_web::error::ErrorInternalServerError(String::new())
-                },
-            )
-            .and_then(move |(db, report)| {
-                web::block(move || models::Task::list_for_report(&db.get().unwrap(), report.id))
-                    .map_err(
-                        |_: actix_web::error::BlockingError<diesel::result::Error>| {
-                            actix_web::error::ErrorInternalServerError(String::new())
-                        },
+            .and_then(move |(db, user)| {
+                web::block(move || {
+                    models::Task::cancel_check_user(
+                        &db.get().unwrap(),
+                        path.report_id,
+                        path.task_id,
+                        &user,
                     )
-                    .and_then(|tasks| HttpResponse::Ok().json(ListResponse { tasks }))
-                    .or_else(|_| HttpResponse::InternalServerError().finish())
+                })
+                .and_then(|task| Ok(HttpResponse::Ok().json(task)))
+                .or_else(|e: BlockingError<models::Error>| match e {
+                    BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::NotFound().finish()),
+                    BlockingError::Error(models::Error::IllegalTransition(..)) => {
+                        Ok(HttpResponse::Conflict().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                })
             })
-            .or_else(|_| HttpResponse::Unauthorized().finish()),
+            .or_else(|e: BlockingError<models::Error>| match e {
+                BlockingError::Error(models::Error::Database(diesel::result::Error::NotFound)) => {
+                    Ok(HttpResponse::Unauthorized().finish())
+                }
+                BlockingError::Error(models::Error::Forbidden) => {
+                    Ok(HttpResponse::Forbidden().finish())
+                }
+                _ => Ok(HttpResponse::InternalServerError().finish()),
+            }),
         )
     } else {
         Either::B(ok(HttpResponse::Unauthorized().finish()))
@@ -0,0 +1,33 @@
+use serde::Serialize;
+
+/// Why a submitted field was refused. `code` is stable for clients to match
+/// on; `message` is for people.
+#[derive(Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: &'static str, message: impl Into<String>) -> Self {
+        FieldError {
+            field,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// The body of a response refusing a request over its fields.
+#[derive(Serialize)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+/// Shorthand for a one-field refusal.
+pub fn invalid(error: FieldError) -> ValidationErrors {
+    ValidationErrors {
+        errors: vec![error],
+    }
+}
@@ -0,0 +1,406 @@
+use std::env;
+
+use actix_web::{http::header, web, Error as AWError, HttpRequest, HttpResponse};
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    Connection, PgConnection,
+};
+use futures::{
+    future::{ok, Either},
+    Future,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::audit;
+use crate::auth;
+use crate::models;
+use crate::secrets;
+use crate::storage;
+
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    enrollment_secret: String,
+    name: String,
+    profiles: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct RegisterResponse {
+    worker_id: i64,
+    token: String,
+}
+
+pub fn register(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    register: web::Json<RegisterRequest>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    let pending = audit::pending(&req);
+
+    let is_enrolled = env::var("WORKER_ENROLLMENT_SECRET")
+        .map(|secret| {
+            !secret.is_empty()
+                && secrets::matches(secret.as_bytes(), register.enrollment_secret.as_bytes())
+        })
+        .unwrap_or(false);
+
+    if is_enrolled {
+        Either::A(
+            web::block(move || {
+                let conn = &db.get().unwrap();
+
+                conn.transaction(|| {
+                    let (worker_id, token) =
+                        models::Worker::register(conn, &register.name, &register.profiles)?;
+                    pending.set_target(format!("worker:{}", worker_id));
+                    pending.record(conn, audit::Outcome::Success)?;
+
+                    Ok((worker_id, token))
+                })
+            })
+            .and_then(|(worker_id, token)| {
+                Ok(HttpResponse::Ok().json(RegisterResponse { worker_id, token }))
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::BadRequest().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Forbidden().finish()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct HeartbeatResponse {
+    worker: models::Worker,
+    /// Tasks the worker should stop scanning because they were cancelled.
+    /// Each is listed in one heartbeat only.
+    cancelled_tasks: Vec<i64>,
+}
+
+pub fn heartbeat(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || models::Worker::heartbeat(&db.get().unwrap(), &token))
+                .and_then(|(worker, cancelled_tasks)| {
+                    Ok(HttpResponse::Ok().json(HeartbeatResponse {
+                        worker,
+                        cancelled_tasks,
+                    }))
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                        actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                            Ok(HttpResponse::Unauthorized().finish())
+                        }
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClaimResponse {
+    task: models::Task,
+    profile: models::Profile,
+    file_url: String,
+}
+
+pub fn claim(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let worker = models::Worker::by_token(&db.get().unwrap(), &token)?;
+
+                Ok((db, worker))
+            })
+            .and_then(|(db, worker)| {
+                web::block(move || {
+                    let conn = &db.get().unwrap();
+
+                    match models::Task::claim_for_worker(conn, worker.id)? {
+                        Some(task) => {
+                            let profile = models::Profile::by_id(conn, task.profile_id)?;
+
+                            Ok(Some((task, profile)))
+                        }
+                        None => Ok(None),
+                    }
+                })
+                .and_then(|claimed| match claimed {
+                    Some((task, profile)) => Ok(HttpResponse::Ok().json(ClaimResponse {
+                        file_url: format!("/v1/worker/tasks/{}/file", task.id),
+                        task,
+                        profile,
+                    })),
+                    None => Ok(HttpResponse::NoContent().finish()),
+                })
+                .or_else(
+                    |_: actix_web::error::BlockingError<diesel::result::Error>| {
+                        Ok(HttpResponse::InternalServerError().finish())
+                    },
+                )
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TaskPath {
+    pub task_id: i64,
+}
+
+pub fn file(
+    req: HttpRequest,
+    path: web::Path<TaskPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    store: web::Data<storage::Store>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let worker = models::Worker::by_token(&db.get().unwrap(), &token)?;
+
+                Ok((db, worker))
+            })
+            .and_then(move |(db, worker)| {
+                web::block(move || -> Result<_, models::Error> {
+                    let file_key = models::Task::sample_for_worker(
+                        &db.get().unwrap(),
+                        path.task_id,
+                        worker.id,
+                    )?;
+
+                    match file_key {
+                        Some(file_key) => Ok(Some(store.get(&file_key)?)),
+                        None => Ok(None),
+                    }
+                })
+                .and_then(|file| match file {
+                    Some(file) => Ok(HttpResponse::Ok()
+                        .content_type("application/octet-stream")
+                        .streaming(storage::BlobStream::new(file))),
+                    None => Ok(HttpResponse::Gone().finish()),
+                })
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::NotFound().finish()),
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                )
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ResultRequest {
+    verdict: models::Verdict,
+    #[serde(default)]
+    detections: Vec<String>,
+    engine_version: Option<String>,
+    details: Option<serde_json::Value>,
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ResultResponse {
+    task: models::Task,
+}
+
+pub fn submit_result(
+    req: HttpRequest,
+    path: web::Path<TaskPath>,
+    submission: web::Json<ResultRequest>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+
+        Either::A(
+            web::block(move || {
+                let worker = models::Worker::by_token(&db.get().unwrap(), &token)?;
+
+                Ok((db, worker))
+            })
+            .and_then(move |(db, worker)| {
+                web::block(move || {
+                    let submission = submission.into_inner();
+
+                    models::Task::complete(
+                        &db.get().unwrap(),
+                        path.task_id,
+                        worker.id,
+                        submission.verdict,
+                        &models::TaskResult {
+                            detections: submission.detections,
+                            engine_version: submission.engine_version,
+                            details: submission.details,
+                        },
+                        submission.message.as_ref().map(String::as_str),
+                    )
+                })
+                .and_then(|task| Ok(HttpResponse::Ok().json(ResultResponse { task })))
+                .or_else(
+                    |e: actix_web::error::BlockingError<models::Error>| match e {
+                        actix_web::error::BlockingError::Error(models::Error::Database(
+                            diesel::result::Error::NotFound,
+                        )) => Ok(HttpResponse::NotFound().finish()),
+                        actix_web::error::BlockingError::Error(
+                            models::Error::IllegalTransition(..),
+                        ) => Ok(HttpResponse::Conflict().finish()),
+                        _ => Ok(HttpResponse::InternalServerError().finish()),
+                    },
+                )
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<diesel::result::Error>| match e {
+                    actix_web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        Ok(HttpResponse::Unauthorized().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    workers: Vec<models::Worker>,
+}
+
+/// Lists every registered worker, for operators rather than workers.
+pub fn list(
+    req: HttpRequest,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+        let client = auth::client(&req);
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                let user = models::User::by_session(conn, &token, &client)?;
+                user.require(models::Permission::ManageWorkers)?;
+
+                Ok(models::Worker::list(conn)?)
+            })
+            .and_then(|workers| Ok(HttpResponse::Ok().json(ListResponse { workers })))
+            .or_else(
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WorkerPath {
+    pub worker_id: i64,
+}
+
+pub fn revoke(
+    req: HttpRequest,
+    path: web::Path<WorkerPath>,
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+) -> impl Future<Item = HttpResponse, Error = AWError> {
+    if let Some(token) = req.headers().get(header::AUTHORIZATION) {
+        let token = token.to_str().unwrap().to_string();
+        let pending = audit::pending(&req);
+        let client = auth::client(&req);
+
+        Either::A(
+            web::block(move || -> Result<_, models::Error> {
+                let conn = &db.get().unwrap();
+
+                let user = models::User::by_session(conn, &token, &client)?;
+                user.require(models::Permission::ManageWorkers)?;
+
+                conn.transaction(|| {
+                    let revoked = models::Worker::revoke(conn, path.worker_id)?;
+                    if revoked {
+                        pending.record(conn, audit::Outcome::Success)?;
+                    }
+
+                    Ok(revoked)
+                })
+            })
+            .and_then(|revoked| {
+                if revoked {
+                    Ok(HttpResponse::Ok().finish())
+                } else {
+                    Ok(HttpResponse::NotFound().finish())
+                }
+            })
+            .or_else(
+                |e: actix_web::error::BlockingError<models::Error>| match e {
+                    actix_web::error::BlockingError::Error(models::Error::Database(
+                        diesel::result::Error::NotFound,
+                    )) => Ok(HttpResponse::Unauthorized().finish()),
+                    actix_web::error::BlockingError::Error(models::Error::Forbidden) => {
+                        Ok(HttpResponse::Forbidden().finish())
+                    }
+                    _ => Ok(HttpResponse::InternalServerError().finish()),
+                },
+            ),
+        )
+    } else {
+        Either::B(ok(HttpResponse::Unauthorized().finish()))
+    }
+}